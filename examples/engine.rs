@@ -0,0 +1,72 @@
+use lyra::compose::{AttributesCreateInfo, Score, ScoreCreateInfo};
+use lyra::render::engine::{Engine, EventDriver, Graph, NodeKind, SoundSource};
+use lyra::render::{hihat, kick_drum, Gain, Pan};
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    const BPM: u32 = 90;
+    const SAMPLE_RATE: u32 = 48000;
+    const BLOCK_SIZE: usize = 128;
+    const OUT_PATH: &str = "output/example/engine.wav";
+
+    let mut score = Score::new(ScoreCreateInfo {
+        title: "Drum Track",
+        ..Default::default()
+    });
+
+    let attr = AttributesCreateInfo {
+        clefs: ["percussion"].into(),
+        ..Default::default()
+    };
+
+    score.part("Kick", |p| {
+        p.measure(|m| {
+            m.attributes(&attr);
+            m.metronome("quarter", BPM);
+            m.dynamics("mf");
+            m.note_repeat("E4:q", 4);
+        });
+    })?;
+
+    score.part("High Hat", |p| {
+        p.measure(|m| {
+            m.attributes(&attr);
+            m.metronome("quarter", BPM);
+            m.dynamics("mp");
+            m.note_repeat("E4:e", 8);
+        });
+    })?;
+
+    // Kick -> Bus, High Hat -> Gain -> Pan -> Bus, Bus -> Output
+    let mut graph = Graph::new();
+    let kick = graph.add_node(NodeKind::Track {
+        source: SoundSource::Instrument(kick_drum()),
+        driver: EventDriver::MusicXmlPart(Box::new(score.parts.remove(0))),
+    });
+    let hat = graph.add_node(NodeKind::Track {
+        source: SoundSource::Instrument(hihat()),
+        driver: EventDriver::MusicXmlPart(Box::new(score.parts.remove(0))),
+    });
+    let hat_gain = graph
+        .add_node(NodeKind::Effect { effect: Box::new(Gain { amount: 0.25 }) });
+    let hat_pan = graph.add_node(NodeKind::Effect {
+        effect: Box::new(Pan { position: -0.3 }),
+    });
+    let bus = graph.add_node(NodeKind::Bus);
+    let out = graph.add_node(NodeKind::Output { target: OUT_PATH.to_string() });
+
+    graph.connect(kick, bus)?;
+    graph.connect(hat, hat_gain)?;
+    graph.connect(hat_gain, hat_pan)?;
+    graph.connect(hat_pan, bus)?;
+    graph.connect(bus, out)?;
+
+    Engine {
+        sample_rate: SAMPLE_RATE,
+        block_size: BLOCK_SIZE,
+        node_graph: graph,
+        threads: None,
+    }
+    .render();
+
+    Ok(())
+}
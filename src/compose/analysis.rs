@@ -0,0 +1,178 @@
+use std::ops::Range;
+
+use super::music::{Measure, MeasureItem, Mode, Part, Score};
+
+/// Krumhansl-Kessler probe tone ratings for a major key, starting at the tonic
+const MAJOR_PROFILE: [f64; 12] =
+    [6.35, 2.23, 3.48, 2.33, 4.38, 4.09, 2.52, 5.19, 2.39, 3.66, 2.29, 2.88];
+
+/// Krumhansl-Kessler probe tone ratings for a minor key, starting at the tonic
+const MINOR_PROFILE: [f64; 12] =
+    [6.33, 2.68, 3.52, 5.38, 2.60, 3.53, 2.54, 4.75, 3.98, 2.69, 3.34, 3.17];
+
+/// One candidate key from `estimate_key`
+#[derive(Clone)]
+pub struct KeyEstimate {
+    /// Pitch class of the tonic, 0 = C, 1 = C#/Db ... 11 = B
+    pub tonic: u8,
+    pub mode: Mode,
+
+    /// Key signature as used by `Attributes::key_fifths`
+    pub fifths: i8,
+
+    /// Correlation between the pitch class profile of the music and the key
+    /// profile, from -1.0 to 1.0. Close candidates (ie. relative keys) mean
+    /// the estimate is ambiguous.
+    pub confidence: f64,
+}
+
+/// Total duration in quarter notes of each pitch class in the measures.
+/// Rests and unpitched notes are ignored.
+pub fn pitch_class_profile(measures: &[Measure]) -> [f64; 12] {
+    let mut profile = [0.0; 12];
+    let mut divisions = 1;
+
+    for measure in measures {
+        let attr = measure
+            .attributes
+            .as_ref()
+            .or(measure.effective_attributes.as_ref());
+        if let Some(attr) = attr {
+            divisions = attr.divisions.max(1);
+        }
+
+        for item in &measure.items {
+            let MeasureItem::Note(note) = item else {
+                continue;
+            };
+            let Some(pitch) = &note.pitch else {
+                continue;
+            };
+            let pc = (pitch.step.to_semitone() as i8
+                + pitch.alter.unwrap_or(0))
+            .rem_euclid(12);
+            profile[pc as usize] += note.duration as f64 / divisions as f64;
+        }
+    }
+    profile
+}
+
+/// Rank all 24 major and minor keys by how well they match a pitch class
+/// profile (Krumhansl-Schmuckler algorithm). The best match comes first. An
+/// empty profile gives every key a confidence of 0.0.
+pub fn estimate_key(profile: &[f64; 12]) -> Vec<KeyEstimate> {
+    let mut estimates = Vec::with_capacity(24);
+
+    for tonic in 0..12u8 {
+        for (mode, key_profile) in
+            [(Mode::Major, &MAJOR_PROFILE), (Mode::Minor, &MINOR_PROFILE)]
+        {
+            // Rotate the key profile so that index 0 is C
+            let rotated: [f64; 12] = std::array::from_fn(|pc| {
+                key_profile[(pc + 12 - tonic as usize) % 12]
+            });
+
+            estimates.push(KeyEstimate {
+                tonic,
+                fifths: fifths_for(tonic, &mode),
+                mode,
+                confidence: correlation(profile, &rotated),
+            });
+        }
+    }
+
+    estimates.sort_by(|a, b| b.confidence.total_cmp(&a.confidence));
+    estimates
+}
+
+/// Key signature of a major or minor key. Enharmonic keys prefer the
+/// signature with fewer accidentals, F# major is used over Gb major.
+fn fifths_for(tonic: u8, mode: &Mode) -> i8 {
+    let major_tonic = match mode {
+        Mode::Minor => (tonic + 3) % 12,
+        _ => tonic,
+    };
+    let fifths = (major_tonic as i8 * 7).rem_euclid(12);
+    if fifths > 6 {
+        fifths - 12
+    } else {
+        fifths
+    }
+}
+
+/// Pearson correlation coefficient
+fn correlation(a: &[f64; 12], b: &[f64; 12]) -> f64 {
+    let mean_a = a.iter().sum::<f64>() / 12.0;
+    let mean_b = b.iter().sum::<f64>() / 12.0;
+
+    let mut cov = 0.0;
+    let mut var_a = 0.0;
+    let mut var_b = 0.0;
+    for (x, y) in a.iter().zip(b) {
+        cov += (x - mean_a) * (y - mean_b);
+        var_a += (x - mean_a).powi(2);
+        var_b += (y - mean_b).powi(2);
+    }
+
+    if var_a == 0.0 || var_b == 0.0 {
+        return 0.0;
+    }
+    cov / (var_a * var_b).sqrt()
+}
+
+impl Part {
+    /// Ranked key candidates for the whole part
+    pub fn estimate_key(&self) -> Vec<KeyEstimate> {
+        estimate_key(&pitch_class_profile(&self.measures))
+    }
+
+    /// Ranked key candidates for a range of measures (indices, not measure
+    /// numbers), ie. to find modulations. The range is clamped to the
+    /// measures of the part, so measures past the end count as empty.
+    pub fn estimate_key_in(&self, measures: Range<usize>) -> Vec<KeyEstimate> {
+        let end = measures.end.min(self.measures.len());
+        let start = measures.start.min(end);
+        estimate_key(&pitch_class_profile(&self.measures[start..end]))
+    }
+
+    /// Estimate the key and write it to the attributes at the start of the
+    /// part. Returns the estimate that was used, or None if the part has no
+    /// pitched notes or no attributes.
+    pub fn detect_key(&mut self) -> Option<KeyEstimate> {
+        let best = self.estimate_key().into_iter().next()?;
+        if best.confidence <= 0.0 {
+            return None;
+        }
+        if !self.set_key(best.fifths, best.mode.clone()) {
+            return None;
+        }
+        Some(best)
+    }
+}
+
+impl Score {
+    /// Ranked key candidates for all parts together
+    pub fn estimate_key(&self) -> Vec<KeyEstimate> {
+        let mut profile = [0.0; 12];
+        for part in &self.parts {
+            for (total, pc) in
+                profile.iter_mut().zip(pitch_class_profile(&part.measures))
+            {
+                *total += pc;
+            }
+        }
+        estimate_key(&profile)
+    }
+
+    /// Estimate the key of the whole score and write it to every part
+    pub fn detect_key(&mut self) -> Option<KeyEstimate> {
+        let best = self.estimate_key().into_iter().next()?;
+        if best.confidence <= 0.0 {
+            return None;
+        }
+        for part in &mut self.parts {
+            part.set_key(best.fifths, best.mode.clone());
+        }
+        Some(best)
+    }
+}
@@ -0,0 +1,265 @@
+use super::music::{Part, Score};
+use crate::render::engine::NoteEvent;
+
+/// Positions closer than this (in quarter notes) to a groove step are
+/// considered to be on that step
+const GRID_TOLERANCE: f64 = 1e-6;
+
+/// The subdivision a swing feel is applied to
+#[derive(Clone, Copy)]
+pub enum SwingResolution {
+    Eighth,
+    Sixteenth,
+}
+
+impl SwingResolution {
+    /// Length in quarter notes of one swung pair of notes
+    pub fn pair_beats(&self) -> f64 {
+        match self {
+            Self::Eighth => 1.0,
+            Self::Sixteenth => 0.5,
+        }
+    }
+}
+
+/// Delays every second note of a subdivision pair.
+pub struct Swing {
+    /// Share of the pair given to the first note in percent. 50.0 is
+    /// straight, 66.7 is a triplet shuffle and 75.0 is a dotted feel.
+    pub percent: f64,
+    pub resolution: SwingResolution,
+}
+
+impl Swing {
+    pub fn to_groove(&self) -> Groove {
+        let pair = self.resolution.pair_beats();
+        Groove {
+            subdivision: pair / 2.0,
+            steps: vec![
+                GrooveStep::default(),
+                GrooveStep {
+                    timing: pair * (self.percent / 100.0 - 0.5),
+                    velocity: 1.0,
+                },
+            ],
+        }
+    }
+}
+
+impl From<Swing> for Groove {
+    fn from(swing: Swing) -> Self {
+        swing.to_groove()
+    }
+}
+
+/// A timing and velocity template on a grid of equal subdivisions. The steps
+/// repeat from the start of every measure, so a groove for a full bar of 4/4
+/// in 16ths has 16 steps with a subdivision of 0.25.
+#[derive(Clone)]
+pub struct Groove {
+    /// Length of one step in quarter notes
+    pub subdivision: f64,
+    pub steps: Vec<GrooveStep>,
+}
+
+#[derive(Clone, Copy)]
+pub struct GrooveStep {
+    /// Offset in quarter notes, positive values play late
+    pub timing: f64,
+
+    /// Multiplier applied to the velocity of notes starting on the step
+    pub velocity: f64,
+}
+
+impl Default for GrooveStep {
+    fn default() -> Self {
+        Self { timing: 0.0, velocity: 1.0 }
+    }
+}
+
+impl Groove {
+    /// Move events that start or end on a groove step. Notes between steps
+    /// are left alone.
+    pub fn apply(&self, events: &mut [NoteEvent]) {
+        for event in events.iter_mut() {
+            let spb = event.position.seconds_per_beat;
+            let end_beat =
+                event.position.beat + (event.end - event.start) / spb;
+
+            // Moving the end with the step it lands on keeps legato notes
+            // connected to the following (shifted) note
+            if let Some(step) = self.step_at(end_beat) {
+                event.end += step.timing * spb;
+            }
+            if let Some(step) = self.step_at(event.position.beat) {
+                event.start = (event.start + step.timing * spb).max(0.0);
+                event.velocity =
+                    (event.velocity * step.velocity).clamp(0.0, 1.0);
+            }
+            event.end = event.end.max(event.start);
+        }
+    }
+
+    /// Collect the events of a part and apply the groove
+    pub fn apply_part(&self, part: &Part) -> Vec<NoteEvent> {
+        let mut events = part.collect_events();
+        self.apply(&mut events);
+        events
+    }
+
+    /// Collect the events of every part in a score and apply the groove
+    pub fn apply_score(&self, score: &Score) -> Vec<Vec<NoteEvent>> {
+        score.parts.iter().map(|p| self.apply_part(p)).collect()
+    }
+
+    /// Build a groove from how the notes of an existing part deviate from a
+    /// grid of `steps` subdivisions. Timing is the average deviation of the
+    /// onsets closest to each step, velocity is the average velocity on the
+    /// step relative to the whole part. Steps without notes stay neutral.
+    pub fn extract(
+        part: &Part,
+        subdivision: f64,
+        steps: usize,
+    ) -> Result<Self, String> {
+        if steps == 0 {
+            return Err("Groove needs at least one step".to_string());
+        }
+        if !(subdivision > 0.0 && subdivision.is_finite()) {
+            return Err(format!(
+                "Groove subdivision should be positive, got {}",
+                subdivision
+            ));
+        }
+
+        let events = part.collect_events();
+
+        // Per step: (sum of deviations, sum of velocities, count)
+        let mut acc = vec![(0.0, 0.0, 0usize); steps];
+        for event in &events {
+            let pos = event.position.beat / subdivision;
+            let nearest = pos.round();
+            let step = (nearest as i64).rem_euclid(steps as i64) as usize;
+            acc[step].0 += (pos - nearest) * subdivision;
+            acc[step].1 += event.velocity;
+            acc[step].2 += 1;
+        }
+
+        let mean_velocity = if events.is_empty() {
+            0.0
+        } else {
+            events.iter().map(|e| e.velocity).sum::<f64>() / events.len() as f64
+        };
+
+        let steps = acc
+            .into_iter()
+            .map(|(timing, velocity, count)| {
+                if count == 0 || mean_velocity == 0.0 {
+                    return GrooveStep::default();
+                }
+                GrooveStep {
+                    timing: timing / count as f64,
+                    velocity: velocity / count as f64 / mean_velocity,
+                }
+            })
+            .collect();
+
+        Ok(Self { subdivision, steps })
+    }
+
+    /// Groove step that a position (quarter notes from the start of the
+    /// measure) falls on, if any
+    fn step_at(&self, beat: f64) -> Option<&GrooveStep> {
+        if self.steps.is_empty() {
+            return None;
+        }
+        let pos = beat / self.subdivision;
+        let nearest = pos.round();
+        if ((pos - nearest) * self.subdivision).abs() > GRID_TOLERANCE {
+            return None;
+        }
+        let index = (nearest as i64).rem_euclid(self.steps.len() as i64);
+        self.steps.get(index as usize)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compose::AttributesCreateInfo;
+
+    const STRAIGHT: [&str; 2] = ["C4:e", "C4:e"];
+
+    /// One measure of 4/4 repeating the notes and rests of a beat
+    fn part(beat: &[&str]) -> Part {
+        let mut part = Part::new("P1", "Groove");
+        part.measure(|m| {
+            m.attributes(&AttributesCreateInfo::default());
+            for item in beat.repeat(4) {
+                match item.contains(':') {
+                    true => m.note(item),
+                    false => m.rest(item),
+                }
+            }
+        });
+        part
+    }
+
+    fn groove(timings: &[f64]) -> Groove {
+        Groove {
+            subdivision: 0.25,
+            steps: timings
+                .iter()
+                .map(|&timing| GrooveStep { timing, velocity: 1.0 })
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn swing_delays_off_beats() {
+        let swing =
+            Swing { percent: 75.0, resolution: SwingResolution::Eighth };
+        let straight = part(&STRAIGHT).collect_events();
+        let swung = swing.to_groove().apply_part(&part(&STRAIGHT));
+        assert_eq!(swung.len(), 8);
+        for (i, (a, b)) in straight.iter().zip(&swung).enumerate() {
+            let spb = a.position.seconds_per_beat;
+            let offset = if i % 2 == 1 { 0.25 * spb } else { 0.0 };
+            assert!((b.start - a.start - offset).abs() < 1e-9, "note {}", i);
+        }
+    }
+
+    #[test]
+    fn step_at_finds_steps_on_the_grid() {
+        let groove = groove(&[0.0, 0.1, 0.2, 0.3]);
+        assert_eq!(groove.step_at(0.5).unwrap().timing, 0.2);
+        // Steps repeat after the last one
+        assert_eq!(groove.step_at(1.25).unwrap().timing, 0.1);
+        assert!(groove.step_at(0.3).is_none());
+        assert!(Groove { subdivision: 0.25, steps: vec![] }
+            .step_at(0.0)
+            .is_none());
+    }
+
+    #[test]
+    fn extracted_groove_reproduces_the_part() {
+        // Off-beats a 32nd note late
+        let late = part(&["C4:e", "t", "C4:s."]);
+        let groove = Groove::extract(&late, 0.5, 2).unwrap();
+        assert_eq!(groove.steps[0].timing, 0.0);
+        assert_eq!(groove.steps[1].timing, 0.125);
+
+        let applied = groove.apply_part(&part(&STRAIGHT));
+        let late = late.collect_events();
+        assert_eq!(late.len(), applied.len());
+        for (a, b) in late.iter().zip(&applied) {
+            assert!((a.start - b.start).abs() < 1e-9);
+            assert!((a.velocity - b.velocity).abs() < 1e-9);
+        }
+    }
+
+    #[test]
+    fn extract_rejects_empty_grids() {
+        assert!(Groove::extract(&part(&STRAIGHT), 0.5, 0).is_err());
+        assert!(Groove::extract(&part(&STRAIGHT), 0.0, 2).is_err());
+    }
+}
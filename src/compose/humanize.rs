@@ -0,0 +1,179 @@
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+
+use super::music::{Part, Score};
+use crate::render::engine::NoteEvent;
+
+/// Beats closer than this (in quarter notes) are considered the same beat
+const BEAT_TOLERANCE: f64 = 1e-6;
+
+/// Random deviations applied to collected note events so that renders do not
+/// sound machine exact. The same seed always produces the same deviations for
+/// the same events.
+#[derive(Clone)]
+pub struct Humanize {
+    pub seed: u64,
+
+    /// Maximum deviation of note starts and ends in milliseconds. Each
+    /// boundary is moved by a random amount in -timing_ms..timing_ms.
+    pub timing_ms: f64,
+
+    /// Maximum deviation of the normalized velocity (0.0 to 1.0)
+    pub velocity: f64,
+
+    /// Fixed offsets for notes starting on specific beats of a measure
+    pub beat_offsets: Vec<BeatOffset>,
+}
+
+/// Pushes (negative offset) or pulls (positive offset) all notes that start on
+/// a given beat of the measure.
+#[derive(Clone)]
+pub struct BeatOffset {
+    /// Quarter notes from the start of the measure, ie. 1.0 for beat 2 in 4/4
+    pub beat: f64,
+    pub offset_ms: f64,
+}
+
+impl Default for Humanize {
+    fn default() -> Self {
+        Self { seed: 0, timing_ms: 10.0, velocity: 0.05, beat_offsets: vec![] }
+    }
+}
+
+impl Humanize {
+    /// Apply deviations to events in place. Events are visited in order, so
+    /// the order of the events must be the same between renders for the
+    /// result to be reproducible.
+    pub fn apply(&self, events: &mut [NoteEvent]) {
+        let mut rng = StdRng::seed_from_u64(self.seed);
+
+        for event in events.iter_mut() {
+            let offset = self
+                .beat_offsets
+                .iter()
+                .find(|o| (o.beat - event.position.beat).abs() < BEAT_TOLERANCE)
+                .map(|o| o.offset_ms)
+                .unwrap_or(0.0);
+
+            let start_jitter = Self::jitter(&mut rng, self.timing_ms);
+            let end_jitter = Self::jitter(&mut rng, self.timing_ms);
+            let velocity_jitter = Self::jitter(&mut rng, self.velocity);
+
+            let duration = event.end - event.start;
+            event.start =
+                (event.start + (offset + start_jitter) / 1000.0).max(0.0);
+
+            // Keep a sliver of the note when jitter would invert it
+            event.end = (event.end + (offset + end_jitter) / 1000.0)
+                .max(event.start + duration * 0.5);
+            event.velocity = (event.velocity + velocity_jitter).clamp(0.0, 1.0);
+        }
+    }
+
+    /// Collect the events of a part and humanize them
+    pub fn apply_part(&self, part: &Part) -> Vec<NoteEvent> {
+        let mut events = part.collect_events();
+        self.apply(&mut events);
+        events
+    }
+
+    /// Collect and humanize the events of every part in a score. Each part is
+    /// seeded differently so parts do not drift together.
+    pub fn apply_score(&self, score: &Score) -> Vec<Vec<NoteEvent>> {
+        score
+            .parts
+            .iter()
+            .enumerate()
+            .map(|(i, part)| {
+                let humanize = Humanize {
+                    seed: self.seed.wrapping_add(i as u64),
+                    ..self.clone()
+                };
+                let mut events = part.collect_events();
+                humanize.apply(&mut events);
+                events
+            })
+            .collect()
+    }
+
+    fn jitter(rng: &mut StdRng, spread: f64) -> f64 {
+        if spread > 0.0 {
+            rng.random_range(-spread..spread)
+        } else {
+            0.0
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compose::AttributesCreateInfo;
+
+    /// Two measures of quarter notes
+    fn events() -> Vec<NoteEvent> {
+        let mut part = Part::new("P1", "Humanize");
+        for _ in 0..2 {
+            part.measure(|m| {
+                m.attributes(&AttributesCreateInfo::default());
+                m.note_repeat("C4:q", 4);
+            });
+        }
+        part.collect_events()
+    }
+
+    fn humanized(humanize: &Humanize) -> Vec<NoteEvent> {
+        let mut events = events();
+        humanize.apply(&mut events);
+        events
+    }
+
+    #[test]
+    fn same_seed_gives_same_events() {
+        let humanize = Humanize { seed: 7, ..Default::default() };
+        let a = humanized(&humanize);
+        let b = humanized(&humanize);
+        for (a, b) in a.iter().zip(&b) {
+            assert_eq!(a.start.to_bits(), b.start.to_bits());
+            assert_eq!(a.end.to_bits(), b.end.to_bits());
+            assert_eq!(a.velocity.to_bits(), b.velocity.to_bits());
+        }
+
+        let other = humanized(&Humanize { seed: 8, ..Default::default() });
+        assert!(a.iter().zip(&other).any(|(a, b)| a.start != b.start));
+    }
+
+    #[test]
+    fn deviations_stay_within_range() {
+        let humanize =
+            Humanize { timing_ms: 15.0, velocity: 0.1, ..Default::default() };
+        let timing = humanize.timing_ms / 1000.0;
+        for seed in 0..20 {
+            let events = humanized(&Humanize { seed, ..humanize.clone() });
+            for (a, b) in self::events().iter().zip(&events) {
+                assert!(b.start >= 0.0);
+                assert!((b.start - a.start).abs() <= timing);
+                assert!((b.end - a.end).abs() <= timing);
+                assert!((b.velocity - a.velocity).abs() <= humanize.velocity);
+            }
+        }
+    }
+
+    #[test]
+    fn beat_offsets_move_notes_on_the_beat() {
+        let humanize = Humanize {
+            timing_ms: 0.0,
+            velocity: 0.0,
+            beat_offsets: vec![BeatOffset { beat: 1.0, offset_ms: 20.0 }],
+            ..Default::default()
+        };
+        let events = humanized(&humanize);
+        for (a, b) in self::events().iter().zip(&events) {
+            let offset = if a.position.beat == 1.0 { 0.02 } else { 0.0 };
+            assert!((b.start - a.start - offset).abs() < 1e-9);
+            assert!((b.end - a.end - offset).abs() < 1e-9);
+            assert_eq!(a.velocity, b.velocity);
+        }
+        assert_eq!(events.iter().filter(|e| e.position.beat == 1.0).count(), 2);
+    }
+}
@@ -1,8 +1,14 @@
+pub mod analysis;
+pub mod groove;
+pub mod humanize;
 pub mod midi;
 pub mod musescore;
 pub mod music;
 pub mod xml;
 
+pub use analysis::*;
+pub use groove::*;
+pub use humanize::*;
 pub use midi::*;
 pub use musescore::*;
 pub use music::*;
@@ -2,7 +2,8 @@ use std::collections::HashMap;
 
 /// Music theory related concepts. Based around the MusicXML spec.
 use crate::compose::xml;
-use crate::render::engine::NoteEvent;
+use crate::render::engine::{MusicalPosition, NoteEvent};
+use crate::render::pitch::{PitchCurve, PitchGlide, Vibrato};
 
 // TODO determine if i need to refer to xmlwriteable things generically
 //pub trait XmlWritable {
@@ -148,6 +149,9 @@ pub struct Part {
     name: String,
     instrument: Option<MusicXmlInstrument>,
 
+    /// Vibrato played on notes under a wavy line
+    pub vibrato: Vibrato,
+
     /// For measures (and notes) to have reference to the most recently defined
     /// <attributes>, this value is updated on measure creation if the measure
     /// contains attributes. This is then rolled forward to each new measure
@@ -159,12 +163,21 @@ pub struct Part {
 pub struct RenderState {
     pub cursor: f64,       // seconds
     pub saved_cursor: f64, //seconds
+    pub beat: f64,         // quarter notes from the start of the measure
+    pub saved_beat: f64,   // quarter notes from the start of the measure
     pub tempo_bpm: f64,
     pub velocity: f64,
     pub divisions: u32,
     pub active_voices: Vec<NoteEvent>,
-    // Pitch => (start_time_beats, total_duration_beats)
-    pub ongoing_ties: HashMap<u8, (f64, f64)>,
+    // Pitch => event started by the first note of the tie
+    pub ongoing_ties: HashMap<u8, NoteEvent>,
+
+    /// Index of an event that glides to the next note, and whether it glides
+    /// in steps (glissando) or continuously (slide)
+    pub pending_glide: Option<(usize, bool)>,
+
+    /// Inside a wavy line, notes are played with vibrato
+    pub wavy_line: bool,
 }
 
 impl Default for RenderState {
@@ -172,11 +185,15 @@ impl Default for RenderState {
         Self {
             cursor: 0.0,
             saved_cursor: 0.0,
+            beat: 0.0,
+            saved_beat: 0.0,
             tempo_bpm: 120.0,
             velocity: 80.0 / 127.0, // mf
             divisions: 480,
             active_voices: vec![],
             ongoing_ties: HashMap::new(),
+            pending_glide: None,
+            wavy_line: false,
         }
     }
 }
@@ -199,8 +216,14 @@ impl RenderState {
         dur_beats * self.seconds_per_beat()
     }
 
+    /// Convert a duration in ticks to a duration in quarter notes
+    pub fn ticks_to_beats(&self, duration: u32) -> f64 {
+        duration as f64 / self.divisions as f64
+    }
+
     pub fn save_cursor(&mut self) {
         self.saved_cursor = self.cursor;
+        self.saved_beat = self.beat;
     }
 }
 impl Part {
@@ -210,6 +233,7 @@ impl Part {
             id: id.to_string(),
             name: name.to_string(),
             instrument: None,
+            vibrato: Vibrato::default(),
             // TODO move state to function level if possible
             effective_attributes: None,
         }
@@ -221,90 +245,227 @@ impl Part {
         // MusicXML part will collect note events during parsing
         let mut note_events: Vec<NoteEvent> = vec![];
 
-        // Flatten measures
-        // TODO delegate this to a part fn
-        let items: Vec<&MeasureItem> =
-            self.measures.iter().flat_map(|m| m.items.iter()).collect();
+        for (index, measure) in self.measures.iter().enumerate() {
+            if let Some(attrs) = &measure.attributes {
+                state.divisions = attrs.divisions;
+            }
 
-        for item in &items {
-            match item {
-                MeasureItem::Note(note) => {
-                    if !note.is_chord {
-                        state.save_cursor();
-                    }
+            // Voices may end at different times when backup/forward is used,
+            // the next measure starts after the longest one.
+            let mut measure_end = state.cursor;
 
-                    let note_duration = state.ticks_to_secs(note.duration);
+            // Pickup notes keep their position relative to the end of the
+            // bar, ie. a quarter note pickup in 4/4 starts on beat 4
+            state.beat = if index == 0 && measure.implicit {
+                measure.incomplete_beats()
+            } else {
+                0.0
+            };
 
-                    if let Some(pitch) = &note.pitch {
-                        let mut event = NoteEvent {
-                            velocity: state.velocity,
-                            start: state.saved_cursor,
-                            end: state.saved_cursor + note_duration,
-                            freq: Some(pitch.to_frequency()),
-                        };
+            for item in &measure.items {
+                match item {
+                    MeasureItem::Note(note) => {
+                        if !note.is_chord {
+                            state.save_cursor();
+                        }
 
-                        match note.tie {
-                            Some(StartStop::Start) => {
-                                state.ongoing_ties.insert(
-                                    pitch.to_semitone(),
-                                    (state.saved_cursor, note_duration),
-                                );
+                        let note_duration = state.ticks_to_secs(note.duration);
+                        let position = MusicalPosition {
+                            measure: index,
+                            beat: state.saved_beat,
+                            seconds_per_beat: state.seconds_per_beat(),
+                        };
 
-                                // do not push to event buffer, this is a tie
-                                // so the event is not over yet
-                                continue;
+                        if let Some(pitch) = &note.pitch {
+                            let freq = pitch.to_frequency();
+                            let mut event = NoteEvent {
+                                velocity: state.velocity,
+                                start: state.saved_cursor,
+                                end: state.saved_cursor + note_duration,
+                                freq: Some(freq),
+                                unpitched: None,
+                                instrument: note.instrument.clone(),
+                                position,
+                                pitch: self.pitch_curve(
+                                    note,
+                                    note_duration,
+                                    &mut state,
+                                ),
+                            };
+
+                            // A glissando or slide ends on the next note
+                            if let Some((index, stepped)) =
+                                state.pending_glide.take()
+                            {
+                                let from = &mut note_events[index];
+                                from.pitch.glide = Some(PitchGlide {
+                                    to: freq,
+                                    start: 0.0,
+                                    end: from.end - from.start,
+                                    stepped,
+                                });
                             }
-                            Some(StartStop::Stop) => {
-                                if let Some((prev_start, prev_duration)) = state
-                                    .ongoing_ties
-                                    .remove(&pitch.to_semitone())
-                                {
+                            let pushed = note_events.len();
+
+                            match note.tie {
+                                Some(StartStop::Start) => {
+                                    // Do not push to event buffer, this is a
+                                    // tie so the event is not over yet
+                                    state
+                                        .ongoing_ties
+                                        .insert(pitch.to_semitone(), event);
+                                }
+                                Some(StartStop::Stop) => {
                                     // Change note event timing based on tie
-                                    event.start = prev_start;
-                                    event.end = prev_start
-                                        + prev_duration
-                                        + note_duration;
+                                    if let Some(tied) = state
+                                        .ongoing_ties
+                                        .remove(&pitch.to_semitone())
+                                    {
+                                        // Curves of the last note start
+                                        // where it is notated
+                                        let offset = event.start - tied.start;
+                                        event.pitch.delay(offset);
+                                        if tied.pitch.vibrato.is_some() {
+                                            event.pitch.vibrato =
+                                                tied.pitch.vibrato;
+                                        }
+                                        event.start = tied.start;
+                                        event.position = tied.position;
+                                    }
+                                    note_events.push(event);
                                 }
+                                None => note_events.push(event),
+                            }
+
+                            if note_events.len() > pushed {
+                                state.pending_glide = note
+                                    .glide_start()
+                                    .map(|stepped| (pushed, stepped));
                             }
-                            None => {}
+                        } else if note.unpitched.is_some() {
+                            note_events.push(NoteEvent {
+                                velocity: state.velocity,
+                                start: state.saved_cursor,
+                                end: state.saved_cursor + note_duration,
+                                freq: None,
+                                unpitched: note.unpitched.clone(),
+                                instrument: note.instrument.clone(),
+                                position,
+                                pitch: PitchCurve::default(),
+                            });
                         }
-                        note_events.push(event);
-                    } else if let Some(_) = &note.unpitched {
-                        note_events.push(NoteEvent {
-                            velocity: state.velocity,
-                            start: state.saved_cursor,
-                            end: state.saved_cursor + note_duration,
-                            freq: None,
-                        });
-                    }
 
-                    if !note.is_chord {
-                        state.cursor += note_duration;
+                        if !note.is_chord {
+                            state.cursor += note_duration;
+                            state.beat += state.ticks_to_beats(note.duration);
+                        }
                     }
-                }
 
-                MeasureItem::Direction(dir) => match &dir.kind {
-                    DirectionType::Metronome { per_minute, .. } => {
-                        state.tempo_bpm = *per_minute as f64;
+                    MeasureItem::Direction(dir) => match &dir.kind {
+                        DirectionType::Metronome { per_minute, .. } => {
+                            state.tempo_bpm = *per_minute as f64;
+                        }
+                        DirectionType::Dynamics(dynamics) => {
+                            state.velocity = dynamics.normalized_velocity();
+                        }
+                        _ => {}
+                    },
+
+                    MeasureItem::Forward(fwd) => {
+                        state.cursor += state.ticks_to_secs(fwd.duration);
+                        state.beat += state.ticks_to_beats(fwd.duration);
                     }
-                    DirectionType::Dynamics(dynamics) => {
-                        state.velocity = dynamics.normalized_velocity();
+
+                    MeasureItem::Backup(bak) => {
+                        state.cursor -= state.ticks_to_secs(bak.duration);
+                        state.beat -= state.ticks_to_beats(bak.duration);
                     }
-                    _ => {}
-                },
 
-                MeasureItem::Forward(fwd) => {
-                    state.cursor += state.ticks_to_secs(fwd.duration);
+                    MeasureItem::Barline(_) => {}
                 }
+                measure_end = measure_end.max(state.cursor);
+            }
+
+            state.cursor = measure_end;
+        }
+        note_events
+    }
+
+    /// Time in seconds of a position given as a measure index and quarter
+    /// notes from the start of that measure. Tempo changes are followed the
+    /// same way as in collect_events(). Positions after the last measure
+    /// continue with the last tempo and time signature.
+    pub fn time_at(&self, measure: usize, beat: f64) -> f64 {
+        let mut state = RenderState::default();
+
+        for (index, m) in self.measures.iter().enumerate() {
+            if let Some(attrs) = &m.attributes {
+                state.divisions = attrs.divisions;
+            }
+
+            state.beat = if index == 0 && m.implicit {
+                m.incomplete_beats()
+            } else {
+                0.0
+            };
+
+            // (beat, seconds, seconds per beat) from which a tempo applies
+            let mut tempo_points =
+                vec![(state.beat, state.cursor, state.seconds_per_beat())];
+            let mut measure_end = state.cursor;
 
-                MeasureItem::Backup(bak) => {
-                    state.cursor -= state.ticks_to_secs(bak.duration);
+            for item in &m.items {
+                match item {
+                    MeasureItem::Note(note) if !note.is_chord => {
+                        state.cursor += state.ticks_to_secs(note.duration);
+                        state.beat += state.ticks_to_beats(note.duration);
+                    }
+                    MeasureItem::Forward(fwd) => {
+                        state.cursor += state.ticks_to_secs(fwd.duration);
+                        state.beat += state.ticks_to_beats(fwd.duration);
+                    }
+                    MeasureItem::Backup(bak) => {
+                        state.cursor -= state.ticks_to_secs(bak.duration);
+                        state.beat -= state.ticks_to_beats(bak.duration);
+                    }
+                    MeasureItem::Direction(dir) => {
+                        if let DirectionType::Metronome { per_minute, .. } =
+                            &dir.kind
+                        {
+                            state.tempo_bpm = *per_minute as f64;
+                            tempo_points.push((
+                                state.beat,
+                                state.cursor,
+                                state.seconds_per_beat(),
+                            ));
+                        }
+                    }
+                    _ => {}
                 }
+                measure_end = measure_end.max(state.cursor);
+            }
 
-                MeasureItem::Barline(_) => {}
+            if index == measure {
+                let (b, t, spb) = tempo_points
+                    .iter()
+                    .filter(|(b, ..)| *b <= beat)
+                    .max_by(|x, y| x.0.total_cmp(&y.0))
+                    .copied()
+                    .unwrap_or(tempo_points[0]);
+                return t + (beat - b) * spb;
             }
+
+            state.cursor = measure_end;
         }
-        note_events
+
+        let measure_beats = self
+            .effective_attributes
+            .as_ref()
+            .map(|a| a.measure_ticks() as f64 / a.divisions as f64)
+            .unwrap_or(4.0);
+        let extra_measures = (measure - self.measures.len()) as f64;
+        state.cursor
+            + (extra_measures * measure_beats + beat) * state.seconds_per_beat()
     }
 
     pub fn nominal_duration_seconds(&self) -> f64 {
@@ -312,7 +473,6 @@ impl Part {
             let quarter_note_duration = 60.0 / bpm;
             (ticks as f64 / divisions as f64) * quarter_note_duration
         }
-        let mut cursor = 0.0 as f64;
         let mut max_cursor = 0.0 as f64;
 
         let mut tempo_bpm = 120.0 as f64;
@@ -323,6 +483,11 @@ impl Part {
                 divisions = attrs.divisions;
             }
 
+            // Each measure (including a short pickup) starts where the
+            // longest voice of the previous one ended, same as in
+            // collect_events()
+            let mut cursor = max_cursor;
+
             for item in &measure.items {
                 match item {
                     MeasureItem::Note(note) => {
@@ -452,17 +617,116 @@ impl Part {
     }
 
     pub fn measure<F>(&mut self, f: F)
+    where
+        F: FnOnce(&mut Measure),
+    {
+        self.push_measure(false, f);
+    }
+
+    /// Add an anacrusis. The pickup is an implicit measure numbered 0 that
+    /// only contains the notes before the first downbeat, it is shorter than
+    /// the time signature.
+    pub fn pickup<F>(&mut self, f: F) -> Result<(), String>
+    where
+        F: FnOnce(&mut Measure),
+    {
+        if !self.measures.is_empty() {
+            return Err("Pickup must be the first measure".to_string());
+        }
+        let m = self.new_measure(true, |m| {
+            m.number("0");
+            f(m);
+        });
+        let attr = m
+            .attributes
+            .as_ref()
+            .or(m.effective_attributes.as_ref())
+            .ok_or("Pickup must set the measure attributes")?;
+        let (ticks, full) = (m.duration_ticks(), attr.measure_ticks());
+        if ticks == 0 || ticks >= full {
+            return Err(format!(
+                "Pickup of {} ticks should be shorter than a measure of {} \
+                 ticks",
+                ticks, full
+            ));
+        }
+        self.add_measure(m);
+        Ok(())
+    }
+
+    /// Replace the key at the start of the part, ie. after estimating the key
+    /// of imported material. The key is updated up to the next measure that
+    /// defines its own attributes. Returns false if the part has no
+    /// attributes to update.
+    pub fn set_key(&mut self, fifths: i8, mode: Mode) -> bool {
+        let Some(first) =
+            self.measures.iter().position(|m| m.attributes.is_some())
+        else {
+            return false;
+        };
+
+        let mut reached_end = true;
+        for (i, measure) in self.measures.iter_mut().enumerate().skip(first) {
+            if i > first && measure.attributes.is_some() {
+                reached_end = false;
+                break;
+            }
+            for attr in
+                [&mut measure.attributes, &mut measure.effective_attributes]
+                    .into_iter()
+                    .flatten()
+            {
+                attr.key_fifths = fifths;
+                attr.key_mode = mode.clone();
+            }
+        }
+
+        // New measures inherit the key as well
+        if reached_end {
+            if let Some(attr) = &mut self.effective_attributes {
+                attr.key_fifths = fifths;
+                attr.key_mode = mode;
+            }
+        }
+        true
+    }
+
+    /// Number for the next measure. Counting continues from the last
+    /// numbered measure, implicit measures are skipped.
+    fn next_measure_number(&self) -> String {
+        self.measures
+            .iter()
+            .rev()
+            .filter(|m| !m.implicit)
+            .find_map(|m| m.number.parse::<usize>().ok())
+            .map(|n| (n + 1).to_string())
+            .unwrap_or_else(|| "1".to_string())
+    }
+
+    fn push_measure<F>(&mut self, implicit: bool, f: F)
+    where
+        F: FnOnce(&mut Measure),
+    {
+        let m = self.new_measure(implicit, f);
+        self.add_measure(m);
+    }
+
+    fn new_measure<F>(&self, implicit: bool, f: F) -> Measure
     where
         F: FnOnce(&mut Measure),
     {
         let mut m = Measure::new(MeasureCreateInfo {
-            measure_number: self.measures.len() + 1,
+            measure_number: self.next_measure_number(),
+            implicit,
             attributes: None,
             effective_attributes: self.effective_attributes.clone(),
         });
 
         f(&mut m);
+        m
+    }
 
+    fn add_measure(&mut self, m: Measure) {
         // After measure is created by user, save attributes to part.
         // This assumes that measures are added to the part in order.
         // TODO i think removing this part-level state is good but i need to
@@ -480,23 +744,17 @@ impl Part {
             .get_current_attr()
             .expect("Cannot add empty measure: no previous attributes found");
 
-        // Calculate ticks for full measure duration
-        let beat_unit_ratio = 4.0 / attrs.time_beat_type as f32;
-        let beat_ticks = (attrs.divisions as f32 * beat_unit_ratio) as u32;
-        let total_divisions = attrs.time_beats as u32 * beat_ticks;
-
         let rest = Note::new(NoteCreateInfo {
             pitch: None,
             kind: NoteType::Whole, // not used
             is_measure_rest: true,
-            duration_override: Some(total_divisions),
+            duration_override: Some(attrs.measure_ticks()),
             ..NoteCreateInfo::default()
         });
 
-        let number = self.measures.len() + 1;
-
         let mut measure = Measure::new(MeasureCreateInfo {
-            measure_number: number,
+            measure_number: self.next_measure_number(),
+            implicit: false,
             attributes: None,
             effective_attributes: self.effective_attributes.clone(),
         });
@@ -540,6 +798,44 @@ impl Part {
                 sound: ci.sound,
             }));
     }
+
+    /// MIDI playback settings of the part instrument, if it has one
+    pub fn midi_instrument(&self) -> Option<&MidiInstrument> {
+        self.instrument.as_ref().map(|i| &i.midi)
+    }
+
+    /// Bends and vibrato of a note from its notations. Glides are set once
+    /// the next note is known.
+    fn pitch_curve(
+        &self,
+        note: &Note,
+        duration: f64,
+        state: &mut RenderState,
+    ) -> PitchCurve {
+        let mut curve = PitchCurve::default();
+        let mut wavy_line_ends = false;
+        for item in note.notation_items() {
+            match item {
+                NotationType::Bend(bend) => {
+                    curve.bend = bend.curve(duration);
+                }
+                NotationType::WavyLine(StartStop::Start) => {
+                    state.wavy_line = true;
+                }
+                NotationType::WavyLine(StartStop::Stop) => {
+                    wavy_line_ends = true;
+                }
+                _ => {}
+            }
+        }
+        if state.wavy_line {
+            curve.vibrato = Some(self.vibrato);
+        }
+        if wavy_line_ends {
+            state.wavy_line = false;
+        }
+        curve
+    }
 }
 
 /// All modes allowed in <mode> from the MusicXML spec
@@ -747,6 +1043,11 @@ impl Attributes {
         }
     }
 
+    /// Ticks in a full measure of the time signature
+    pub fn measure_ticks(&self) -> u32 {
+        self.divisions * 4 * self.time_beats as u32 / self.time_beat_type as u32
+    }
+
     pub fn write_to<W: std::io::Write>(
         &self,
         writer: &mut xml::Writer<W>,
@@ -914,7 +1215,14 @@ pub struct Forward {
 /// proceeding it.
 pub struct Measure {
     pub items: Vec<MeasureItem>,
-    number: usize,
+
+    /// MusicXML measure numbers are tokens, not integers. Custom labels such
+    /// as "12a" are allowed.
+    number: String,
+
+    /// Implicit measures (ie. pickups) are not counted in measure numbering
+    /// and may be shorter than the time signature.
+    implicit: bool,
     pub attributes: Option<Attributes>,
 
     /// This value is cloned from the parent part of the measure. This is used
@@ -924,7 +1232,8 @@ pub struct Measure {
 }
 
 pub struct MeasureCreateInfo {
-    measure_number: usize,
+    measure_number: String,
+    implicit: bool,
     attributes: Option<Attributes>,
     effective_attributes: Option<Attributes>,
 }
@@ -933,6 +1242,7 @@ impl Measure {
     pub fn new(ci: MeasureCreateInfo) -> Self {
         Self {
             number: ci.measure_number,
+            implicit: ci.implicit,
             items: Vec::new(),
             attributes: ci.attributes,
             effective_attributes: ci.effective_attributes,
@@ -943,13 +1253,11 @@ impl Measure {
         &self,
         writer: &mut xml::Writer<W>,
     ) -> std::io::Result<()> {
-        writer.open_tag(
-            "measure",
-            Some(xml::XmlAttributes::new(vec![(
-                "number",
-                &self.number.to_string(),
-            )])),
-        )?;
+        let mut attrs = vec![("number", self.number.as_str())];
+        if self.implicit {
+            attrs.push(("implicit", "yes"));
+        }
+        writer.open_tag("measure", Some(xml::XmlAttributes::new(attrs)))?;
 
         if let Some(attributes) = &self.attributes {
             attributes.write_to(writer)?;
@@ -970,6 +1278,55 @@ impl Measure {
         Ok(())
     }
 
+    /// Override the measure number assigned by the part. Following measures
+    /// continue counting from it if it is numeric.
+    pub fn number(&mut self, number: &str) {
+        self.number = number.to_string();
+    }
+
+    /// Mark the measure as implicit so it does not count in the measure
+    /// numbering, ie. the second half of a measure split by a repeat.
+    pub fn implicit(&mut self) {
+        self.implicit = true;
+    }
+
+    pub fn is_implicit(&self) -> bool {
+        self.implicit
+    }
+
+    /// Length of the measure content in ticks. Multiple voices written with
+    /// backup and forward are as long as the longest voice.
+    pub fn duration_ticks(&self) -> u32 {
+        let mut cursor: i64 = 0;
+        let mut end: i64 = 0;
+        for item in &self.items {
+            match item {
+                MeasureItem::Note(note) if !note.is_chord => {
+                    cursor += note.duration as i64
+                }
+                MeasureItem::Forward(fwd) => cursor += fwd.duration as i64,
+                MeasureItem::Backup(bak) => cursor -= bak.duration as i64,
+                _ => {}
+            }
+            end = end.max(cursor);
+        }
+        end as u32
+    }
+
+    /// Quarter notes missing from the measure compared to a full measure of
+    /// its time signature. This is the length of the rest before the first
+    /// note of a pickup.
+    pub fn incomplete_beats(&self) -> f64 {
+        let Some(attr) =
+            self.attributes.as_ref().or(self.effective_attributes.as_ref())
+        else {
+            return 0.0;
+        };
+        let missing =
+            attr.measure_ticks().saturating_sub(self.duration_ticks());
+        missing as f64 / attr.divisions as f64
+    }
+
     /// The most generalized way to append to a measure. Functions that
     /// add any measure elements to a part use this internally.
     pub fn item(&mut self, item: MeasureItem) {
@@ -1072,6 +1429,24 @@ impl Measure {
         self.item(MeasureItem::Note(note));
     }
 
+    /// Attach a notation to the last note of the measure, ie. a slide or a
+    /// bend
+    pub fn notation(&mut self, notation: NotationType) {
+        let note = self
+            .items
+            .iter_mut()
+            .rev()
+            .find_map(|item| match item {
+                MeasureItem::Note(note) => Some(note),
+                _ => None,
+            })
+            .expect("Notations must follow a note");
+        note.notations
+            .get_or_insert_with(|| Notations::new(vec![]))
+            .items
+            .push(notation);
+    }
+
     /// Convenience function to add a rest to a measure.
     /// Parses rests from custom DSL format specifying duration
     /// ie. "h." -> dotted half rest
@@ -1388,6 +1763,18 @@ impl StartStop {
             Self::Stop => "stop".to_string(),
         }
     }
+
+    /// Write an empty element with this value as its type attribute
+    fn write_tag<W: std::io::Write>(
+        &self,
+        tag: &str,
+        writer: &mut xml::Writer<W>,
+    ) -> std::io::Result<()> {
+        writer.self_closing_tag(
+            tag,
+            Some(xml::XmlAttributes::new(vec![("type", &self.to_string())])),
+        )
+    }
 }
 
 // TODO embed in Note struct
@@ -1401,10 +1788,21 @@ pub enum NotationType {
     Tied(Tied),
     Slur,
     Tuplet(Tuplet),
-    Glissando,
-    Slide,
+
+    /// Line to the next note, played in semitone steps
+    Glissando(StartStop),
+
+    /// Line to the next note, played as a continuous slide
+    Slide(StartStop),
     Ornaments,
+
+    /// <wavy-line> of <ornaments>, played as vibrato from the start to the
+    /// stop note
+    WavyLine(StartStop),
     Technical,
+
+    /// <bend> of <technical>
+    Bend(Bend),
     Articulations,
     // TODO Check if there is naming conflict for Dynamics,
     Fermata,
@@ -1414,6 +1812,49 @@ pub enum NotationType {
     OtherNotation,
 }
 
+/// String bend by a number of semitones
+pub struct Bend {
+    /// Semitones, negative values bend down
+    pub alter: f64,
+
+    /// The note starts at the bent pitch (<pre-bend/>)
+    pub pre_bend: bool,
+
+    /// The bend returns to the notated pitch (<release/>)
+    pub release: bool,
+
+    /// Start and end of the bend in percent of the note duration
+    pub first_beat: f64,
+    pub last_beat: f64,
+}
+
+impl Bend {
+    pub fn new(alter: f64) -> Self {
+        Self {
+            alter,
+            pre_bend: false,
+            release: false,
+            first_beat: 25.0,
+            last_beat: 75.0,
+        }
+    }
+
+    /// Breakpoints in cents for a note of `duration` seconds
+    pub fn curve(&self, duration: f64) -> Vec<(f64, f64)> {
+        let cents = self.alter * 100.0;
+        let first = duration * self.first_beat / 100.0;
+        let last = duration * self.last_beat / 100.0;
+        match (self.pre_bend, self.release) {
+            (true, true) => vec![(0.0, cents), (first, cents), (last, 0.0)],
+            (true, false) => vec![(0.0, cents)],
+            (false, true) => {
+                vec![(first, 0.0), ((first + last) / 2.0, cents), (last, 0.0)]
+            }
+            (false, false) => vec![(first, 0.0), (last, cents)],
+        }
+    }
+}
+
 // TODO implement optional attributes from
 // https://www.w3.org/2021/06/musicxml40/musicxml-reference/elements/tuplet/
 pub struct Tuplet {
@@ -1433,12 +1874,42 @@ impl NotationType {
                     &t.kind.to_string(),
                 )])),
             ),
+            Self::Glissando(k) => k.write_tag("glissando", writer),
+            Self::Slide(k) => k.write_tag("slide", writer),
+            Self::WavyLine(k) => {
+                writer.open_tag("ornaments", None)?;
+                k.write_tag("wavy-line", writer)?;
+                writer.close_tag("ornaments")
+            }
+            Self::Bend(bend) => {
+                writer.open_tag("technical", None)?;
+                writer.open_tag(
+                    "bend",
+                    Some(xml::XmlAttributes::new(vec![
+                        ("first-beat", &bend.first_beat.to_string()),
+                        ("last-beat", &bend.last_beat.to_string()),
+                    ])),
+                )?;
+                writer.text_element("bend-alter", &bend.alter.to_string())?;
+                if bend.pre_bend {
+                    writer.self_closing_tag("pre-bend", None)?;
+                }
+                if bend.release {
+                    writer.self_closing_tag("release", None)?;
+                }
+                writer.close_tag("bend")?;
+                writer.close_tag("technical")
+            }
             _ => panic!("Notation type not implemented"),
         }
     }
 }
 
 impl Notations {
+    pub fn new(items: Vec<NotationType>) -> Self {
+        Self { items, footnote: None, level: None }
+    }
+
     pub fn write_to<W: std::io::Write>(
         &self,
         writer: &mut xml::Writer<W>,
@@ -1481,9 +1952,10 @@ impl TimeModification {
     }
 }
 
+#[derive(Clone)]
 pub struct Unpitched {
-    display_step: NaturalTone,
-    display_octave: i8,
+    pub display_step: NaturalTone,
+    pub display_octave: i8,
 }
 
 pub struct Note {
@@ -1499,6 +1971,10 @@ pub struct Note {
     dots: Option<u8>,
     pub tie: Option<StartStop>,
     is_measure_rest: bool,
+
+    /// Id of the <score-instrument> playing the note, used to tell apart the
+    /// instruments of a percussion part (ie. "P1-I36")
+    pub instrument: Option<String>,
 }
 
 pub struct NoteCreateInfo {
@@ -1514,6 +1990,7 @@ pub struct NoteCreateInfo {
     pub dots: Option<u8>,
     pub tie: Option<StartStop>,
     pub is_measure_rest: bool,
+    pub instrument: Option<String>,
 
     // TODO this is to handle the case of measure rests. Measure rests should
     // ignore the duration derived from note type and other elements, measure
@@ -1537,6 +2014,7 @@ impl Default for NoteCreateInfo {
             dots: None,
             tie: None,
             is_measure_rest: false,
+            instrument: None,
             duration_override: None,
         }
     }
@@ -1588,6 +2066,19 @@ impl std::str::FromStr for NoteCreateInfo {
 }
 
 impl Note {
+    pub fn notation_items(&self) -> &[NotationType] {
+        self.notations.as_ref().map_or(&[], |n| n.items.as_slice())
+    }
+
+    /// Whether a glissando (stepped) or slide (continuous) starts on the note
+    fn glide_start(&self) -> Option<bool> {
+        self.notation_items().iter().find_map(|item| match item {
+            NotationType::Glissando(StartStop::Start) => Some(true),
+            NotationType::Slide(StartStop::Start) => Some(false),
+            _ => None,
+        })
+    }
+
     pub fn new(opt: NoteCreateInfo) -> Self {
         // Measure rests should ignore the normal duration calculation.
         // Measure rests will have a duration value that fills the whole
@@ -1621,6 +2112,7 @@ impl Note {
             dots: opt.dots,
             tie: opt.tie,
             is_measure_rest: opt.is_measure_rest,
+            instrument: opt.instrument,
         }
     }
 
@@ -1664,6 +2156,13 @@ impl Note {
 
         writer.text_element("duration", &self.duration.to_string())?;
 
+        if let Some(id) = &self.instrument {
+            writer.self_closing_tag(
+                "instrument",
+                Some(xml::XmlAttributes::new(vec![("id", id)])),
+            )?;
+        }
+
         if !self.is_measure_rest {
             writer.text_element("type", &self.kind.to_string())?;
         }
@@ -2031,7 +2530,7 @@ impl std::str::FromStr for ChordQuality {
 pub struct Scale;
 pub struct Voice;
 
-#[derive(Clone)]
+#[derive(Clone, PartialEq)]
 pub enum NaturalTone {
     C,
     D,
@@ -2068,3 +2567,29 @@ impl NaturalTone {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pickup(part: &mut Part, notes: &str) -> Result<(), String> {
+        let attributes = AttributesCreateInfo::default();
+        part.pickup(|m| {
+            m.attributes(&attributes);
+            m.note(notes);
+        })
+    }
+
+    #[test]
+    fn pickup_is_shorter_than_a_measure() {
+        pickup(&mut Part::new("P1", "Lead"), "C4:q").unwrap();
+        assert!(pickup(&mut Part::new("P1", "Lead"), "C4:w").is_err());
+
+        let mut part = Part::new("P1", "Lead");
+        part.measure(|m| {
+            m.attributes(&AttributesCreateInfo::default());
+            m.note("C4:w");
+        });
+        assert!(pickup(&mut part, "C4:q").is_err());
+    }
+}
@@ -0,0 +1,269 @@
+use super::dsp::ModulationTarget;
+use super::effect::{AudioEffect, EffectDescription};
+use super::processor::AudioBuffer;
+use super::types::{Float, Seconds};
+use crate::compose::Part;
+
+/// Shape of the transition from a breakpoint to the next one
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Curve {
+    Linear,
+
+    /// Constant ratio per second, sounds even for frequencies and gain.
+    /// Falls back to linear if the values are not both positive or both
+    /// negative.
+    Exponential,
+
+    /// Hold the value until the next breakpoint
+    Step,
+}
+
+#[derive(Clone)]
+pub struct Breakpoint {
+    pub time: Seconds,
+    pub value: Float,
+
+    /// Curve from this breakpoint to the next
+    pub curve: Curve,
+}
+
+/// Values of one parameter over time. Before the first breakpoint the lane
+/// holds the first value, after the last one it holds the last value.
+#[derive(Clone)]
+pub struct AutomationLane {
+    pub target: ModulationTarget,
+    points: Vec<Breakpoint>,
+}
+
+impl AutomationLane {
+    pub fn new(target: ModulationTarget) -> Self {
+        Self { target, points: vec![] }
+    }
+
+    /// Add a breakpoint at a time in seconds
+    pub fn point(mut self, time: Seconds, value: Float, curve: Curve) -> Self {
+        let index = self.points.partition_point(|p| p.time <= time);
+        self.points.insert(index, Breakpoint { time, value, curve });
+        self
+    }
+
+    /// Add a breakpoint at a musical position of a part: a measure index and
+    /// quarter notes from the start of the measure
+    pub fn point_at_beat(
+        self,
+        part: &Part,
+        measure: usize,
+        beat: Float,
+        value: Float,
+        curve: Curve,
+    ) -> Self {
+        self.point(part.time_at(measure, beat), value, curve)
+    }
+
+    pub fn points(&self) -> &[Breakpoint] {
+        &self.points
+    }
+
+    pub fn value_at(&self, time: Seconds) -> Option<Float> {
+        let next = self.points.partition_point(|p| p.time <= time);
+        let Some(from) = next.checked_sub(1).map(|i| &self.points[i]) else {
+            return self.points.first().map(|p| p.value);
+        };
+        let Some(to) = self.points.get(next) else {
+            return Some(from.value);
+        };
+
+        let x = (time - from.time) / (to.time - from.time);
+        let (a, b) = (from.value, to.value);
+        let value = match from.curve {
+            Curve::Step => a,
+            Curve::Exponential if a * b > 0.0 => a * (b / a).powf(x),
+            Curve::Linear | Curve::Exponential => a + (b - a) * x,
+        };
+        Some(value)
+    }
+}
+
+/// Wraps an effect and sets its parameters from automation lanes before
+/// every sample. Time is counted from the first sample the wrapper processes,
+/// which is the start of the track for track and master effects.
+pub struct Automated {
+    pub effect: Box<dyn AudioEffect>,
+    lanes: Vec<AutomationLane>,
+
+    /// Samples processed so far
+    position: usize,
+}
+
+impl Automated {
+    /// Fails if a lane targets a parameter the effect doesn't have. Each
+    /// lane is tried once with its first value, which the effect is set to
+    /// at the first sample anyway.
+    pub fn new(
+        mut effect: Box<dyn AudioEffect>,
+        lanes: Vec<AutomationLane>,
+    ) -> Result<Self, String> {
+        for lane in &lanes {
+            let Some(first) = lane.points.first() else {
+                continue;
+            };
+            if !effect.set_param(lane.target, first.value) {
+                return Err(format!(
+                    "Effect cannot be automated with {:?}",
+                    lane.target
+                ));
+            }
+        }
+        Ok(Self { effect, lanes, position: 0 })
+    }
+
+    pub fn lanes(&self) -> &[AutomationLane] {
+        &self.lanes
+    }
+}
+
+impl Automated {
+    fn run(
+        &mut self,
+        buffer: &mut AudioBuffer,
+        sidechain: Option<&AudioBuffer>,
+        sample_rate: u32,
+    ) {
+        // Single frame buffers, allocated once for the whole block
+        let empty_frame = |buffer: &AudioBuffer| match buffer {
+            AudioBuffer::Mono(_) => AudioBuffer::Mono(vec![0.0]),
+            AudioBuffer::Stereo(_) => AudioBuffer::Stereo(vec![(0.0, 0.0)]),
+        };
+        let mut frame = empty_frame(buffer);
+        let mut sc_frame = sidechain.map(empty_frame);
+
+        for i in 0..buffer.len() {
+            let t = (self.position + i) as Seconds / sample_rate as Seconds;
+            for lane in &self.lanes {
+                // Lanes were checked against the effect on creation
+                if let Some(value) = lane.value_at(t) {
+                    self.effect.set_param(lane.target, value);
+                }
+            }
+
+            // Process one frame at a time so parameter changes land on the
+            // exact sample
+            load_frame(&mut frame, buffer, i);
+            match (sidechain, &mut sc_frame) {
+                (Some(sc), Some(sc_frame)) => {
+                    load_frame(sc_frame, sc, i);
+                    self.effect.process_sidechain(
+                        &mut frame,
+                        sc_frame,
+                        sample_rate,
+                    );
+                }
+                _ => self.effect.process(&mut frame, sample_rate),
+            }
+            // Effects may turn mono into stereo, ie. Pan
+            if let (AudioBuffer::Stereo(_), AudioBuffer::Mono(_)) =
+                (&frame, &*buffer)
+            {
+                *buffer = buffer.to_stereo();
+            }
+            match &frame {
+                AudioBuffer::Mono(f) => buffer.set(i, f[0]),
+                AudioBuffer::Stereo(f) => buffer.set_stereo(i, f[0]),
+            }
+        }
+        self.position += buffer.len();
+    }
+}
+
+/// Copy frame `i` of a buffer into a single frame buffer of the same type,
+/// frames past the end are silent
+fn load_frame(frame: &mut AudioBuffer, buffer: &AudioBuffer, i: usize) {
+    match (frame, buffer) {
+        (AudioBuffer::Mono(f), AudioBuffer::Mono(b)) => {
+            f[0] = b.get(i).copied().unwrap_or(0.0)
+        }
+        (AudioBuffer::Stereo(f), AudioBuffer::Stereo(b)) => {
+            f[0] = b.get(i).copied().unwrap_or((0.0, 0.0))
+        }
+        _ => unreachable!("Frame matches the buffer type"),
+    }
+}
+
+impl AudioEffect for Automated {
+    /// The wrapped effect with its current parameter values, the lanes are
+    /// not part of the description
+    fn describe(&self) -> Option<EffectDescription> {
+        self.effect.describe()
+    }
+
+    fn process(&mut self, buffer: &mut AudioBuffer, sample_rate: u32) {
+        self.run(buffer, None, sample_rate);
+    }
+
+    fn process_sidechain(
+        &mut self,
+        buffer: &mut AudioBuffer,
+        sidechain: &AudioBuffer,
+        sample_rate: u32,
+    ) {
+        self.run(buffer, Some(sidechain), sample_rate);
+    }
+
+    fn tail_time(&self, sample_rate: u32) -> Seconds {
+        self.effect.tail_time(sample_rate)
+    }
+
+    fn latency(&self) -> usize {
+        self.effect.latency()
+    }
+
+    fn reset(&mut self) {
+        self.position = 0;
+        self.effect.reset();
+    }
+
+    fn set_param(&mut self, target: ModulationTarget, value: Float) -> bool {
+        self.effect.set_param(target, value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::render::effect::{Compressor, Pan};
+
+    fn bursts() -> AudioBuffer {
+        AudioBuffer::Mono((0..400).map(|i| ((i / 50) % 2) as Float).collect())
+    }
+
+    #[test]
+    fn sidechain_matches_the_plain_effect() {
+        let mut plain = AudioBuffer::Stereo(vec![(0.5, 0.25); 400]);
+        let mut automated = plain.clone();
+        Compressor::ducker().process_sidechain(&mut plain, &bursts(), 8000);
+
+        let mut effect =
+            Automated::new(Box::new(Compressor::ducker()), vec![]).unwrap();
+        effect.process_sidechain(&mut automated, &bursts(), 8000);
+        for i in 0..plain.len() {
+            assert_eq!(plain.get_stereo(i), automated.get_stereo(i));
+        }
+        assert_eq!(effect.describe().unwrap().kind, "Compressor");
+    }
+
+    #[test]
+    fn automated_pan_turns_mono_into_stereo() {
+        let lane = AutomationLane::new(ModulationTarget::PanPosition)
+            .point(0.0, -1.0, Curve::Step)
+            .point(0.01, 1.0, Curve::Step);
+        let mut effect =
+            Automated::new(Box::new(Pan { position: 0.0 }), vec![lane])
+                .unwrap();
+        let mut buffer = bursts();
+        effect.process(&mut buffer, 8000);
+
+        assert_eq!(buffer.get_stereo(0), (0.0, 0.0));
+        assert_eq!(buffer.get_stereo(50), (1.0, 0.0));
+        assert_eq!(buffer.get_stereo(150), (0.0, 1.0));
+    }
+}
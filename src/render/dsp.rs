@@ -1,5 +1,6 @@
 /// Tools for generating and modulating signals
 use super::types::{Float, Seconds};
+use super::voice::Voice;
 
 pub mod signal {
     use std::cell::RefCell;
@@ -8,41 +9,358 @@ pub mod signal {
     use rand::Rng;
     use rand::SeedableRng;
 
+    use super::super::processor::AudioBuffer;
     use super::super::types::{Float, Hz, Seconds};
-    use super::wave;
+    use super::super::voice::Voice;
+    use super::super::wav::read_wav;
+    use super::spectrum::{fft, hann_window};
+    use super::{wave, Gate, ParametricEnvelope};
 
     pub enum SignalSource {
         Oscillator(Oscillator),
         Noise(Noise),
         Sampler(Sampler),
+        Pluck(Pluck),
+        Fm(Fm),
+        Additive(Additive),
         Silence,
     }
 
     impl SignalSource {
-        /// Noise and silence ignore t
+        /// Noise and silence ignore t. Used for modulation, where there is
+        /// no sample rate, so oscillators are not band-limited. That is fine
+        /// for LFOs.
         pub fn sample(&self, t: Seconds) -> Float {
             match self {
-                Self::Oscillator(osc) => osc.sample(t),
+                Self::Oscillator(osc) => {
+                    osc.wave.sample_phase(osc.freq * t + osc.phase, 0.0)
+                }
                 Self::Noise(n) => n.sample(),
-                //Self::Sampler(s) => s.sample(t),
+                Self::Sampler(s) => s.sample(s.freq, t),
+                Self::Pluck(p) => p.sample(p.freq),
+                Self::Fm(fm) => fm.sample(fm.freq),
+                Self::Additive(a) => a.sample(a.freq),
                 Self::Silence => 0.0,
-                _ => panic!("Not implemented"),
+            }
+        }
+
+        /// Sample oscillators at a frequency other than the one set, so
+        /// voices can share the source. Noise keeps its own state and is
+        /// sampled as usual.
+        pub fn sample_at(&self, freq: Hz, t: Seconds) -> Float {
+            match self {
+                Self::Oscillator(osc) => osc.wave.sample(freq, t),
+                Self::Sampler(s) => s.sample(freq, t),
+                Self::Pluck(p) => p.sample(freq),
+                Self::Fm(fm) => fm.sample(freq),
+                Self::Additive(a) => a.sample(freq),
+                _ => self.sample(t),
+            }
+        }
+
+        /// Fresh copy of a source that keeps state between samples, for
+        /// unison copy `copy` of a voice to render with. Noise is seeded from
+        /// both, so copies are as different as separate voices. None for
+        /// stateless sources.
+        pub fn for_voice(
+            &self,
+            voice: &Voice,
+            copy: usize,
+            sample_rate: u32,
+        ) -> Option<SignalSource> {
+            // Large odd stride keeps the seeds of copies apart from the
+            // seeds of the following voices
+            let seed = voice.index.wrapping_add(copy.wrapping_mul(0x9E37_79B9));
+            match self {
+                Self::Noise(n) => Some(Self::Noise(n.for_voice(seed))),
+                Self::Pluck(p) => {
+                    Some(Self::Pluck(p.for_voice(seed, sample_rate)))
+                }
+                Self::Fm(fm) => {
+                    Some(Self::Fm(fm.for_voice(voice, sample_rate)))
+                }
+                Self::Additive(a) => {
+                    Some(Self::Additive(a.for_voice(voice, sample_rate)))
+                }
+                _ => None,
+            }
+        }
+
+        /// Gate of a voice as the source plays it. One-shot samples ignore
+        /// the note-off, so their envelopes never release.
+        pub fn gate(&self, gate: Gate) -> Gate {
+            match self {
+                Self::Sampler(s) if s.mode == SampleMode::OneShot => {
+                    Gate { on: gate.on, off: None }
+                }
+                _ => gate,
+            }
+        }
+
+        /// Time the source keeps sounding after the gate closes
+        pub fn release_time(&self) -> Seconds {
+            match self {
+                Self::Sampler(s) => s.release_time(),
+                Self::Fm(fm) => fm.release_time(),
+                Self::Additive(a) => a.release_time(),
+                _ => 0.0,
             }
         }
 
         pub fn set_frequency(&mut self, freq: Float) {
-            if let SignalSource::Oscillator(ref mut osc) = self {
-                osc.freq = freq;
+            match self {
+                Self::Oscillator(osc) => osc.freq = freq,
+                Self::Sampler(s) => s.freq = freq,
+                Self::Pluck(p) => p.freq = freq,
+                Self::Fm(fm) => fm.freq = freq,
+                Self::Additive(a) => a.freq = freq,
+                _ => {}
             }
         }
     }
 
-    // TODO Implement
-    pub struct Sampler;
+    /// How a sampler plays past the end of its sustain loop
+    #[derive(Clone, Copy, Debug, PartialEq)]
+    pub enum SampleMode {
+        /// Play from start to end once, then stay silent. The sample ends
+        /// with the release of the note if that comes first.
+        NoLoop,
+
+        /// Play from start to end once regardless of the note length, ie.
+        /// drum hits and foley. The note-off is ignored.
+        OneShot,
+
+        /// Repeat the frames between `start` and `end` for as long as the
+        /// voice lasts. The last `crossfade` frames of the loop are blended
+        /// with the frames before the loop start, which hides the seam.
+        Loop { start: usize, end: usize, crossfade: usize },
+
+        /// Loop like `Loop` while the note is held. When the gate closes the
+        /// current pass plays on to `end` and playback continues into the
+        /// rest of the sample.
+        SustainLoop { start: usize, end: usize, crossfade: usize },
+    }
+
+    /// Plays a recording. Other pitches than the root are played by
+    /// resampling, so they are shorter or longer than the recording.
+    pub struct Sampler {
+        /// Mono frames of the recording
+        pub data: Vec<Float>,
+
+        /// Sample rate of the recording
+        pub sample_rate: u32,
+
+        /// Pitch the recording plays at its original speed
+        pub root: Hz,
+
+        /// Pitch used when the sampler is played without a voice
+        pub freq: Hz,
+
+        /// First frame played
+        pub start: usize,
+
+        /// Frame after the last one played without a loop
+        pub end: usize,
+        pub mode: SampleMode,
+    }
+
+    impl Sampler {
+        /// Sampler of a whole recording without a loop. Stereo files are
+        /// mixed down to mono.
+        pub fn new(data: Vec<Float>, sample_rate: u32, root: Hz) -> Self {
+            Self {
+                end: data.len(),
+                data,
+                sample_rate,
+                root,
+                freq: root,
+                start: 0,
+                mode: SampleMode::NoLoop,
+            }
+        }
+
+        /// Load a WAV file, ie. a recorded note with its pitch as root
+        pub fn load(path: &str, root: Hz) -> Self {
+            let (buffer, sample_rate) = read_wav(path);
+            let AudioBuffer::Mono(data) = buffer.to_mono() else {
+                unreachable!()
+            };
+            Self::new(data, sample_rate, root)
+        }
+
+        /// Loop between two frames for as long as the voice lasts,
+        /// crossfading over `crossfade` frames
+        pub fn looped(
+            mut self,
+            start: usize,
+            end: usize,
+            crossfade: usize,
+        ) -> Self {
+            self.check_loop(start, end);
+            self.mode = SampleMode::Loop { start, end, crossfade };
+            self
+        }
+
+        /// Loop between two frames while the note is held and play the rest
+        /// of the sample after the release
+        pub fn sustain_looped(
+            mut self,
+            start: usize,
+            end: usize,
+            crossfade: usize,
+        ) -> Self {
+            self.check_loop(start, end);
+            self.mode = SampleMode::SustainLoop { start, end, crossfade };
+            self
+        }
+
+        fn check_loop(&self, start: usize, end: usize) {
+            assert!(
+                self.loop_fits(start, end),
+                "Loop should lie within the sample"
+            );
+        }
+
+        /// Whether a loop from frame `start` up to `end` can be played, it
+        /// must not be empty, begin before `self.start` or end past the
+        /// data
+        pub fn loop_fits(&self, start: usize, end: usize) -> bool {
+            self.start <= start && start < end && end <= self.data.len()
+        }
+
+        /// Play to the end of the sample regardless of the note length
+        pub fn one_shot(mut self) -> Self {
+            self.mode = SampleMode::OneShot;
+            self
+        }
+
+        /// Time the sample keeps playing after the gate closes at the root
+        /// pitch, at most one pass of a sustain loop and the rest of the
+        /// sample, or all of a one-shot sample
+        pub fn release_time(&self) -> Seconds {
+            let frames = match self.mode {
+                SampleMode::SustainLoop { start, .. } => {
+                    self.end.saturating_sub(start)
+                }
+                SampleMode::OneShot => self.end.saturating_sub(self.start),
+                _ => 0,
+            };
+            frames as Seconds / self.sample_rate as Seconds
+        }
+
+        /// Sample `t` seconds into playback at a pitch while the note is
+        /// held. A pitch of zero plays at the original speed.
+        pub fn sample(&self, freq: Hz, t: Seconds) -> Float {
+            self.sample_gated(freq, t, &Gate { on: 0.0, off: None })
+        }
+
+        /// Sample `t` seconds into playback at a pitch, where `t` and the
+        /// gate share the same start. The gate only matters to sustain
+        /// loops.
+        pub fn sample_gated(&self, freq: Hz, t: Seconds, gate: &Gate) -> Float {
+            let ratio = if freq > 0.0 { freq / self.root } else { 1.0 };
+            let frames = |t: Seconds| {
+                self.start as Float + t * ratio * self.sample_rate as Float
+            };
+            let pos = frames(t);
+
+            match self.mode {
+                SampleMode::NoLoop | SampleMode::OneShot => {
+                    self.sample_to_end(pos)
+                }
+                SampleMode::Loop { start, end, crossfade } => {
+                    self.sample_loop(pos, start, end, crossfade)
+                }
+                SampleMode::SustainLoop { start, end, crossfade } => {
+                    let Some(off) = gate.off else {
+                        return self.sample_loop(pos, start, end, crossfade);
+                    };
+
+                    // The pass that is already crossfading towards the loop
+                    // start when the gate closes completes, the next one
+                    // runs on past the loop end
+                    let len = (end - start) as Float;
+                    let fade_start =
+                        end as Float - self.loop_fade(start, end, crossfade);
+                    let passes =
+                        ((frames(off) - fade_start) / len).ceil().max(0.0);
+                    if pos < fade_start + passes * len {
+                        return self.sample_loop(pos, start, end, crossfade);
+                    }
+                    self.sample_to_end(pos - passes * len)
+                }
+            }
+        }
+
+        fn sample_to_end(&self, pos: Float) -> Float {
+            if pos >= self.end as Float {
+                return 0.0;
+            }
+            self.interpolate(pos)
+        }
+
+        /// Crossfade length of a loop, limited by the loop length and the
+        /// frames before the loop start. The fields are public, so a `start`
+        /// moved past the loop after `looped` gives no crossfade.
+        fn loop_fade(
+            &self,
+            start: usize,
+            end: usize,
+            crossfade: usize,
+        ) -> Float {
+            (crossfade as Float)
+                .min(start.saturating_sub(self.start) as Float)
+                .min(end.saturating_sub(start) as Float)
+        }
+
+        fn sample_loop(
+            &self,
+            pos: Float,
+            start: usize,
+            end: usize,
+            crossfade: usize,
+        ) -> Float {
+            let fade = self.loop_fade(start, end, crossfade);
+            let (start, end) = (start as Float, end as Float);
+            let len = end - start;
+            let pos =
+                if pos >= end { start + (pos - start) % len } else { pos };
+
+            // Fade into the frames leading up to the loop start, which is
+            // where playback continues after the wrap
+            let fade_start = end - fade;
+            if fade > 0.0 && pos >= fade_start {
+                let w = (pos - fade_start) / fade;
+                return self.interpolate(pos) * (1.0 - w)
+                    + self.interpolate(pos - len) * w;
+            }
+            self.interpolate(pos)
+        }
+
+        /// Cubic Hermite interpolation between frames
+        fn interpolate(&self, pos: Float) -> Float {
+            let i = pos.floor() as isize;
+            let frac = pos - i as Float;
+            let at = |i: isize| -> Float {
+                if i < 0 {
+                    return 0.0;
+                }
+                self.data.get(i as usize).copied().unwrap_or(0.0)
+            };
+            let (y0, y1, y2, y3) = (at(i - 1), at(i), at(i + 1), at(i + 2));
+
+            let c1 = 0.5 * (y2 - y0);
+            let c2 = y0 - 2.5 * y1 + 2.0 * y2 - 0.5 * y3;
+            let c3 = 0.5 * (y3 - y0) + 1.5 * (y1 - y2);
+            ((c3 * frac + c2) * frac + c1) * frac + y1
+        }
+    }
 
     pub struct Oscillator {
         pub wave: wave::Wave,
         pub freq: Hz,
+
+        /// Start phase in cycles
         pub phase: Float,
     }
 
@@ -53,20 +371,32 @@ pub mod signal {
     }
 
     impl Oscillator {
-        /// Return sample of waveform at specified frequency (f) and time (t)
+        /// Sample of the wave `t` seconds in without band-limiting, so it
+        /// aliases at audio pitches
+        #[deprecated(note = "aliases at audio pitches, use `sample_at_rate`")]
         pub fn sample(&self, t: Seconds) -> Float {
-            self.wave.sample(self.freq, t)
+            self.wave.sample_phase(self.freq * t + self.phase, 0.0)
+        }
+
+        /// Band-limited sample of the wave `t` seconds in, when it is played
+        /// at `sample_rate`. The phase of a constant pitch advances by the
+        /// same increment per sample as the running phase of a voice.
+        pub fn sample_at_rate(&self, t: Seconds, sample_rate: u32) -> Float {
+            let increment = self.freq / sample_rate as Float;
+            self.wave.sample_phase(self.freq * t + self.phase, increment)
         }
     }
 
     pub struct Noise {
         pub kind: NoiseType,
+        pub seed: u64,
         pub rng: RefCell<StdRng>,
 
         // TODO used for brown noise. refactor probably needed
         pub last_sample: RefCell<Float>,
     }
 
+    #[derive(Clone, Copy)]
     pub enum NoiseType {
         White,
         Brown,
@@ -77,6 +407,7 @@ pub mod signal {
         pub fn new(kind: NoiseType, seed: u64) -> Self {
             Self {
                 kind,
+                seed,
                 rng: RefCell::new(StdRng::seed_from_u64(seed)),
                 last_sample: RefCell::new(0.0),
             }
@@ -84,6 +415,12 @@ pub mod signal {
     }
 
     impl Noise {
+        /// Fresh generator for a voice, seeded from the voice index so every
+        /// voice sounds different but renders are repeatable
+        pub fn for_voice(&self, voice: usize) -> Self {
+            Self::new(self.kind, self.seed.wrapping_add(voice as u64))
+        }
+
         pub fn sample(&self) -> Float {
             match self.kind {
                 NoiseType::White => {
@@ -100,6 +437,636 @@ pub mod signal {
             }
         }
     }
+
+    /// Sound that sets a plucked string vibrating
+    #[derive(Clone)]
+    pub enum Excitation {
+        /// White noise burst one period of the note long
+        Noise,
+
+        /// Recorded or designed burst, ie. a pick or finger attack
+        Buffer(Vec<Float>),
+    }
+
+    /// Karplus-Strong plucked string. An excitation burst circulates in a
+    /// delay line one period of the note long and loses its high harmonics
+    /// a little more on every pass.
+    ///
+    /// The string keeps its state between samples, so every voice renders
+    /// with its own copy from `for_voice`. Without one the source is silent.
+    pub struct Pluck {
+        pub excitation: Excitation,
+
+        /// 0 damps like the original algorithm, 1 keeps all harmonics
+        pub brightness: Float,
+
+        /// Decay stretch, 1 is the natural decay of the damping and larger
+        /// values ring longer
+        pub stretch: Float,
+
+        /// Where the string is picked as a fraction of its length. Picking
+        /// near the end (0) sounds thin, near the middle (0.5) round.
+        pub pick_position: Float,
+
+        /// Pitch used when the string is played without a voice
+        pub freq: Hz,
+        pub seed: u64,
+        state: RefCell<Option<StringState>>,
+    }
+
+    struct StringState {
+        sample_rate: u32,
+        delay: Vec<Float>,
+        write: usize,
+        burst: Vec<Float>,
+        elapsed: usize,
+
+        /// Last input and output of the fractional delay allpass
+        allpass: (Float, Float),
+
+        /// Last input of the damping filter
+        last: Float,
+    }
+
+    /// Lowest pitch the delay line can hold
+    const LOWEST_PLUCK: Hz = 20.0;
+
+    impl Pluck {
+        pub fn new(excitation: Excitation) -> Self {
+            Self {
+                excitation,
+                brightness: 0.5,
+                stretch: 1.0,
+                pick_position: 0.13,
+                freq: 110.0,
+                seed: 0,
+                state: RefCell::new(None),
+            }
+        }
+
+        /// Fresh string for a voice. Noise bursts are seeded from the voice
+        /// index so every note sounds a little different.
+        pub fn for_voice(&self, voice: usize, sample_rate: u32) -> Self {
+            let state = StringState {
+                sample_rate,
+                delay: vec![
+                    0.0;
+                    (sample_rate as Float / LOWEST_PLUCK) as usize
+                ],
+                write: 0,
+                burst: vec![],
+                elapsed: 0,
+                allpass: (0.0, 0.0),
+                last: 0.0,
+            };
+            Self {
+                excitation: self.excitation.clone(),
+                brightness: self.brightness,
+                stretch: self.stretch,
+                pick_position: self.pick_position,
+                freq: self.freq,
+                seed: self.seed.wrapping_add(voice as u64),
+                state: RefCell::new(Some(state)),
+            }
+        }
+
+        /// Weight of the previous sample in the two point damping filter
+        fn damping(&self) -> Float {
+            0.5 * (1.0 - self.brightness.clamp(0.0, 1.0))
+                / self.stretch.max(1.0)
+        }
+
+        /// Next sample of the string at a pitch
+        pub fn sample(&self, freq: Hz) -> Float {
+            let mut state = self.state.borrow_mut();
+            let Some(s) = state.as_mut() else {
+                return 0.0;
+            };
+            let sr = s.sample_rate as Float;
+            let period = sr / freq.max(LOWEST_PLUCK);
+            if s.elapsed == 0 {
+                s.burst = self.burst(period);
+            }
+
+            // The damping filter delays the loop by `damping` samples, the
+            // allpass makes up the fraction the delay line can't
+            let damping = self.damping();
+            let len = s.delay.len();
+            let loop_delay = (period - damping).clamp(1.1, len as Float);
+            let whole = (loop_delay - 0.1).floor() as usize;
+            let frac = loop_delay - whole as Float;
+            let c = (1.0 - frac) / (1.0 + frac);
+
+            let delayed = s.delay[(s.write + len - whole) % len];
+            let (ap_in, ap_out) = s.allpass;
+            let out = c * delayed + ap_in - c * ap_out;
+            s.allpass = (delayed, out);
+
+            let filtered = (1.0 - damping) * out + damping * s.last;
+            s.last = out;
+
+            let input =
+                filtered + s.burst.get(s.elapsed).copied().unwrap_or(0.0);
+            s.delay[s.write] = input;
+            s.write = (s.write + 1) % len;
+            s.elapsed += 1;
+            input
+        }
+
+        /// Excitation shaped by the pick position. Picking at a point
+        /// cancels the harmonics that have a node there.
+        fn burst(&self, period: Float) -> Vec<Float> {
+            let mut burst = match &self.excitation {
+                Excitation::Noise => {
+                    let mut rng = StdRng::seed_from_u64(self.seed);
+                    (0..period.round() as usize)
+                        .map(|_| rng.random_range(-1.0..1.0))
+                        .collect()
+                }
+                Excitation::Buffer(b) => b.clone(),
+            };
+
+            // A constant offset would circulate forever
+            let mean =
+                burst.iter().sum::<Float>() / burst.len().max(1) as Float;
+            burst.iter_mut().for_each(|x| *x -= mean);
+
+            let offset = (self.pick_position.clamp(0.0, 1.0) * period).round();
+            let offset = offset as usize;
+            if offset > 0 {
+                let source = burst.clone();
+                for i in offset..burst.len() {
+                    burst[i] -= source[i - offset];
+                }
+
+                // The difference of two bursts peaks twice as high
+                burst.iter_mut().for_each(|x| *x *= 0.5);
+            }
+            burst
+        }
+    }
+
+    #[derive(Clone, Copy, Debug)]
+    pub enum OperatorFrequency {
+        /// Multiple of the note frequency
+        Ratio(Float),
+
+        /// Frequency independent of the note, ie. for inharmonic bell tones
+        Fixed(Hz),
+    }
+
+    /// Sine oscillator of an FM synth
+    #[derive(Clone)]
+    pub struct Operator {
+        pub frequency: OperatorFrequency,
+
+        /// Output amplitude of carriers. For modulators this is the
+        /// modulation index, the peak phase deviation in radians of the
+        /// operators it modulates.
+        pub level: Float,
+
+        /// Shapes the level over the note, following the voice gate
+        pub envelope: Option<ParametricEnvelope>,
+
+        /// Modulation index of the operator modulating itself
+        pub feedback: Float,
+    }
+
+    impl Operator {
+        pub fn new(frequency: OperatorFrequency, level: Float) -> Self {
+            Self { frequency, level, envelope: None, feedback: 0.0 }
+        }
+    }
+
+    /// Routing between the operators of an FM synth
+    #[derive(Clone, Debug)]
+    pub struct Algorithm {
+        /// (modulator, target) pairs. Modulators have higher indices than
+        /// their targets, so every operator is evaluated after the
+        /// operators that modulate it.
+        pub modulations: Vec<(usize, usize)>,
+
+        /// Operators heard in the output
+        pub carriers: Vec<usize>,
+    }
+
+    impl Algorithm {
+        pub fn new(
+            modulations: Vec<(usize, usize)>,
+            carriers: Vec<usize>,
+        ) -> Self {
+            for &(modulator, target) in &modulations {
+                assert!(
+                    modulator > target,
+                    "Operator {} should not modulate operator {}",
+                    modulator,
+                    target
+                );
+            }
+            Self { modulations, carriers }
+        }
+
+        /// Each operator modulates the one below it and operator 0 is the
+        /// only carrier
+        pub fn stack(operators: usize) -> Self {
+            Self::new((1..operators).map(|i| (i, i - 1)).collect(), vec![0])
+        }
+
+        /// Every operator is a carrier, the synth plays like an additive
+        /// organ
+        pub fn parallel(operators: usize) -> Self {
+            Self::new(vec![], (0..operators).collect())
+        }
+
+        /// Stacks of two: odd operators modulate the even operator below
+        /// them, ie. the classic electric piano routing for four operators
+        pub fn pairs(operators: usize) -> Self {
+            Self::new(
+                (1..operators).step_by(2).map(|i| (i, i - 1)).collect(),
+                (0..operators).step_by(2).collect(),
+            )
+        }
+    }
+
+    /// Phase modulation synth. Operator levels can be modulated by layer
+    /// routes targeting `ModulationTarget::OperatorLevel`.
+    ///
+    /// Operators keep their phases between samples, so every voice renders
+    /// with its own copy from `for_voice`. Without one the source is silent.
+    pub struct Fm {
+        pub operators: Vec<Operator>,
+        pub algorithm: Algorithm,
+
+        /// Pitch used when the synth is played without a voice
+        pub freq: Hz,
+        state: RefCell<Option<FmState>>,
+    }
+
+    struct FmState {
+        sample_rate: u32,
+        gate: Gate,
+        elapsed: usize,
+
+        /// Phase of each operator in cycles
+        phases: Vec<Float>,
+
+        /// Last two outputs of each operator, for feedback
+        history: Vec<(Float, Float)>,
+
+        /// Operator levels before envelopes, after layer modulation
+        levels: Vec<Float>,
+
+        /// Output of each operator in the current sample, kept to not
+        /// allocate per sample
+        outputs: Vec<Float>,
+    }
+
+    impl Fm {
+        pub fn new(operators: Vec<Operator>, algorithm: Algorithm) -> Self {
+            let count = operators.len();
+            let routed =
+                algorithm.modulations.iter().flat_map(|&(m, t)| [m, t]);
+            assert!(
+                routed
+                    .chain(algorithm.carriers.iter().copied())
+                    .all(|i| i < count),
+                "Algorithm should only route the {} operators",
+                count
+            );
+            Self {
+                operators,
+                algorithm,
+                freq: 440.0,
+                state: RefCell::new(None),
+            }
+        }
+
+        /// Fresh copy for a voice with its operator envelopes following the
+        /// voice gate
+        pub fn for_voice(&self, voice: &Voice, sample_rate: u32) -> Self {
+            let count = self.operators.len();
+            let state = FmState {
+                sample_rate,
+                gate: voice.gate(),
+                elapsed: 0,
+                phases: vec![0.0; count],
+                history: vec![(0.0, 0.0); count],
+                levels: self.operators.iter().map(|op| op.level).collect(),
+                outputs: vec![0.0; count],
+            };
+            Self {
+                operators: self.operators.clone(),
+                algorithm: self.algorithm.clone(),
+                freq: self.freq,
+                state: RefCell::new(Some(state)),
+            }
+        }
+
+        /// Longest release of the carrier envelopes
+        pub fn release_time(&self) -> Seconds {
+            self.algorithm
+                .carriers
+                .iter()
+                .filter_map(|&i| self.operators[i].envelope.as_ref())
+                .map(|env| env.release_time())
+                .fold(0.0, Float::max)
+        }
+
+        /// Set the operator levels of the next sample, ie. from modulation
+        /// routes. `level` maps an operator index and its set level to the
+        /// level played.
+        pub fn modulate_levels<F>(&self, level: F)
+        where
+            F: Fn(usize, Float) -> Float,
+        {
+            if let Some(s) = self.state.borrow_mut().as_mut() {
+                for (i, op) in self.operators.iter().enumerate() {
+                    s.levels[i] = level(i, op.level);
+                }
+            }
+        }
+
+        /// Next sample of the synth at a pitch
+        pub fn sample(&self, freq: Hz) -> Float {
+            let mut state = self.state.borrow_mut();
+            let Some(s) = state.as_mut() else {
+                return 0.0;
+            };
+            let sr = s.sample_rate as Float;
+            let t = s.elapsed as Float / sr;
+            let count = self.operators.len();
+
+            // Operators not computed yet in this sample don't modulate
+            s.outputs.fill(0.0);
+            for i in (0..count).rev() {
+                let op = &self.operators[i];
+                let modulation: Float = self
+                    .algorithm
+                    .modulations
+                    .iter()
+                    .filter(|&&(_, target)| target == i)
+                    .map(|&(modulator, _)| s.outputs[modulator])
+                    .sum();
+
+                // Averaging the last two outputs tames feedback noise
+                let (a, b) = s.history[i];
+                let feedback = op.feedback * (a + b) / 2.0;
+
+                let envelope = op
+                    .envelope
+                    .as_ref()
+                    .map_or(1.0, |env| env.value_gated(t, s.gate));
+                let phase = 2.0 * std::f64::consts::PI * s.phases[i];
+                let out = (phase + modulation + feedback).sin()
+                    * s.levels[i]
+                    * envelope;
+                s.outputs[i] = out;
+                s.history[i] = (out, a);
+
+                let op_freq = match op.frequency {
+                    OperatorFrequency::Ratio(ratio) => freq * ratio,
+                    OperatorFrequency::Fixed(hz) => hz,
+                };
+                s.phases[i] = (s.phases[i] + op_freq / sr).fract();
+            }
+
+            s.elapsed += 1;
+            self.algorithm.carriers.iter().map(|&i| s.outputs[i]).sum()
+        }
+    }
+
+    /// Sine component of an additive sound
+    #[derive(Clone, Debug)]
+    pub struct Partial {
+        /// Multiple of the note frequency
+        pub ratio: Float,
+        pub amplitude: Float,
+
+        /// Start phase in cycles
+        pub phase: Float,
+
+        /// Shapes the amplitude over the note, following the voice gate
+        pub envelope: Option<ParametricEnvelope>,
+    }
+
+    impl Partial {
+        pub fn new(ratio: Float, amplitude: Float) -> Self {
+            Self { ratio, amplitude, phase: 0.0, envelope: None }
+        }
+    }
+
+    /// Length the analysis of a single cycle resamples it to
+    const CYCLE_LEN: usize = 2048;
+
+    /// Partials this far below the loudest one are left out of analyses
+    const PARTIAL_FLOOR_DB: Float = -80.0;
+
+    /// Sum of sine partials. Partials above Nyquist are skipped, so the
+    /// source does not alias at high notes.
+    ///
+    /// Partials keep their phases between samples, so every voice renders
+    /// with its own copy from `for_voice`. Without one the source is silent.
+    pub struct Additive {
+        pub partials: Vec<Partial>,
+
+        /// Brightness as a spectral tilt in dB per octave above the
+        /// fundamental. Negative values darken the sound.
+        pub tilt: Float,
+
+        /// Stiffness of a string or bar, partial ratios `r` are stretched to
+        /// `r * sqrt(1 + inharmonicity * r^2)`. Piano strings are around
+        /// 0.0001 to 0.001, bells and bars far higher.
+        pub inharmonicity: Float,
+
+        /// Pitch used when the source is played without a voice
+        pub freq: Hz,
+        state: RefCell<Option<AdditiveState>>,
+    }
+
+    struct AdditiveState {
+        sample_rate: u32,
+        gate: Gate,
+        elapsed: usize,
+
+        /// Phase of each partial in cycles
+        phases: Vec<Float>,
+    }
+
+    impl Additive {
+        pub fn new(partials: Vec<Partial>) -> Self {
+            Self {
+                partials,
+                tilt: 0.0,
+                inharmonicity: 0.0,
+                freq: 440.0,
+                state: RefCell::new(None),
+            }
+        }
+
+        /// Harmonics of one cycle of a waveform, ie. a drawn or recorded
+        /// wavetable. Keeps up to `max_partials` harmonics with their phases.
+        pub fn from_cycle(cycle: &[Float], max_partials: usize) -> Self {
+            assert!(!cycle.is_empty(), "Cycle should not be empty");
+
+            // Resample to a power of two so bin k is harmonic k
+            let len = cycle.len() as Float;
+            let mut data: Vec<(Float, Float)> = (0..CYCLE_LEN)
+                .map(|i| {
+                    let pos = i as Float * len / CYCLE_LEN as Float;
+                    let a = cycle[pos as usize];
+                    let b = cycle[(pos as usize + 1) % cycle.len()];
+                    (a + (b - a) * pos.fract(), 0.0)
+                })
+                .collect();
+            fft(&mut data);
+
+            let scale = 2.0 / CYCLE_LEN as Float;
+            let harmonics = max_partials.min(CYCLE_LEN / 2 - 1);
+            let partials = (1..=harmonics)
+                .map(|k| {
+                    let (re, im) = data[k];
+
+                    // A sine starting at phase p has its bin at p - 90°
+                    let angle = im.atan2(re) / (2.0 * std::f64::consts::PI);
+                    let amplitude = (re * re + im * im).sqrt() * scale;
+                    Partial {
+                        phase: (angle + 0.25).rem_euclid(1.0),
+                        ..Partial::new(k as Float, amplitude)
+                    }
+                })
+                .collect();
+            Self::new(loudest(partials))
+        }
+
+        /// Partials of a short recorded note with a known fundamental. Each
+        /// harmonic is looked up as the strongest peak near its nominal
+        /// frequency, so slightly inharmonic partials keep their measured
+        /// ratio. Phases are not kept. Empty or silent data has no partials.
+        pub fn from_sample(
+            data: &[Float],
+            sample_rate: u32,
+            fundamental: Hz,
+            max_partials: usize,
+        ) -> Self {
+            assert!(fundamental > 0.0, "Fundamental should be positive");
+            let len = data.len().next_power_of_two().clamp(1024, 1 << 18);
+            let window = hann_window(data.len().min(len));
+            let window_sum = window.iter().sum::<Float>();
+            if window_sum == 0.0 {
+                return Self::new(vec![]);
+            }
+            let mut frame: Vec<(Float, Float)> = vec![(0.0, 0.0); len];
+            for (i, w) in window.iter().enumerate() {
+                frame[i] = (data[i] * w, 0.0);
+            }
+            fft(&mut frame);
+
+            let bin_hz = sample_rate as Float / len as Float;
+            let magnitude = |k: usize| {
+                let (re, im) = frame[k];
+                (re * re + im * im).sqrt()
+            };
+            let scale = 2.0 / window_sum;
+
+            let mut partials = vec![];
+            for n in 1..=max_partials {
+                let center = n as Float * fundamental / bin_hz;
+                let spread = (fundamental / 4.0 / bin_hz).max(1.0);
+                let low = ((center - spread).floor() as usize).max(1);
+                let high = ((center + spread).ceil() as usize).min(len / 2 - 1);
+                if low >= high {
+                    break;
+                }
+                let peak = (low..=high)
+                    .max_by(|&a, &b| magnitude(a).total_cmp(&magnitude(b)))
+                    .unwrap();
+
+                // Parabolic interpolation between the bins around the peak
+                let (a, b, c) =
+                    (magnitude(peak - 1), magnitude(peak), magnitude(peak + 1));
+                let denom = a - 2.0 * b + c;
+                let offset = match denom != 0.0 {
+                    true => 0.5 * (a - c) / denom,
+                    false => 0.0,
+                };
+                let freq = (peak as Float + offset) * bin_hz;
+                partials.push(Partial::new(freq / fundamental, b * scale));
+            }
+            Self::new(loudest(partials))
+        }
+
+        /// Fresh copy for a voice with its partial envelopes following the
+        /// voice gate
+        pub fn for_voice(&self, voice: &Voice, sample_rate: u32) -> Self {
+            let state = AdditiveState {
+                sample_rate,
+                gate: voice.gate(),
+                elapsed: 0,
+                phases: self.partials.iter().map(|p| p.phase).collect(),
+            };
+            Self {
+                partials: self.partials.clone(),
+                tilt: self.tilt,
+                inharmonicity: self.inharmonicity,
+                freq: self.freq,
+                state: RefCell::new(Some(state)),
+            }
+        }
+
+        /// Longest release of the partial envelopes
+        pub fn release_time(&self) -> Seconds {
+            self.partials
+                .iter()
+                .filter_map(|p| p.envelope.as_ref())
+                .map(|env| env.release_time())
+                .fold(0.0, Float::max)
+        }
+
+        /// Ratio of a partial after stretching
+        pub fn stretched_ratio(&self, ratio: Float) -> Float {
+            ratio * (1.0 + self.inharmonicity * ratio * ratio).sqrt()
+        }
+
+        /// Next sample of the source at a pitch
+        pub fn sample(&self, freq: Hz) -> Float {
+            let mut state = self.state.borrow_mut();
+            let Some(s) = state.as_mut() else {
+                return 0.0;
+            };
+            let sr = s.sample_rate as Float;
+            let t = s.elapsed as Float / sr;
+            let mut out = 0.0;
+
+            for (partial, phase) in self.partials.iter().zip(&mut s.phases) {
+                let ratio = self.stretched_ratio(partial.ratio);
+                let partial_freq = freq * ratio;
+                if ratio <= 0.0 || partial_freq >= sr / 2.0 {
+                    continue;
+                }
+
+                let tilt = 10.0_f64.powf(self.tilt * ratio.log2() / 20.0);
+                let envelope = partial
+                    .envelope
+                    .as_ref()
+                    .map_or(1.0, |env| env.value_gated(t, s.gate));
+                out += (2.0 * std::f64::consts::PI * *phase).sin()
+                    * partial.amplitude
+                    * tilt
+                    * envelope;
+                *phase = (*phase + partial_freq / sr).fract();
+            }
+
+            s.elapsed += 1;
+            out
+        }
+    }
+
+    /// Drop partials below the analysis floor
+    fn loudest(partials: Vec<Partial>) -> Vec<Partial> {
+        let max = partials.iter().map(|p| p.amplitude).fold(0.0, Float::max);
+        let floor = max * 10.0_f64.powf(PARTIAL_FLOOR_DB / 20.0);
+        partials.into_iter().filter(|p| p.amplitude > floor).collect()
+    }
 }
 
 pub mod wave {
@@ -120,7 +1087,15 @@ pub mod wave {
 
     impl Wave {
         pub fn sample(&self, frequency: Hz, t: Seconds) -> Float {
-            let mut s = self.source.sample(frequency, t);
+            self.modify(self.source.sample(frequency, t))
+        }
+
+        /// Sample at a running phase, see `WaveSource::sample_phase`
+        pub fn sample_phase(&self, phase: Float, increment: Float) -> Float {
+            self.modify(self.source.sample_phase(phase, increment))
+        }
+
+        fn modify(&self, mut s: Float) -> Float {
             if let Some(modifiers) = &self.modifiers {
                 for m in modifiers {
                     s = m.apply(s);
@@ -130,12 +1105,22 @@ pub mod wave {
         }
     }
 
-    pub trait WaveModifier {
+    // Send allows instruments to be rendered on worker threads
+    pub trait WaveModifier: Send {
         fn apply(&self, sample: Float) -> Float;
     }
 
-    pub trait WaveSource {
+    pub trait WaveSource: Send {
         fn sample(&self, frequency: Hz, t: Seconds) -> Float;
+
+        /// Sample at `phase` cycles into the wave, where the phase advances
+        /// by `increment` cycles per sample. Oscillators keep a running
+        /// phase, so pitch changes don't make the wave jump, and the
+        /// increment lets sources band-limit their output.
+        fn sample_phase(&self, phase: Float, increment: Float) -> Float {
+            let _ = increment;
+            self.sample(1.0, phase)
+        }
     }
 
     pub struct Wavetable1D {
@@ -163,55 +1148,67 @@ pub mod wave {
             let b = self.waves[i + 1].sample(frequency, t);
             a * (1.0 - frac) + b * frac
         }
+
+        fn sample_phase(&self, phase: Float, increment: Float) -> Float {
+            let n = self.waves.len();
+            if n == 0 {
+                return 0.0;
+            }
+
+            let idx = self.position.clamp(0.0, 1.0) * (n - 1) as Float;
+            let i = (idx.floor() as usize).min(n - 1);
+            let frac = idx - i as Float;
+            let a = self.waves[i].sample_phase(phase, increment);
+            if i == n - 1 {
+                return a;
+            }
+            let b = self.waves[i + 1].sample_phase(phase, increment);
+            a * (1.0 - frac) + b * frac
+        }
     }
 
+    /// Basic waveforms. Phases are in cycles from 0 to 1 and every shape
+    /// starts its cycle at -1 (sine at 0).
     pub enum WaveShape {
         Sine,
+
+        /// Rises from -1 to 1 until the skew point, then falls back to -1.
+        /// A skew of 0.5 is the symmetric triangle, towards 0 or 1 it turns
+        /// into a falling or rising saw.
         Triangle(Skew),
+
+        /// Rising saw bent at the skew point, where it crosses zero. A skew
+        /// of 0.5 is the straight saw, other values shift the energy towards
+        /// the start or the end of the ramp.
         Saw(Skew),
+
+        /// High for the duty cycle fraction of the cycle, then low
         Pulse(DutyCycle),
     }
 
-    impl WaveSource for WaveShape {
-        fn sample(&self, frequency: Hz, t: Seconds) -> Float {
-            let phase = 2.0 * PI * frequency * t;
+    impl WaveShape {
+        /// Wave value without band-limiting
+        fn naive(&self, phase: Float) -> Float {
             match self {
-                Self::Sine => phase.sin(),
-                Self::Saw(skew) => {
-                    if *skew == 0.5 {
-                        // Classic symmetric sawtooth: rising from -1 to 1
-                        2.0 * (phase - 0.5)
-                    } else if *skew < 0.5 {
-                        let norm_phase = phase / (2.0 * skew);
-                        if phase < *skew {
-                            2.0 * norm_phase - 1.0
-                        } else {
-                            1.0
-                        }
+                Self::Sine => (2.0 * PI * phase).sin(),
+                Self::Triangle(skew) => {
+                    let skew = skew.clamp(SKEW_LIMIT, 1.0 - SKEW_LIMIT);
+                    if phase < skew {
+                        2.0 * phase / skew - 1.0
                     } else {
-                        let norm_phase = (phase - skew) / (1.0 - skew);
-                        if phase >= *skew {
-                            -2.0 * norm_phase + 1.0
-                        } else {
-                            -1.0
-                        }
+                        1.0 - 2.0 * (phase - skew) / (1.0 - skew)
                     }
                 }
-                Self::Triangle(skew) => {
-                    if *skew == 0.5 {
-                        // Classic triangle
-                        4.0 * (phase - 0.5).abs() - 1.0
-                    } else if phase < *skew {
-                        // Rising segment
-                        (2.0 / skew) * phase - 1.0
+                Self::Saw(skew) => {
+                    let skew = skew.clamp(SKEW_LIMIT, 1.0 - SKEW_LIMIT);
+                    if phase < skew {
+                        phase / skew - 1.0
                     } else {
-                        // Falling segment
-                        (-2.0 / (1.0 - skew)) * (phase - skew) + 1.0
+                        (phase - skew) / (1.0 - skew)
                     }
                 }
                 Self::Pulse(duty) => {
-                    let phase_fraction = (frequency * t / (2.0 * PI)) % 1.0;
-                    if phase_fraction < *duty {
+                    if phase < *duty {
                         1.0
                     } else {
                         -1.0
@@ -221,6 +1218,85 @@ pub mod wave {
         }
     }
 
+    /// Skews are kept this far from 0 and 1 so slopes stay finite
+    const SKEW_LIMIT: Float = 1e-3;
+
+    impl WaveSource for WaveShape {
+        fn sample(&self, frequency: Hz, t: Seconds) -> Float {
+            self.naive((frequency * t).rem_euclid(1.0))
+        }
+
+        /// Band-limited with PolyBLEP at jumps and PolyBLAMP at corners
+        fn sample_phase(&self, phase: Float, increment: Float) -> Float {
+            let phase = phase.rem_euclid(1.0);
+            let dt = increment.abs().min(0.5);
+            let naive = self.naive(phase);
+            if dt == 0.0 {
+                return naive;
+            }
+
+            // Position of the phase relative to a discontinuity at `at`
+            let from = |at: Float| (phase - at).rem_euclid(1.0);
+            match self {
+                Self::Sine => naive,
+                Self::Pulse(duty) => {
+                    let duty = duty.clamp(0.0, 1.0);
+                    if duty == 0.0 || duty == 1.0 {
+                        return naive;
+                    }
+                    naive + poly_blep(from(0.0), dt) - poly_blep(from(duty), dt)
+                }
+                Self::Triangle(skew) => {
+                    let skew = skew.clamp(SKEW_LIMIT, 1.0 - SKEW_LIMIT);
+
+                    // Slope change in units per cycle at the two corners
+                    let bend = 2.0 / skew + 2.0 / (1.0 - skew);
+                    naive + bend * dt * poly_blamp(from(0.0), dt)
+                        - bend * dt * poly_blamp(from(skew), dt)
+                }
+                Self::Saw(skew) => {
+                    let skew = skew.clamp(SKEW_LIMIT, 1.0 - SKEW_LIMIT);
+                    let bend = 1.0 / (1.0 - skew) - 1.0 / skew;
+
+                    // Drop of 2 at the wrap, slope changes at the wrap and
+                    // the skew point
+                    naive
+                        - poly_blep(from(0.0), dt)
+                        - bend * dt * poly_blamp(from(0.0), dt)
+                        + bend * dt * poly_blamp(from(skew), dt)
+                }
+            }
+        }
+    }
+
+    /// Correction for a jump of 2 at phase 0, `t` is the phase after the
+    /// jump and `dt` the phase increment per sample
+    fn poly_blep(t: Float, dt: Float) -> Float {
+        if t < dt {
+            let x = t / dt;
+            2.0 * x - x * x - 1.0
+        } else if t > 1.0 - dt {
+            let x = (t - 1.0) / dt;
+            x * x + 2.0 * x + 1.0
+        } else {
+            0.0
+        }
+    }
+
+    /// Correction for a slope increase of one unit per sample at phase 0,
+    /// the integral of `poly_blep`
+    fn poly_blamp(t: Float, dt: Float) -> Float {
+        if t < dt {
+            let x = 1.0 - t / dt;
+            x * x * x / 6.0
+        } else if t > 1.0 - dt {
+            let x = 1.0 + (t - 1.0) / dt;
+            x * x * x / 6.0
+        } else {
+            0.0
+        }
+    }
+
     pub struct Drive(pub Float);
     impl WaveModifier for Drive {
         fn apply(&self, x: Float) -> Float {
@@ -276,37 +1352,55 @@ pub mod wave {
             }
         }
     }
-
-    /// Linearly interpolates between `a` and `b` by the interpolation factor
-    /// `t`.
-    ///
-    /// - `t = 0.0` returns `a`
-    /// - `t = 1.0` returns `b`
-    /// - Values of `t` between 0.0 and 1.0 return a weighted average
-    ///
-    /// Useful for smooth transitions or blending between two values.
-    fn lerp(a: Float, b: Float, t: Float) -> Float {
-        a * (1.0 - t) + b * t
-    }
 }
 
 pub enum ModulationSource {
     Constant(Float),
     Envelope(ParametricEnvelope),
-    Signal(signal::SignalSource),
+
+    /// Boxed, signal sources are much larger than the other variants
+    Signal(Box<signal::SignalSource>),
 }
 
 impl ModulationSource {
-    fn value_at(&self, t: Seconds) -> Float {
+    /// Envelopes follow `gate` if given, otherwise their own gate
+    fn value_at(&self, t: Seconds, gate: Option<Gate>) -> Float {
         match self {
             Self::Constant(v) => *v,
-            Self::Envelope(e) => e.value(t),
+            Self::Envelope(e) => match gate {
+                Some(gate) => e.value_gated(t, gate),
+                None => e.value(t),
+            },
             Self::Signal(s) => s.sample(t),
         }
     }
+
+    /// Fresh copy of a signal that keeps state between samples, see
+    /// `SignalSource::for_voice`. None if the source can be shared.
+    pub fn for_voice(
+        &self,
+        voice: &Voice,
+        copy: usize,
+        sample_rate: u32,
+    ) -> Option<Self> {
+        match self {
+            Self::Signal(s) => s
+                .for_voice(voice, copy, sample_rate)
+                .map(|s| Self::Signal(Box::new(s))),
+            _ => None,
+        }
+    }
+}
+
+/// Gate times of a single voice. Evaluating envelopes with a gate instead of
+/// their own gate state lets overlapping voices share an envelope.
+#[derive(Clone, Copy, Debug)]
+pub struct Gate {
+    pub on: Seconds,
+    pub off: Option<Seconds>,
 }
 
-#[derive(PartialEq)]
+#[derive(Clone, Copy, Debug, PartialEq)]
 // Assumes all modulation targets are represented by Float value
 pub enum ModulationTarget {
     // Oscillator / note
@@ -317,6 +1411,7 @@ pub enum ModulationTarget {
     PhaseOffset,
 
     // Effects
+    Gain,
     FilterCutoff,
     FilterResonance,
     DistortionDrive,
@@ -335,6 +1430,9 @@ pub enum ModulationTarget {
 
     // Modulations
     ModulationDepth,
+
+    /// Level of an FM operator by index
+    OperatorLevel(usize),
 }
 
 //impl ModulationTarget {
@@ -368,16 +1466,38 @@ pub struct ModulationRoute {
 
 impl ModulationRoute {
     pub fn apply(&self, base: Float, t: Seconds) -> Float {
+        self.modulate(base, t, None)
+    }
+
+    /// Apply with the envelopes following the gate of a voice
+    pub fn apply_gated(&self, base: Float, t: Seconds, gate: Gate) -> Float {
+        self.modulate(base, t, Some(gate))
+    }
+
+    fn modulate(&self, base: Float, t: Seconds, gate: Option<Gate>) -> Float {
+        self.modulate_with(&self.source, self.depth_mod.as_ref(), base, t, gate)
+    }
+
+    /// Modulate with other sources than the ones of the route, ie. the
+    /// copies of a voice
+    fn modulate_with(
+        &self,
+        source: &ModulationSource,
+        depth_mod: Option<&ModulationSource>,
+        base: Float,
+        t: Seconds,
+        gate: Option<Gate>,
+    ) -> Float {
         assert!(
             self.depth >= 0.0 && self.depth <= 1.0,
             "Modulation depths value out of range"
         );
 
         let mut depth = self.depth;
-        if let Some(dm) = &self.depth_mod {
-            depth *= dm.value_at(t)
+        if let Some(dm) = depth_mod {
+            depth *= dm.value_at(t, gate)
         }
-        let mod_val = self.source.value_at(t) * depth;
+        let mod_val = source.value_at(t, gate) * depth;
 
         match self.mode {
             ModulationMode::Add => base + mod_val,
@@ -417,6 +1537,46 @@ impl ModulationMatrix {
         combined
     }
 
+    /// Apply with the envelopes following the gate of a voice instead of
+    /// their own gate state
+    pub fn apply_gated(
+        &self,
+        target: ModulationTarget,
+        base: Float,
+        t: Seconds,
+        gate: Gate,
+    ) -> Float {
+        self.routes
+            .iter()
+            .filter(|r| r.target == target)
+            .fold(base, |value, r| r.apply_gated(value, t, gate))
+    }
+
+    /// The matrix as one voice plays it, with its own copy of every source
+    /// that keeps state between samples, ie. noise. `copy` is the unison
+    /// copy of the voice.
+    pub fn for_voice(
+        &self,
+        voice: &Voice,
+        copy: usize,
+        sample_rate: u32,
+    ) -> VoiceModulation<'_> {
+        let sources = self
+            .routes
+            .iter()
+            .map(|route| {
+                let copy_of = |s: &ModulationSource| {
+                    s.for_voice(voice, copy, sample_rate)
+                };
+                (
+                    copy_of(&route.source),
+                    route.depth_mod.as_ref().and_then(copy_of),
+                )
+            })
+            .collect();
+        VoiceModulation { matrix: self, sources }
+    }
+
     /// Turns all envelope gates in the matrix on
     pub fn gate_on(&mut self, t: Seconds) {
         for route in &mut self.routes {
@@ -446,6 +1606,41 @@ impl ModulationMatrix {
     }
 }
 
+/// Modulation matrix of a single voice, see `ModulationMatrix::for_voice`
+pub struct VoiceModulation<'a> {
+    matrix: &'a ModulationMatrix,
+
+    /// Copies of the source and depth source of each route, None where the
+    /// route's own source is shared
+    sources: Vec<(Option<ModulationSource>, Option<ModulationSource>)>,
+}
+
+impl VoiceModulation<'_> {
+    /// Apply with the envelopes following the gate of the voice
+    pub fn apply_gated(
+        &self,
+        target: ModulationTarget,
+        base: Float,
+        t: Seconds,
+        gate: Gate,
+    ) -> Float {
+        self.matrix
+            .routes
+            .iter()
+            .zip(&self.sources)
+            .filter(|(r, _)| r.target == target)
+            .fold(base, |value, (r, (source, depth_mod))| {
+                r.modulate_with(
+                    source.as_ref().unwrap_or(&r.source),
+                    depth_mod.as_ref().or(r.depth_mod.as_ref()),
+                    value,
+                    t,
+                    Some(gate),
+                )
+            })
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct EnvelopeStage {
     pub kind: StageKind,
@@ -511,11 +1706,16 @@ impl ParametricEnvelope {
             Some(t) => t,
             None => return 0.0,
         };
+        self.value_gated(now, Gate { on: gate_on, off: self.gate_off_time })
+    }
 
-        let t = now - gate_on;
+    /// Value for the given gate times, ignoring the gate state of the
+    /// envelope
+    pub fn value_gated(&self, now: Float, gate: Gate) -> Float {
+        let t = now - gate.on;
 
         // If note is still on
-        if self.gate_off_time.is_none() {
+        if gate.off.is_none_or(|off| now < off) {
             let mut cursor = 0.0;
             for (i, stage) in self.stages.iter().enumerate() {
                 match &stage.kind {
@@ -569,7 +1769,7 @@ impl ParametricEnvelope {
         }
 
         // Note is off, proceed through release stages
-        let release_t = now - self.gate_off_time.unwrap();
+        let release_t = now - gate.off.unwrap();
         let mut cursor = 0.0;
         for stage in &self.release_stages {
             match &stage.kind {
@@ -753,3 +1953,148 @@ impl ParametricEnvelope {
         }
     }
 }
+
+/// Frequency domain analysis
+pub mod spectrum {
+    use std::f64::consts::PI;
+
+    use super::super::types::Float;
+
+    /// In place radix-2 FFT over (re, im) pairs. The length must be a power
+    /// of two.
+    pub fn fft(data: &mut [(Float, Float)]) {
+        let n = data.len();
+        assert!(n.is_power_of_two(), "FFT length should be a power of two");
+
+        // Bit reversal permutation
+        let mut j = 0;
+        for i in 1..n {
+            let mut bit = n >> 1;
+            while j & bit != 0 {
+                j ^= bit;
+                bit >>= 1;
+            }
+            j |= bit;
+            if i < j {
+                data.swap(i, j);
+            }
+        }
+
+        let mut len = 2;
+        while len <= n {
+            let angle = -2.0 * PI / len as Float;
+            for start in (0..n).step_by(len) {
+                for k in 0..len / 2 {
+                    let (sin, cos) = (angle * k as Float).sin_cos();
+                    let (re, im) = data[start + k + len / 2];
+                    let t = (re * cos - im * sin, re * sin + im * cos);
+                    let u = data[start + k];
+                    data[start + k] = (u.0 + t.0, u.1 + t.1);
+                    data[start + k + len / 2] = (u.0 - t.0, u.1 - t.1);
+                }
+            }
+            len <<= 1;
+        }
+    }
+
+    pub fn hann_window(len: usize) -> Vec<Float> {
+        (0..len)
+            .map(|i| 0.5 - 0.5 * (2.0 * PI * i as Float / len as Float).cos())
+            .collect()
+    }
+
+    /// Average magnitude spectrum of Hann windowed frames of `frame_len`
+    /// samples overlapping by half. Bin `k` is at `k * sample_rate /
+    /// frame_len` Hz, up to and including Nyquist. Signals shorter than a
+    /// frame are zero padded.
+    pub fn average_spectrum(samples: &[Float], frame_len: usize) -> Vec<Float> {
+        let window = hann_window(frame_len);
+        let hop = frame_len / 2;
+        let mut sum = vec![0.0; frame_len / 2 + 1];
+        let mut frames = 0;
+
+        let mut start = 0;
+        loop {
+            let mut frame: Vec<(Float, Float)> = (0..frame_len)
+                .map(|i| {
+                    let s = samples.get(start + i).copied().unwrap_or(0.0);
+                    (s * window[i], 0.0)
+                })
+                .collect();
+            fft(&mut frame);
+            for (bin, (re, im)) in sum.iter_mut().zip(&frame) {
+                *bin += (re * re + im * im).sqrt();
+            }
+            frames += 1;
+
+            start += hop;
+            if start + frame_len > samples.len().max(frame_len) {
+                break;
+            }
+        }
+
+        sum.iter().map(|m| m / frames as Float).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::f64::consts::PI;
+
+    use super::signal::{Additive, Oscillator, Sampler};
+    use super::wave::{Wave, WaveShape};
+    use super::*;
+
+    #[test]
+    fn oscillator_matches_running_phase() {
+        let sample_rate = 8000;
+        let osc = Oscillator {
+            wave: Wave {
+                source: Box::new(WaveShape::Saw(0.5)),
+                modifiers: None,
+            },
+            freq: 1500.0,
+            phase: 0.25,
+        };
+        let increment = osc.freq / sample_rate as Float;
+        let mut phase = osc.phase;
+        let mut smoothed = false;
+        for i in 0..64 {
+            let t = i as Seconds / sample_rate as Seconds;
+            let running = osc.wave.sample_phase(phase, increment);
+            smoothed |= running != osc.wave.sample_phase(phase, 0.0);
+            assert!(
+                (osc.sample_at_rate(t, sample_rate) - running).abs() < 1e-9
+            );
+            phase = (phase + increment).rem_euclid(1.0);
+        }
+        assert!(smoothed, "Steps of the saw should be band-limited");
+    }
+
+    #[test]
+    fn sampler_start_moved_into_the_loop() {
+        let data: Vec<Float> = (0..1000).map(|i| (i as Float).sin()).collect();
+        let mut sampler = Sampler::new(data, 1000, 100.0).looped(200, 800, 100);
+        sampler.start = 500;
+        for i in 0..2000 {
+            let t = i as Seconds / 1000.0;
+            assert!(sampler.sample(0.0, t).is_finite());
+        }
+    }
+
+    #[test]
+    fn additive_from_silence_has_no_partials() {
+        for data in [vec![], vec![0.5], vec![0.0; 4096]] {
+            let additive = Additive::from_sample(&data, 44100, 220.0, 16);
+            assert!(additive.partials.is_empty());
+        }
+
+        let sine: Vec<Float> = (0..4096)
+            .map(|i| (2.0 * PI * 220.0 * i as Float / 44100.0).sin())
+            .collect();
+        let additive = Additive::from_sample(&sine, 44100, 220.0, 16);
+        let first = &additive.partials[0];
+        assert!((first.ratio - 1.0).abs() < 0.01);
+        assert!(additive.partials.iter().all(|p| p.amplitude.is_finite()));
+    }
+}
@@ -1,8 +1,62 @@
+use super::dsp::ModulationTarget;
 use super::processor::AudioBuffer;
-use super::types::Float;
+use super::types::{Float, Seconds};
 
-pub trait AudioEffect {
+/// Samples at or below this level (-100 dB) are considered silent when
+/// deciding where an effect tail ends
+pub const SILENCE_THRESHOLD: Float = 1e-5;
+
+/// Upper bound for tails that never decay, ie. a delay with full feedback
+pub const MAX_TAIL_TIME: Seconds = 30.0;
+
+/// Type name and parameter values of an effect, used when a graph is saved
+/// in the text format
+pub struct EffectDescription {
+    pub kind: &'static str,
+    pub params: Vec<(&'static str, Float)>,
+}
+
+/// Effects are Send so that tracks can be processed on worker threads
+pub trait AudioEffect: Send {
     fn process(&mut self, buffer: &mut AudioBuffer, sample_rate: u32);
+
+    /// How long the effect keeps producing output after its input goes
+    /// silent, until the output decays below SILENCE_THRESHOLD
+    fn tail_time(&self, _sample_rate: u32) -> Seconds {
+        0.0
+    }
+
+    /// Samples the output lags behind the input
+    fn latency(&self) -> usize {
+        0
+    }
+
+    /// Process with a secondary input the effect reads but does not output,
+    /// ie. the kick drum driving a ducking compressor. Effects without a use
+    /// for it ignore the sidechain.
+    fn process_sidechain(
+        &mut self,
+        buffer: &mut AudioBuffer,
+        _sidechain: &AudioBuffer,
+        sample_rate: u32,
+    ) {
+        self.process(buffer, sample_rate);
+    }
+
+    /// Clear internal state (delay lines, filter memory) so the effect can
+    /// process an unrelated signal, ie. the next stem
+    fn reset(&mut self) {}
+
+    /// Change a parameter between calls to process, ie. from an automation
+    /// lane. Returns false if the effect has no such parameter.
+    fn set_param(&mut self, _target: ModulationTarget, _value: Float) -> bool {
+        false
+    }
+
+    /// Effects that return None can't be saved in the graph text format
+    fn describe(&self) -> Option<EffectDescription> {
+        None
+    }
 }
 
 pub struct EffectChain {
@@ -15,32 +69,122 @@ impl EffectChain {
             fx.process(buffer, sample_rate);
         }
     }
+
+    /// Effects are in series, so the tails (and latencies) add up
+    pub fn tail_time(&self, sample_rate: u32) -> Seconds {
+        self.effects
+            .iter()
+            .map(|fx| {
+                fx.tail_time(sample_rate)
+                    + fx.latency() as Seconds / sample_rate as Seconds
+            })
+            .sum::<Seconds>()
+            .min(MAX_TAIL_TIME)
+    }
+
+    pub fn latency(&self) -> usize {
+        self.effects.iter().map(|fx| fx.latency()).sum()
+    }
+
+    pub fn reset(&mut self) {
+        for fx in &mut self.effects {
+            fx.reset();
+        }
+    }
 }
 
-/// Pan effect: -1.0 (left) to +1.0 (right)
+/// Number of times a signal has to be multiplied by `gain` to decay below
+/// SILENCE_THRESHOLD. None if it never decays.
+fn decay_steps(gain: Float) -> Option<Float> {
+    let gain = gain.abs();
+    if gain >= 1.0 {
+        return None;
+    }
+    if gain == 0.0 {
+        return Some(1.0);
+    }
+    Some((SILENCE_THRESHOLD.ln() / gain.ln()).ceil())
+}
+
+/// Pan effect: -1.0 (left) to +1.0 (right). Stereo input is summed to mono
+/// first, use `Balance` to keep the width of stereo material. Mono input is
+/// turned into stereo.
 pub struct Pan {
     pub position: Float, // -1.0 to 1.0
 }
 
 impl AudioEffect for Pan {
+    fn describe(&self) -> Option<EffectDescription> {
+        Some(EffectDescription {
+            kind: "Pan",
+            params: vec![("position", self.position)],
+        })
+    }
+
     fn process(&mut self, buffer: &mut AudioBuffer, _sr: u32) {
         let position = self.position.clamp(-1.0, 1.0);
         let left_gain = ((1.0 - position) * 0.5).sqrt();
         let right_gain = ((1.0 + position) * 0.5).sqrt();
 
-        match buffer {
-            AudioBuffer::Mono(_) => {
-                panic!("Cannot pan a mono buffer");
+        if let AudioBuffer::Mono(_) = buffer {
+            *buffer = buffer.to_stereo();
+        }
+        if let AudioBuffer::Stereo(buf) = buffer {
+            for (l, r) in buf {
+                let mid = (*l + *r) * 0.5;
+                *l = mid * left_gain;
+                *r = mid * right_gain;
             }
-            AudioBuffer::Stereo(buf) => {
-                for (l, r) in buf {
-                    let mid = (*l + *r) * 0.5;
-                    *l = mid * left_gain;
-                    *r = mid * right_gain;
-                }
+        }
+    }
+
+    fn set_param(&mut self, target: ModulationTarget, value: Float) -> bool {
+        match target {
+            ModulationTarget::PanPosition => self.position = value,
+            _ => return false,
+        }
+        true
+    }
+}
+
+/// Balance control: -1.0 (left) to +1.0 (right). Turns down the opposite
+/// channel and leaves the other one as it is, so stereo material keeps its
+/// width. Mono input is turned into stereo.
+pub struct Balance {
+    pub position: Float, // -1.0 to 1.0
+}
+
+impl AudioEffect for Balance {
+    fn describe(&self) -> Option<EffectDescription> {
+        Some(EffectDescription {
+            kind: "Balance",
+            params: vec![("position", self.position)],
+        })
+    }
+
+    fn process(&mut self, buffer: &mut AudioBuffer, _sr: u32) {
+        let position = self.position.clamp(-1.0, 1.0);
+        let left_gain = (1.0 - position).min(1.0);
+        let right_gain = (1.0 + position).min(1.0);
+
+        if let AudioBuffer::Mono(_) = buffer {
+            *buffer = buffer.to_stereo();
+        }
+        if let AudioBuffer::Stereo(buf) = buffer {
+            for (l, r) in buf {
+                *l *= left_gain;
+                *r *= right_gain;
             }
         }
     }
+
+    fn set_param(&mut self, target: ModulationTarget, value: Float) -> bool {
+        match target {
+            ModulationTarget::PanPosition => self.position = value,
+            _ => return false,
+        }
+        true
+    }
 }
 
 /// Simple gain adjustment
@@ -49,6 +193,13 @@ pub struct Gain {
 }
 
 impl AudioEffect for Gain {
+    fn describe(&self) -> Option<EffectDescription> {
+        Some(EffectDescription {
+            kind: "Gain",
+            params: vec![("amount", self.amount)],
+        })
+    }
+
     fn process(&mut self, buffer: &mut AudioBuffer, _sr: u32) {
         match buffer {
             AudioBuffer::Mono(ref mut buf) => {
@@ -64,6 +215,15 @@ impl AudioEffect for Gain {
             }
         }
     }
+
+    /// Linear amount, use Gain::from_db for the conversion from decibels
+    fn set_param(&mut self, target: ModulationTarget, value: Float) -> bool {
+        match target {
+            ModulationTarget::Gain => self.amount = value,
+            _ => return false,
+        }
+        true
+    }
 }
 
 impl Gain {
@@ -86,6 +246,13 @@ impl LowPass {
 }
 
 impl AudioEffect for LowPass {
+    fn describe(&self) -> Option<EffectDescription> {
+        Some(EffectDescription {
+            kind: "LowPass",
+            params: vec![("cutoff_hz", self.cutoff_hz)],
+        })
+    }
+
     fn process(&mut self, buffer: &mut AudioBuffer, sample_rate: u32) {
         let alpha = (2.0 * std::f64::consts::PI * self.cutoff_hz
             / sample_rate as Float)
@@ -111,12 +278,37 @@ impl AudioEffect for LowPass {
             }
         }
     }
+
+    fn tail_time(&self, sample_rate: u32) -> Seconds {
+        let alpha = (2.0 * std::f64::consts::PI * self.cutoff_hz
+            / sample_rate as Float)
+            .min(1.0);
+        decay_steps(1.0 - alpha)
+            .map(|n| n / sample_rate as Float)
+            .unwrap_or(MAX_TAIL_TIME)
+    }
+
+    fn reset(&mut self) {
+        self.state = None;
+    }
+
+    fn set_param(&mut self, target: ModulationTarget, value: Float) -> bool {
+        match target {
+            ModulationTarget::FilterCutoff => self.cutoff_hz = value.max(0.0),
+            _ => return false,
+        }
+        true
+    }
 }
 
 /// Simple soft saturation
 pub struct Saturation;
 
 impl AudioEffect for Saturation {
+    fn describe(&self) -> Option<EffectDescription> {
+        Some(EffectDescription { kind: "Saturation", params: vec![] })
+    }
+
     fn process(&mut self, buffer: &mut AudioBuffer, _sr: u32) {
         fn saturate(x: Float) -> Float {
             (x + 0.5 * x.powi(3)).clamp(-1.0, 1.0)
@@ -144,6 +336,13 @@ pub struct Distortion {
 }
 
 impl AudioEffect for Distortion {
+    fn describe(&self) -> Option<EffectDescription> {
+        Some(EffectDescription {
+            kind: "Distortion",
+            params: vec![("threshold", self.threshold)],
+        })
+    }
+
     fn process(&mut self, buffer: &mut AudioBuffer, _sr: u32) {
         let th = self.threshold.abs().max(0.01);
         match buffer {
@@ -168,6 +367,13 @@ pub struct Bitcrusher {
 }
 
 impl AudioEffect for Bitcrusher {
+    fn describe(&self) -> Option<EffectDescription> {
+        Some(EffectDescription {
+            kind: "Bitcrusher",
+            params: vec![("bits", self.bits as Float)],
+        })
+    }
+
     fn process(&mut self, buffer: &mut AudioBuffer, _sr: u32) {
         let levels = 2u32.pow(self.bits.min(24)) as Float;
         match buffer {
@@ -184,14 +390,26 @@ impl AudioEffect for Bitcrusher {
             }
         }
     }
+
+    /// Bit depth, rounded to the nearest whole bit
+    fn set_param(&mut self, target: ModulationTarget, value: Float) -> bool {
+        match target {
+            ModulationTarget::BitCrushAmount => {
+                self.bits = value.round().max(1.0) as u32
+            }
+            _ => return false,
+        }
+        true
+    }
 }
 
-/// Simple delay (mono only for now)
+/// Simple delay. Stereo buffers are delayed per channel, mono buffers only
+/// use the left delay line.
 pub struct Delay {
     pub delay_samples: usize,
     pub feedback: Float,
     pub mix: Float,
-    buffer: Vec<Float>,
+    buffer: Vec<(Float, Float)>,
     pos: usize,
 }
 
@@ -201,25 +419,183 @@ impl Delay {
             delay_samples,
             feedback,
             mix,
-            buffer: vec![0.0; delay_samples],
+            buffer: vec![(0.0, 0.0); delay_samples],
             pos: 0,
         }
     }
 }
 
 impl AudioEffect for Delay {
+    fn describe(&self) -> Option<EffectDescription> {
+        Some(EffectDescription {
+            kind: "Delay",
+            params: vec![
+                ("delay_samples", self.delay_samples as Float),
+                ("feedback", self.feedback),
+                ("mix", self.mix),
+            ],
+        })
+    }
+
     fn process(&mut self, buffer: &mut AudioBuffer, _sr: u32) {
         match buffer {
             AudioBuffer::Mono(ref mut buf) => {
                 for s in buf {
-                    let delayed = self.buffer[self.pos];
-                    self.buffer[self.pos] = *s + delayed * self.feedback;
+                    let delayed = self.buffer[self.pos].0;
+                    self.buffer[self.pos].0 = *s + delayed * self.feedback;
                     *s = *s * (1.0 - self.mix) + delayed * self.mix;
                     self.pos = (self.pos + 1) % self.buffer.len();
                 }
             }
-            _ => {} // TODO: stereo delay
+            AudioBuffer::Stereo(ref mut buf) => {
+                for (l, r) in buf {
+                    let (dl, dr) = self.buffer[self.pos];
+                    self.buffer[self.pos] =
+                        (*l + dl * self.feedback, *r + dr * self.feedback);
+                    *l = *l * (1.0 - self.mix) + dl * self.mix;
+                    *r = *r * (1.0 - self.mix) + dr * self.mix;
+                    self.pos = (self.pos + 1) % self.buffer.len();
+                }
+            }
+        }
+    }
+
+    /// One repeat per delay time until the feedback decays
+    fn tail_time(&self, sample_rate: u32) -> Seconds {
+        let delay = self.delay_samples as Seconds / sample_rate as Seconds;
+        decay_steps(self.feedback)
+            .map(|n| delay * n)
+            .unwrap_or(MAX_TAIL_TIME)
+            .min(MAX_TAIL_TIME)
+    }
+
+    fn reset(&mut self) {
+        self.buffer.fill((0.0, 0.0));
+        self.pos = 0;
+    }
+
+    fn set_param(&mut self, target: ModulationTarget, value: Float) -> bool {
+        match target {
+            ModulationTarget::DelayFeedback => self.feedback = value,
+            _ => return false,
+        }
+        true
+    }
+}
+
+/// Feed-forward compressor. Without a sidechain the gain reduction follows
+/// the processed signal. With a sidechain it follows the sidechain signal,
+/// which ducks the processed signal whenever the sidechain is loud.
+pub struct Compressor {
+    pub threshold_db: Float,
+
+    /// Input to output level ratio above the threshold, ie. 4.0 for 4:1
+    pub ratio: Float,
+    pub attack: Seconds,
+    pub release: Seconds,
+    pub makeup_db: Float,
+    envelope: Float,
+}
+
+impl Compressor {
+    pub fn new(
+        threshold_db: Float,
+        ratio: Float,
+        attack: Seconds,
+        release: Seconds,
+    ) -> Self {
+        Self {
+            threshold_db,
+            ratio,
+            attack,
+            release,
+            makeup_db: 0.0,
+            envelope: 0.0,
+        }
+    }
+
+    /// Sidechain ducking preset: strong reduction with a fast attack and a
+    /// release that pumps with the beat
+    pub fn ducker() -> Self {
+        Self::new(-30.0, 8.0, 0.002, 0.15)
+    }
+
+    fn compress(
+        &mut self,
+        buffer: &mut AudioBuffer,
+        detector: Option<&AudioBuffer>,
+        sample_rate: u32,
+    ) {
+        fn peak(buffer: &AudioBuffer, i: usize) -> Float {
+            match buffer {
+                AudioBuffer::Mono(b) => b.get(i).map_or(0.0, |s| s.abs()),
+                AudioBuffer::Stereo(b) => {
+                    b.get(i).map_or(0.0, |(l, r)| l.abs().max(r.abs()))
+                }
+            }
+        }
+        fn coefficient(time: Seconds, sample_rate: u32) -> Float {
+            if time <= 0.0 {
+                0.0
+            } else {
+                (-1.0 / (time * sample_rate as Float)).exp()
+            }
         }
+
+        let attack = coefficient(self.attack, sample_rate);
+        let release = coefficient(self.release, sample_rate);
+        let slope = 1.0 - 1.0 / self.ratio.max(1.0);
+
+        for i in 0..buffer.len() {
+            let level = peak(detector.unwrap_or(buffer), i);
+            let coef = if level > self.envelope { attack } else { release };
+            self.envelope = coef * self.envelope + (1.0 - coef) * level;
+
+            let env_db = 20.0 * self.envelope.max(1e-9).log10();
+            let over = (env_db - self.threshold_db).max(0.0);
+            let gain_db = -over * slope + self.makeup_db;
+            let gain = 10.0_f64.powf(gain_db / 20.0);
+
+            match buffer {
+                AudioBuffer::Mono(b) => b[i] *= gain,
+                AudioBuffer::Stereo(b) => {
+                    b[i].0 *= gain;
+                    b[i].1 *= gain;
+                }
+            }
+        }
+    }
+}
+
+impl AudioEffect for Compressor {
+    fn describe(&self) -> Option<EffectDescription> {
+        Some(EffectDescription {
+            kind: "Compressor",
+            params: vec![
+                ("threshold_db", self.threshold_db),
+                ("ratio", self.ratio),
+                ("attack", self.attack),
+                ("release", self.release),
+                ("makeup_db", self.makeup_db),
+            ],
+        })
+    }
+
+    fn process(&mut self, buffer: &mut AudioBuffer, sample_rate: u32) {
+        self.compress(buffer, None, sample_rate);
+    }
+
+    fn process_sidechain(
+        &mut self,
+        buffer: &mut AudioBuffer,
+        sidechain: &AudioBuffer,
+        sample_rate: u32,
+    ) {
+        self.compress(buffer, Some(sidechain), sample_rate);
+    }
+
+    fn reset(&mut self) {
+        self.envelope = 0.0;
     }
 }
 
@@ -227,72 +603,181 @@ impl AudioEffect for Delay {
 pub struct Bypass;
 
 impl AudioEffect for Bypass {
+    fn describe(&self) -> Option<EffectDescription> {
+        Some(EffectDescription { kind: "Bypass", params: vec![] })
+    }
+
     fn process(&mut self, _buffer: &mut AudioBuffer, _sr: u32) {}
 }
 
-/// Simple Schroeder reverb with comb + allpass filters
+/// Simple Schroeder reverb with comb + allpass filters. Stereo buffers run
+/// each channel through its own set of filters, the right ones slightly
+/// longer so the reverb tails of the channels are decorrelated.
 pub struct SimpleReverb {
+    left: ReverbTank,
+    right: ReverbTank,
+    feedback: Float,
+    mix: Float,
+}
+
+/// Comb and allpass filters of one reverb channel
+struct ReverbTank {
     comb_buffers: Vec<Vec<Float>>,
     allpass_buffers: Vec<Vec<Float>>,
     comb_indices: Vec<usize>,
     allpass_indices: Vec<usize>,
-    feedback: Float,
-    mix: Float,
 }
 
 impl SimpleReverb {
+    /// Extra filter length of the right channel in samples
+    const STEREO_SPREAD: usize = 23;
+
     pub fn new(feedback: Float, mix: Float) -> Self {
+        SimpleReverb {
+            left: ReverbTank::new(0),
+            right: ReverbTank::new(Self::STEREO_SPREAD),
+            feedback,
+            mix,
+        }
+    }
+}
+
+impl ReverbTank {
+    fn new(spread: usize) -> Self {
         let comb_lengths = [1116, 1188, 1277, 1356]; // Prime lengths
         let allpass_lengths = [225, 556];
 
-        SimpleReverb {
-            comb_buffers: comb_lengths.iter().map(|&l| vec![0.0; l]).collect(),
+        ReverbTank {
+            comb_buffers: comb_lengths
+                .iter()
+                .map(|&l| vec![0.0; l + spread])
+                .collect(),
             allpass_buffers: allpass_lengths
                 .iter()
-                .map(|&l| vec![0.0; l])
+                .map(|&l| vec![0.0; l + spread])
                 .collect(),
             comb_indices: vec![0; comb_lengths.len()],
             allpass_indices: vec![0; allpass_lengths.len()],
-            feedback,
-            mix,
         }
     }
+
+    /// Wet signal for one input sample
+    fn process(&mut self, input: Float, feedback: Float) -> Float {
+        // === Comb Filters ===
+        let mut comb_sum = 0.0;
+        for (i, buffer) in self.comb_buffers.iter_mut().enumerate() {
+            let idx = self.comb_indices[i];
+            let out = buffer[idx];
+            buffer[idx] = input + out * feedback;
+            self.comb_indices[i] = (idx + 1) % buffer.len();
+            comb_sum += out;
+        }
+
+        // === Allpass Filters ===
+        let mut y = comb_sum;
+        for (i, buffer) in self.allpass_buffers.iter_mut().enumerate() {
+            let idx = self.allpass_indices[i];
+            let buf_val = buffer[idx];
+            let input = y;
+            y = -input + buf_val;
+            buffer[idx] = input + buf_val * 0.5;
+            self.allpass_indices[i] = (idx + 1) % buffer.len();
+        }
+        y
+    }
+
+    fn reset(&mut self) {
+        for buffer in self.comb_buffers.iter_mut() {
+            buffer.fill(0.0);
+        }
+        for buffer in self.allpass_buffers.iter_mut() {
+            buffer.fill(0.0);
+        }
+        self.comb_indices.fill(0);
+        self.allpass_indices.fill(0);
+    }
 }
 
 impl AudioEffect for SimpleReverb {
+    fn describe(&self) -> Option<EffectDescription> {
+        Some(EffectDescription {
+            kind: "SimpleReverb",
+            params: vec![("feedback", self.feedback), ("mix", self.mix)],
+        })
+    }
+
     fn process(&mut self, buffer: &mut AudioBuffer, _sr: u32) {
+        let mix = self.mix;
         match buffer {
             AudioBuffer::Mono(ref mut buf) => {
                 for s in buf {
-                    // === Comb Filters ===
-                    let mut comb_sum = 0.0;
-                    for (i, buffer) in self.comb_buffers.iter_mut().enumerate()
-                    {
-                        let idx = self.comb_indices[i];
-                        let out = buffer[idx];
-                        buffer[idx] = *s + out * self.feedback;
-                        self.comb_indices[i] = (idx + 1) % buffer.len();
-                        comb_sum += out;
-                    }
-
-                    // === Allpass Filters ===
-                    let mut y = comb_sum;
-                    for (i, buffer) in
-                        self.allpass_buffers.iter_mut().enumerate()
-                    {
-                        let idx = self.allpass_indices[i];
-                        let buf_val = buffer[idx];
-                        let input = y;
-                        y = -input + buf_val;
-                        buffer[idx] = input + buf_val * 0.5;
-                        self.allpass_indices[i] = (idx + 1) % buffer.len();
-                    }
-
-                    let wet = y;
-                    *s = *s * (1.0 - self.mix) + wet * self.mix;
+                    let wet = self.left.process(*s, self.feedback);
+                    *s = *s * (1.0 - mix) + wet * mix;
                 }
             }
-            _ => todo!("Stereo reverb not implemented yet"),
+            AudioBuffer::Stereo(ref mut buf) => {
+                for (l, r) in buf {
+                    let wet_l = self.left.process(*l, self.feedback);
+                    let wet_r = self.right.process(*r, self.feedback);
+                    *l = *l * (1.0 - mix) + wet_l * mix;
+                    *r = *r * (1.0 - mix) + wet_r * mix;
+                }
+            }
+        }
+    }
+
+    /// The longest comb filter rings the longest, the allpass filters add a
+    /// short decay of their own
+    fn tail_time(&self, sample_rate: u32) -> Seconds {
+        let tank = &self.right;
+        let longest_comb =
+            tank.comb_buffers.iter().map(|b| b.len()).max().unwrap_or(0);
+        let Some(comb_steps) = decay_steps(self.feedback) else {
+            return MAX_TAIL_TIME;
+        };
+        let allpass_steps = decay_steps(0.5).unwrap_or(1.0);
+        let allpass_len: usize =
+            tank.allpass_buffers.iter().map(|b| b.len()).sum();
+
+        let samples = longest_comb as Float * comb_steps
+            + allpass_len as Float * allpass_steps;
+        (samples / sample_rate as Float).min(MAX_TAIL_TIME)
+    }
+
+    fn reset(&mut self) {
+        self.left.reset();
+        self.right.reset();
+    }
+
+    fn set_param(&mut self, target: ModulationTarget, value: Float) -> bool {
+        match target {
+            ModulationTarget::ReverbMix => self.mix = value.clamp(0.0, 1.0),
+            _ => return false,
         }
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pan_turns_mono_into_stereo() {
+        let mut buffer = AudioBuffer::Mono(vec![1.0; 4]);
+        Pan { position: 1.0 }.process(&mut buffer, 8000);
+        assert_eq!(buffer.get_stereo(0), (0.0, 1.0));
+    }
+
+    #[test]
+    fn balance_keeps_stereo_width() {
+        let mut buffer = AudioBuffer::Stereo(vec![(1.0, -0.5); 4]);
+        Balance { position: 0.5 }.process(&mut buffer, 8000);
+        assert_eq!(buffer.get_stereo(0), (0.5, -0.5));
+
+        let mut buffer = AudioBuffer::Stereo(vec![(1.0, -0.5); 4]);
+        Pan { position: 0.0 }.process(&mut buffer, 8000);
+        let (l, r) = buffer.get_stereo(0);
+        assert_eq!(l, r);
     }
 }
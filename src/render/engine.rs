@@ -1,33 +1,49 @@
 use std::collections::{HashMap, VecDeque};
-use std::fs::File;
+use std::fs::{create_dir_all, File};
 use std::io::BufWriter;
+use std::path::Path;
 
 use hound::{SampleFormat, WavSpec, WavWriter};
 
-use super::effect::AudioEffect;
-use super::instrument::Instrument;
+use super::effect::{AudioEffect, MAX_TAIL_TIME, SILENCE_THRESHOLD};
+use super::instrument::{DrumKit, Instrument};
+use super::parallel;
+use super::pitch::PitchCurve;
 use super::processor::AudioBuffer;
-use super::types::{Float, Seconds};
-use crate::compose::Part;
+use super::types::{Float, Seconds, StereoBuffer};
+use super::voice::Voice;
+use crate::compose::{Part, Unpitched};
 
 /// The top level of the rendering layer
 pub struct Engine {
     pub sample_rate: u32,
     pub block_size: usize,
     pub node_graph: Graph,
+
+    /// Worker threads that render the tracks, None for one per CPU core.
+    /// The output is the same for any number of threads.
+    pub threads: Option<usize>,
 }
 
 impl Engine {
-    /// Offline render
+    /// Offline render. Nodes are processed block by block in topological
+    /// order and every output node writes the sum of its inputs to a WAV
+    /// file. The render lasts until the last note of every track has been
+    /// released and the effect tails have decayed.
     pub fn render(&mut self) {
         // Each output node will write to a file using its own buffered WAV
         // writer
-        let mut writer_out_map: HashMap<NodeId, WavWriter<BufWriter<File>>> =
-            HashMap::new();
+        let mut writer_out_map: HashMap<NodeId, OutputWriter> = HashMap::new();
         for node in &self.node_graph.nodes {
             if let NodeKind::Output { target } = &node.kind {
+                let path = Path::new(target);
+                if let Some(parent) = path.parent() {
+                    create_dir_all(parent)
+                        .expect("Parent path should be created");
+                }
+
                 let writer = WavWriter::create(
-                    target,
+                    path,
                     WavSpec {
                         channels: 2,
                         sample_rate: self.sample_rate,
@@ -37,151 +53,575 @@ impl Engine {
                 )
                 .expect("Failed to create WavWriter");
 
-                writer_out_map.insert(node.id, writer);
+                writer_out_map.insert(
+                    node.id,
+                    OutputWriter { writer, trimmer: TailTrimmer::default() },
+                );
             }
         }
 
-        // Each node may have an associated audio buffer. A map keeps track of
-        // these assignments.
-        let mut buf_map: HashMap<NodeId, AudioBuffer> = HashMap::new();
+        let outputs: Vec<NodeId> = writer_out_map.keys().copied().collect();
+        let mut renderer = BlockRenderer::new(
+            &mut self.node_graph,
+            self.sample_rate,
+            self.threads,
+            &outputs,
+        );
 
-        let nodes_sorted = self.node_graph.topological_sort();
+        while let Some(start) =
+            renderer.process_block(&mut self.node_graph, self.block_size)
+        {
+            for (id, writer) in &mut writer_out_map {
+                for (i, &frame) in renderer.output(*id).iter().enumerate() {
+                    writer.write(frame, renderer.in_tail(start + i as u64));
+                }
+            }
+        }
 
-        // Collect note events for all track nodes
-        let event_queue_map: HashMap<NodeId, Vec<NoteEvent>> = HashMap::new();
-        for node in &self.node_graph.nodes {
-            if let NodeKind::Track { source, driver } = &node.kind {
-                let queue = driver.collect_events();
-                event_queue_map.insert(node.id, queue);
+        for writer in writer_out_map.into_values() {
+            writer.finalize();
+        }
+    }
+
+    /// Pull-based render of a single node (usually an output node). Every
+    /// item is a block of `block_size` stereo frames, only the last block may
+    /// be shorter. Memory use does not grow with the length of the music, so
+    /// long pieces can be written to disk or piped to other tools as they
+    /// are rendered. The stream produces the same samples as `render`.
+    pub fn stream(&mut self, output: NodeId) -> EngineStream<'_> {
+        assert!(
+            self.node_graph.get_node(output).is_some(),
+            "Streamed node should be found in graph"
+        );
+        let renderer = BlockRenderer::new(
+            &mut self.node_graph,
+            self.sample_rate,
+            self.threads,
+            &[output],
+        );
+        EngineStream {
+            engine: self,
+            renderer,
+            output,
+            trimmer: TailTrimmer::default(),
+            ready: VecDeque::new(),
+            finished: false,
+        }
+    }
+}
+
+/// Iterator over the rendered blocks of one node, see `Engine::stream`
+pub struct EngineStream<'a> {
+    engine: &'a mut Engine,
+    renderer: BlockRenderer,
+    output: NodeId,
+    trimmer: TailTrimmer,
+
+    /// Frames that passed the tail trimmer but were not yielded yet
+    ready: VecDeque<(Float, Float)>,
+    finished: bool,
+}
+
+impl Iterator for EngineStream<'_> {
+    type Item = StereoBuffer;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let block_size = self.engine.block_size;
+
+        while !self.finished && self.ready.len() < block_size {
+            let graph = &mut self.engine.node_graph;
+            let Some(start) = self.renderer.process_block(graph, block_size)
+            else {
+                self.finished = true;
+                break;
+            };
+
+            let ready = &mut self.ready;
+            for (i, &frame) in
+                self.renderer.output(self.output).iter().enumerate()
+            {
+                let in_tail = self.renderer.in_tail(start + i as u64);
+                self.trimmer.push(frame, in_tail, |f| ready.push_back(f));
             }
         }
 
-        // Initialize active note buffers
-        let mut active_note_map: HashMap<NodeId, Vec<NoteEvent>> =
-            HashMap::new();
-        for (k, v) in event_queue_map {
-            active_note_map.insert(k, vec![]);
+        if self.ready.is_empty() {
+            return None;
         }
+        let len = self.ready.len().min(block_size);
+        Some(self.ready.drain(..len).collect())
+    }
+}
+
+/// Number of blocks each track renders ahead. Larger batches spend less time
+/// starting threads but hold more audio in memory.
+const PREFETCH_BLOCKS: usize = 64;
+
+/// Graph processing state shared by `Engine::render` and `Engine::stream`
+struct BlockRenderer {
+    sample_rate: u32,
+    threads: Option<usize>,
+    nodes_sorted: Vec<NodeId>,
+    clock: Clock,
+    track_map: HashMap<NodeId, TrackState>,
+
+    /// Blocks rendered ahead for each track node. Tracks do not depend on
+    /// other nodes, so they are rendered concurrently in batches.
+    track_blocks: HashMap<NodeId, VecDeque<AudioBuffer>>,
+
+    /// Inputs of feedback nodes are read after the block is complete and
+    /// played back in the next one
+    feedback_map: HashMap<NodeId, AudioBuffer>,
+
+    /// Each node may have an associated audio buffer. A map keeps track of
+    /// these assignments.
+    buf_map: HashMap<NodeId, AudioBuffer>,
 
-        // Calculate total render time
-        let mut max_note_end_time: f64 = 0.0;
-        for q in event_queue_map.values() {
-            for e in q {
-                max_note_end_time = max_note_end_time.max(e.end);
+    /// Samples until the last release
+    content_samples: u64,
+
+    /// Samples until the estimated end of the effect tails
+    total_samples: u64,
+}
+
+impl BlockRenderer {
+    /// Prepare a render of the graph. The render length covers the tails
+    /// that reach the given output nodes. Effects are reset, so state left
+    /// by an earlier render does not leak into this one.
+    fn new(
+        graph: &mut Graph,
+        sample_rate: u32,
+        threads: Option<usize>,
+        outputs: &[NodeId],
+    ) -> Self {
+        let nodes_sorted =
+            graph.topological_sort().expect("Graph should not have cycles");
+
+        for node in &mut graph.nodes {
+            match &mut node.kind {
+                NodeKind::Effect { effect } => effect.reset(),
+                NodeKind::Track { source, .. } => {
+                    for index in 0..source.instrument_count() {
+                        if let Some(fx) = &mut source.instrument_mut(index).fx {
+                            fx.reset();
+                        }
+                    }
+                }
+                _ => {}
             }
         }
 
-        let mut max_release_time: f64 = 0.0;
-        for n in &self.node_graph.nodes {
-            if let NodeKind::Track { source, .. } = &n.kind {
-                max_release_time = max_release_time.max(source.release_time());
+        // Collect note events for all track nodes
+        let mut track_map: HashMap<NodeId, TrackState> = HashMap::new();
+        for node in &graph.nodes {
+            if let NodeKind::Track { source, driver } = &node.kind {
+                track_map.insert(
+                    node.id,
+                    TrackState::new(source, driver, sample_rate),
+                );
             }
         }
 
-        let mut max_effect_tail: f64 = 0.0;
-        // TODO implement topological tail propagation to calculate tail time
-        // from effect nodes
-        //for node in &self.node_graph.nodes {
-        //    if let NodeKind::Effect { effect } = &node.kind {
-        //        max_effect_tail = max_effect_tail.max(effect.tail_time());
-        //    }
-        //}
-
-        let max_render_time: f64 =
-            max_note_end_time + max_release_time + max_effect_tail;
-        let mut block_time = 0.0;
-
-        while block_time < max_render_time {
-            // Clear buffer map for new block
-            buf_map.clear();
-
-            for node_id in nodes_sorted {
-                let node = &self
-                    .node_graph
-                    .get_node(node_id)
-                    .expect("Node should be found in graph");
-
-                let bufs_in = &self
-                    .node_graph
-                    .inputs(node_id)
-                    .iter()
-                    .filter_map(|input_id| buf_map.get(input_id))
-                    .cloned()
-                    .collect::<Vec<_>>();
+        // Calculate total render length. The tail is an upper bound, output
+        // that decays earlier is trimmed afterwards.
+        let content_samples =
+            track_map.values().map(|t| t.end_sample).max().unwrap_or(0) as u64;
+        let tail_map = graph.tail_times(sample_rate);
+        let max_tail =
+            outputs.iter().map(|id| tail_map[id]).fold(0.0, Float::max);
+        let total_samples =
+            content_samples + (max_tail * sample_rate as Float).ceil() as u64;
 
-                let mut buf_out =
-                    AudioBuffer::Stereo(vec![(0.0, 0.0); self.block_size]);
+        Self {
+            sample_rate,
+            threads,
+            nodes_sorted,
+            clock: Clock { sample_rate, sample_counter: 0 },
+            track_map,
+            track_blocks: HashMap::new(),
+            feedback_map: HashMap::new(),
+            buf_map: HashMap::new(),
+            content_samples,
+            total_samples,
+        }
+    }
 
-                // Process the node
-                match &node.kind {
-                    NodeKind::Track { source, driver } => {
-                        // Feed current clock time + block_size
-                    }
-                    NodeKind::Effect { effect } => {
-                        // An effect will only use the first input found
-                        if let Some(input) = bufs_in.first() {
-                            buf_out.clone_from(input);
+    /// Process all nodes for the next block. Returns the position of the
+    /// first sample of the block, or None once the render is complete.
+    fn process_block(
+        &mut self,
+        graph: &mut Graph,
+        block_size: usize,
+    ) -> Option<u64> {
+        let start = self.clock.sample();
+        if start >= self.total_samples {
+            return None;
+        }
+
+        // The last block is cut short so the render ends exactly with the
+        // estimated tail
+        let block_len =
+            (self.total_samples - start).min(block_size as u64) as usize;
+
+        if self.track_blocks.values().all(|q| q.is_empty()) {
+            self.prefetch_tracks(graph, block_size);
+        }
+
+        // Clear buffer map for new block
+        self.buf_map.clear();
+
+        for &node_id in &self.nodes_sorted {
+            let bufs_in = graph
+                .inputs(node_id)
+                .iter()
+                .filter_map(|input_id| self.buf_map.get(input_id))
+                .collect::<Vec<_>>();
+
+            let sidechain_ids = graph.sidechain_inputs(node_id);
+
+            let node = graph
+                .get_node_mut(node_id)
+                .expect("Node should be found in graph");
+
+            let mut buf_out = AudioBuffer::Stereo(vec![(0.0, 0.0); block_len]);
+
+            // Process the node
+            match &mut node.kind {
+                NodeKind::Track { .. } => {
+                    buf_out = self
+                        .track_blocks
+                        .get_mut(&node_id)
+                        .and_then(|q| q.pop_front())
+                        .expect("Track block should be prefetched")
+                        .to_stereo();
+                }
+                NodeKind::Effect { effect } => {
+                    // An effect will only use the first input found
+                    if let Some(input) = bufs_in.first() {
+                        buf_out.clone_from(input);
+                        if sidechain_ids.is_empty() {
                             effect.process(&mut buf_out, self.sample_rate);
-                        }
-                    }
-                    NodeKind::Send { amount } => {
-                        // Send will only use the first input found
-                        if let Some(b) = bufs_in.first() {
-                            for i in 0..self.block_size {
-                                b.scale(amount);
-
-                                // TODO all processing is in stereo, make this
-                                // more explicit
-                                let sample = b.get_stereo(i);
-                                out_buffer.set_stereo(i, sample);
+                        } else {
+                            let mut sidechain = AudioBuffer::Stereo(vec![
+                                (0.0, 0.0);
+                                block_len
+                            ]);
+                            for id in &sidechain_ids {
+                                sidechain.add(&self.buf_map[id]);
                             }
+                            effect.process_sidechain(
+                                &mut buf_out,
+                                &sidechain,
+                                self.sample_rate,
+                            );
                         }
                     }
-                    NodeKind::Bus => {
-                        // Sum all inputs
-                        for b in bufs_in {
-                            buf_out.add(b);
-                        }
+                }
+                NodeKind::Send { amount } => {
+                    // Send will only use the first input found
+                    if let Some(input) = bufs_in.first() {
+                        // TODO all processing is in stereo, make this more
+                        // explicit
+                        buf_out.clone_from(input);
+                        buf_out.scale(amount);
                     }
-                    NodeKind::Output { target } => {
-                        for b in bufs_in {
-                            buf_out.add(b);
+                }
+                NodeKind::Bus | NodeKind::Output { .. } => {
+                    // Sum all inputs
+                    for b in bufs_in {
+                        buf_out.add(b);
+                    }
+                }
+                NodeKind::Feedback => {
+                    if let Some(previous) = self.feedback_map.get(&node_id) {
+                        buf_out.clone_from(previous);
+                        buf_out.resize(block_len);
+                    }
+                }
+            }
+            self.buf_map.insert(node_id, buf_out);
+        }
+
+        for node in &graph.nodes {
+            if let NodeKind::Feedback = node.kind {
+                let mut buf = AudioBuffer::Stereo(vec![(0.0, 0.0); block_len]);
+                for input in graph.inputs(node.id) {
+                    buf.add(&self.buf_map[&input]);
+                }
+                self.feedback_map.insert(node.id, buf);
+            }
+        }
+
+        self.clock.advance(block_len);
+        Some(start)
+    }
+
+    /// Render the next PREFETCH_BLOCKS blocks of every track, one thread
+    /// per track. Each track still renders its blocks in order, so the
+    /// result does not depend on the number of threads.
+    fn prefetch_tracks(&mut self, graph: &mut Graph, block_size: usize) {
+        let mut blocks = vec![];
+        let mut pos = self.clock.sample();
+        while blocks.len() < PREFETCH_BLOCKS && pos < self.total_samples {
+            let len = (self.total_samples - pos).min(block_size as u64);
+            blocks.push((pos as usize, len as usize));
+            pos += len;
+        }
+
+        let mut sources: HashMap<NodeId, &mut SoundSource> = graph
+            .nodes
+            .iter_mut()
+            .filter_map(|n| match &mut n.kind {
+                NodeKind::Track { source, .. } => Some((n.id, source)),
+                _ => None,
+            })
+            .collect();
+        let jobs: Vec<_> = self
+            .track_map
+            .iter_mut()
+            .map(|(id, track)| {
+                let source =
+                    sources.remove(id).expect("Track node should be in graph");
+                (*id, track, source)
+            })
+            .collect();
+
+        let sample_rate = self.sample_rate;
+        let blocks = &blocks;
+        let rendered =
+            parallel::map(jobs, self.threads, |(id, track, source)| {
+                let queue = blocks
+                    .iter()
+                    .map(|&(start, len)| {
+                        track.render_block(source, start, len, sample_rate)
+                    })
+                    .collect::<VecDeque<_>>();
+                (id, queue)
+            });
+        self.track_blocks = rendered.into_iter().collect();
+    }
+
+    /// Buffer of a node for the last processed block
+    fn output(&self, id: NodeId) -> &StereoBuffer {
+        match &self.buf_map[&id] {
+            AudioBuffer::Stereo(b) => b,
+            AudioBuffer::Mono(_) => panic!("Output buffer must be stereo"),
+        }
+    }
+
+    /// Whether a sample lies after the last release
+    fn in_tail(&self, sample: u64) -> bool {
+        sample >= self.content_samples
+    }
+}
+
+/// Silent frames in the tail region are held back and only passed on if
+/// something audible follows, so the output ends where the tail decays below
+/// SILENCE_THRESHOLD. Only the silent stretch is buffered.
+#[derive(Default)]
+struct TailTrimmer {
+    pending: Vec<(Float, Float)>,
+}
+
+impl TailTrimmer {
+    fn push<F>(&mut self, frame: (Float, Float), in_tail: bool, mut emit: F)
+    where
+        F: FnMut((Float, Float)),
+    {
+        let silent = frame.0.abs() <= SILENCE_THRESHOLD
+            && frame.1.abs() <= SILENCE_THRESHOLD;
+        if in_tail && silent {
+            self.pending.push(frame);
+            return;
+        }
+
+        for pending in self.pending.drain(..) {
+            emit(pending);
+        }
+        emit(frame);
+    }
+}
+
+/// WAV writer of an output node
+struct OutputWriter {
+    writer: WavWriter<BufWriter<File>>,
+    trimmer: TailTrimmer,
+}
+
+impl OutputWriter {
+    fn write(&mut self, frame: (Float, Float), in_tail: bool) {
+        let writer = &mut self.writer;
+        self.trimmer.push(frame, in_tail, |(left, right)| {
+            writer.write_sample(left as f32).unwrap();
+            writer.write_sample(right as f32).unwrap();
+        });
+    }
+
+    /// Pending silence is dropped
+    fn finalize(self) {
+        self.writer.finalize().expect("Failed to finalize WAV file");
+    }
+}
+
+/// A voice being played by a track. Voices are rendered in full when they
+/// start and mixed into the blocks they overlap.
+struct PlayingVoice {
+    /// Index of the instrument of the sound source playing the voice
+    instrument: usize,
+    start_sample: usize,
+    buffer: AudioBuffer,
+}
+
+/// Playback state of a track node during a render
+struct TrackState {
+    /// Upcoming voices in order of onset, with the index of the instrument
+    /// that plays them
+    queue: VecDeque<(usize, Voice)>,
+    voices: Vec<PlayingVoice>,
+
+    /// Sample after the last release, or the end of the part if it is longer
+    end_sample: usize,
+}
+
+impl TrackState {
+    fn new(
+        source: &SoundSource,
+        driver: &EventDriver,
+        sample_rate: u32,
+    ) -> Self {
+        let mut events = driver.collect_events();
+        events.sort_by(|a, b| a.start.total_cmp(&b.start));
+
+        // Each instrument allocates the voices of its own notes
+        let mut routed = vec![vec![]; source.instrument_count()];
+        for event in events {
+            if let Some(index) = source.route(&event) {
+                routed[index].push(event);
+            }
+        }
+
+        let mut end_sample =
+            (driver.nominal_duration() * sample_rate as Float) as usize;
+        let mut queue = vec![];
+        for (index, events) in routed.iter().enumerate() {
+            let instrument = source.instrument(index);
+            for voice in instrument.allocate_voices(events) {
+                let length = instrument.voice_length(&voice, sample_rate);
+                end_sample = end_sample
+                    .max(voice.event.start_sample(sample_rate) + length);
+                queue.push((index, voice));
+            }
+        }
+        queue.sort_by(|a, b| a.1.start().total_cmp(&b.1.start()));
+
+        Self { queue: queue.into(), voices: vec![], end_sample }
+    }
+
+    /// Mix of the voices of a track for one block, with the global effects
+    /// of each instrument applied. The mix is mono unless an instrument
+    /// renders stereo voices.
+    fn render_block(
+        &mut self,
+        source: &mut SoundSource,
+        block_start: usize,
+        block_len: usize,
+        sample_rate: u32,
+    ) -> AudioBuffer {
+        let block_end = block_start + block_len;
+
+        // Start the voices of notes beginning in this block
+        while let Some((_, voice)) = self.queue.front() {
+            if voice.event.start_sample(sample_rate) >= block_end {
+                break;
+            }
+            let (index, voice) = self.queue.pop_front().unwrap();
+            let buffer =
+                source.instrument_mut(index).render_voice(&voice, sample_rate);
+            self.voices.push(PlayingVoice {
+                instrument: index,
+                start_sample: voice.event.start_sample(sample_rate),
+                buffer,
+            });
+        }
+
+        let silence = |stereo: bool| match stereo {
+            true => AudioBuffer::Stereo(vec![(0.0, 0.0); block_len]),
+            false => AudioBuffer::Mono(vec![0.0; block_len]),
+        };
+        let mut out = silence(source.is_stereo());
+        for index in 0..source.instrument_count() {
+            let mut buf = silence(source.instrument(index).is_stereo());
+            for voice in self.voices.iter().filter(|v| v.instrument == index) {
+                let from = voice.start_sample.max(block_start);
+                let to =
+                    (voice.start_sample + voice.buffer.len()).min(block_end);
+                match (&mut buf, &voice.buffer) {
+                    (AudioBuffer::Mono(b), AudioBuffer::Mono(v)) => {
+                        for pos in from..to {
+                            b[pos - block_start] += v[pos - voice.start_sample];
                         }
-                        if let AudioBuffer::Stereo(b) = &buf_out {
-                            for &(left, right) in b {
-                                writer_out_map
-                                    .get(&node_id)
-                                    .unwrap()
-                                    .write_sample(left as f32)
-                                    .unwrap();
-                                writer_out_map
-                                    .get(&node_id)
-                                    .unwrap()
-                                    .write_sample(right as f32)
-                                    .unwrap();
-                            }
-                        } else {
-                            panic!("Output buffer must be stereo")
+                    }
+                    (AudioBuffer::Stereo(b), AudioBuffer::Stereo(v)) => {
+                        for pos in from..to {
+                            let (l, r) = v[pos - voice.start_sample];
+                            b[pos - block_start].0 += l;
+                            b[pos - block_start].1 += r;
                         }
                     }
+                    _ => panic!("Voices must match their instrument channels"),
                 }
-                buf_map.insert(node_id, buf_out);
             }
 
-            block_time += self.block_size as f64 / self.sample_rate as f64;
+            if let Some(fx) = &mut source.instrument_mut(index).fx {
+                fx.process(&mut buf, sample_rate);
+            }
+            out.add(&buf);
         }
 
-        for (k, v) in writer_out_map {
-            v.finalize();
-        }
+        self.voices.retain(|v| v.start_sample + v.buffer.len() > block_end);
+        out
     }
 }
 
+#[derive(Clone)]
 pub struct NoteEvent {
     pub freq: Option<Float>,
     pub velocity: Float,
     pub start: Seconds,
     pub end: Seconds,
+
+    /// Staff position of percussion notes, used to pick a drum kit piece
+    pub unpitched: Option<Unpitched>,
+
+    /// MusicXML instrument id of the note, if the part defines several
+    pub instrument: Option<String>,
+
+    /// Notated onset of the event. Timing transforms (humanization, swing)
+    /// move `start` and `end` but leave the position untouched.
+    pub position: MusicalPosition,
+
+    /// Glides, bends and vibrato relative to `freq`
+    pub pitch: PitchCurve,
+}
+
+impl NoteEvent {
+    /// Index of the first sample of the event
+    pub fn start_sample(&self, sample_rate: u32) -> usize {
+        (self.start * sample_rate as Float).round() as usize
+    }
+}
+
+/// Location of an event in musical time
+#[derive(Clone, Copy, Default)]
+pub struct MusicalPosition {
+    /// Zero based index of the measure containing the onset
+    pub measure: usize,
+
+    /// Offset of the onset from the start of the measure in quarter notes
+    pub beat: Float,
+
+    /// Tempo in effect at the onset
+    pub seconds_per_beat: Seconds,
 }
 
 pub struct Clock {
@@ -209,6 +649,7 @@ impl Clock {
 }
 
 /// DAG audio node graph
+#[derive(Default)]
 pub struct Graph {
     pub nodes: Vec<Node>,
     pub edges: Vec<Edge>,
@@ -216,29 +657,117 @@ pub struct Graph {
 
 pub type NodeId = u32;
 
-// From => To
-pub type Edge = (NodeId, NodeId);
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Edge {
+    pub from: NodeId,
+    pub to: NodeId,
+    pub port: Port,
+}
+
+/// The input of a node an edge is connected to
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Port {
+    /// The signal that is processed
+    Main,
+
+    /// A control signal read by the effect, ie. the kick triggering a
+    /// ducking compressor. Only effect nodes have a sidechain input.
+    Sidechain,
+}
 
 impl Graph {
     pub fn new() -> Self {
         Graph { nodes: Vec::new(), edges: Vec::new() }
     }
 
-    /// Add a node to the graph and return its NodeId
+    /// Add a node to the graph and return its NodeId. The node is named
+    /// "node<n>" with the first n that no other node is named after.
     pub fn add_node(&mut self, kind: NodeKind) -> NodeId {
+        let name = (self.nodes.len()..)
+            .map(|n| format!("node{}", n))
+            .find(|name| self.find(name).is_none())
+            .expect("Some node name should be free");
+        self.push_node(name, kind)
+    }
+
+    /// Add a node with a name it can be looked up by. The name must not be
+    /// taken by another node.
+    pub fn add_named_node(
+        &mut self,
+        name: &str,
+        kind: NodeKind,
+    ) -> Result<NodeId, GraphError> {
+        if self.find(name).is_some() {
+            return Err(GraphError::DuplicateName(name.to_string()));
+        }
+        Ok(self.push_node(name.to_string(), kind))
+    }
+
+    fn push_node(&mut self, name: String, kind: NodeKind) -> NodeId {
         let id = self.nodes.len() as NodeId;
-        self.nodes.push(Node { id, kind });
+        self.nodes.push(Node { id, name, kind });
         id
     }
 
-    /// Connect two nodes (from → to)
-    pub fn connect(&mut self, from: NodeId, to: NodeId) {
-        self.edges.push((from, to));
+    /// Id of the node with the given name
+    pub fn find(&self, name: &str) -> Option<NodeId> {
+        self.nodes.iter().find(|n| n.name == name).map(|n| n.id)
     }
 
-    /// Disconnect two nodes (from → to)
+    /// Connect two nodes (from → to). The edge is rejected if either node
+    /// does not exist, if it leaves an output node or if it would close a
+    /// cycle. Loops must go through a `NodeKind::Feedback` node.
+    pub fn connect(
+        &mut self,
+        from: NodeId,
+        to: NodeId,
+    ) -> Result<(), GraphError> {
+        self.connect_port(from, to, Port::Main)
+    }
+
+    /// Connect a node to the sidechain input of an effect node (from → to).
+    /// Multiple sidechain inputs are summed.
+    pub fn connect_sidechain(
+        &mut self,
+        from: NodeId,
+        to: NodeId,
+    ) -> Result<(), GraphError> {
+        if let Some(node) = self.get_node(to) {
+            if !matches!(node.kind, NodeKind::Effect { .. }) {
+                return Err(GraphError::NoSidechain(to));
+            }
+        }
+        self.connect_port(from, to, Port::Sidechain)
+    }
+
+    fn connect_port(
+        &mut self,
+        from: NodeId,
+        to: NodeId,
+        port: Port,
+    ) -> Result<(), GraphError> {
+        for id in [from, to] {
+            if self.get_node(id).is_none() {
+                return Err(GraphError::UnknownNode(id));
+            }
+        }
+        if let Some(Node { kind: NodeKind::Output { .. }, .. }) =
+            self.get_node(from)
+        {
+            return Err(GraphError::FromOutput(from));
+        }
+
+        self.edges.push(Edge { from, to, port });
+        if let Err(err) = self.topological_sort() {
+            self.edges.pop();
+            return Err(err);
+        }
+        Ok(())
+    }
+
+    /// Disconnect two nodes (from → to), on both ports
     pub fn disconnect(&mut self, from: NodeId, to: NodeId) {
-        self.edges.retain(|(a, b)| *a != from || *b != to);
+        self.edges.retain(|e| e.from != from || e.to != to);
     }
 
     /// Get a reference to a node by its ID
@@ -251,19 +780,34 @@ impl Graph {
         self.nodes.iter_mut().find(|n| n.id == id)
     }
 
-    /// List all edges from a given node
+    /// List all edges from a given node, to any port
     pub fn outputs(&self, from: NodeId) -> Vec<NodeId> {
         self.edges
             .iter()
-            .filter_map(|(a, b)| if *a == from { Some(*b) } else { None })
+            .filter_map(|e| if e.from == from { Some(e.to) } else { None })
             .collect()
     }
 
-    /// List all edges into a given node
+    /// List all edges into the main input of a given node
     pub fn inputs(&self, to: NodeId) -> Vec<NodeId> {
+        self.port_inputs(to, Port::Main)
+    }
+
+    /// List all edges into the sidechain input of a given node
+    pub fn sidechain_inputs(&self, to: NodeId) -> Vec<NodeId> {
+        self.port_inputs(to, Port::Sidechain)
+    }
+
+    fn port_inputs(&self, to: NodeId, port: Port) -> Vec<NodeId> {
         self.edges
             .iter()
-            .filter_map(|(a, b)| if *b == to { Some(*a) } else { None })
+            .filter_map(|e| {
+                if e.to == to && e.port == port {
+                    Some(e.from)
+                } else {
+                    None
+                }
+            })
             .collect()
     }
 
@@ -277,20 +821,71 @@ impl Graph {
         self.outputs(id).len()
     }
 
-    pub fn topological_sort(&self) -> Vec<NodeId> {
+    /// Tail of every node in seconds: the longest tail of its inputs plus
+    /// its own. Tracks add the tail of their instrument effects.
+    pub fn tail_times(&self, sample_rate: u32) -> HashMap<NodeId, Seconds> {
+        let mut tails: HashMap<NodeId, Seconds> = HashMap::new();
+        let sorted = self.topological_sort().expect("Graph should be valid");
+        for id in sorted {
+            let node =
+                self.get_node(id).expect("Node should be found in graph");
+
+            // How long a loop rings depends on the effects inside it, assume
+            // the worst. Output that decays earlier is trimmed.
+            if let NodeKind::Feedback = node.kind {
+                tails.insert(id, MAX_TAIL_TIME);
+                continue;
+            }
+
+            let inputs = self
+                .inputs(id)
+                .iter()
+                .map(|input| tails[input])
+                .fold(0.0, Float::max);
+
+            let own = match &node.kind {
+                NodeKind::Track { source, .. } => source.tail_time(sample_rate),
+                NodeKind::Effect { effect } => {
+                    effect.tail_time(sample_rate)
+                        + effect.latency() as Seconds / sample_rate as Seconds
+                }
+                _ => 0.0,
+            };
+            tails.insert(id, (inputs + own).min(MAX_TAIL_TIME));
+        }
+        tails
+    }
+
+    fn is_feedback(&self, id: NodeId) -> bool {
+        matches!(self.get_node(id), Some(Node { kind: NodeKind::Feedback, .. }))
+    }
+
+    /// Order in which nodes have to be processed so that every node comes
+    /// after its inputs. Edges into feedback nodes are not dependencies, the
+    /// feedback node outputs what it received in the previous block.
+    pub fn topological_sort(&self) -> Result<Vec<NodeId>, GraphError> {
+        let dependencies: Vec<(NodeId, NodeId)> = self
+            .edges
+            .iter()
+            .filter(|e| !self.is_feedback(e.to))
+            .map(|e| (e.from, e.to))
+            .collect();
+
         // Count incoming edges per node
         let mut in_degree: HashMap<NodeId, usize> =
             self.nodes.iter().map(|n| (n.id, 0)).collect();
 
-        for &(_, to) in &self.edges {
+        for &(_, to) in &dependencies {
             *in_degree.entry(to).or_insert(0) += 1;
         }
 
-        // Start with nodes that have no incoming edges
-        let mut queue: VecDeque<NodeId> = in_degree
+        // Start with nodes that have no incoming edges, in insertion order so
+        // the processing order is stable between renders
+        let mut queue: VecDeque<NodeId> = self
+            .nodes
             .iter()
-            .filter(|(_, &deg)| deg == 0)
-            .map(|(&id, _)| id)
+            .map(|n| n.id)
+            .filter(|id| in_degree[id] == 0)
             .collect();
 
         let mut result = Vec::new();
@@ -298,7 +893,7 @@ impl Graph {
         while let Some(current) = queue.pop_front() {
             result.push(current);
 
-            for &(from, to) in &self.edges {
+            for &(from, to) in &dependencies {
                 if from == current {
                     if let Some(degree) = in_degree.get_mut(&to) {
                         *degree -= 1;
@@ -312,29 +907,103 @@ impl Graph {
 
         // Check for cycles
         if result.len() != self.nodes.len() {
-            panic!("Graph has cycles; cannot perform topological sort");
+            let cycle = self
+                .nodes
+                .iter()
+                .map(|n| n.id)
+                .filter(|id| !result.contains(id))
+                .collect();
+            return Err(GraphError::Cycle(cycle));
         }
 
-        result
+        Ok(result)
     }
 }
 
+#[derive(Debug)]
+pub enum GraphError {
+    /// No node with the id exists in the graph
+    UnknownNode(NodeId),
+
+    /// Output nodes are sinks and cannot feed other nodes
+    FromOutput(NodeId),
+
+    /// Sidechain connection into a node that is not an effect
+    NoSidechain(NodeId),
+
+    /// Nodes that are part of (or depend on) a cycle without a feedback node
+    Cycle(Vec<NodeId>),
+
+    /// Another node already has the name
+    DuplicateName(String),
+}
+
+impl std::fmt::Display for GraphError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::UnknownNode(id) => write!(f, "Unknown node id {}", id),
+            Self::FromOutput(id) => {
+                write!(
+                    f,
+                    "Output node {} cannot be connected to other nodes",
+                    id
+                )
+            }
+            Self::NoSidechain(id) => {
+                write!(f, "Node {} has no sidechain input", id)
+            }
+            Self::Cycle(ids) => write!(
+                f,
+                "Graph has a cycle without a feedback node between nodes {:?}",
+                ids
+            ),
+            Self::DuplicateName(name) => {
+                write!(f, "Duplicate node name '{}'", name)
+            }
+        }
+    }
+}
+
+impl std::error::Error for GraphError {}
+
 pub struct Node {
     pub id: NodeId,
+
+    /// Refers to the node in the text format, unique within a graph
+    pub name: String,
     pub kind: NodeKind,
 }
 
 pub enum NodeKind {
-    Track { source: SoundSource, driver: EventDriver },
-    Effect { effect: Box<dyn AudioEffect> },
+    Track {
+        source: SoundSource,
+        driver: EventDriver,
+    },
+    Effect {
+        effect: Box<dyn AudioEffect>,
+    },
     Bus,
-    Send { amount: f64 },
-    Output { target: String },
+    Send {
+        amount: f64,
+    },
+
+    /// Allows loops in the graph, ie. a delay send routed back into itself.
+    /// The node outputs the sum of its inputs from the previous block, so the
+    /// loop is delayed by one block. Use an effect in the loop to set the
+    /// feedback level.
+    Feedback,
+    Output {
+        target: String,
+    },
 }
 
 /// Component of a track that dictates when audio events occur
 pub enum EventDriver {
-    MusicXmlPart(Part),
+    /// Boxed, a part is much larger than the other drivers
+    MusicXmlPart(Box<Part>),
+
+    /// Events collected ahead of time, ie. a part after humanization
+    Events(Vec<NoteEvent>),
     // TODO impl generative or random drivers
 }
 
@@ -343,6 +1012,18 @@ impl EventDriver {
     pub fn collect_events(&self) -> Vec<NoteEvent> {
         match self {
             Self::MusicXmlPart(p) => p.collect_events(),
+            Self::Events(events) => events.clone(),
+        }
+    }
+
+    /// Length of the material without note releases. Trailing rests of a
+    /// part count, events end with the last note.
+    pub fn nominal_duration(&self) -> Seconds {
+        match self {
+            Self::MusicXmlPart(p) => p.nominal_duration_seconds(),
+            Self::Events(events) => {
+                events.iter().map(|e| e.end).fold(0.0, Float::max)
+            }
         }
     }
 }
@@ -350,12 +1031,123 @@ impl EventDriver {
 /// Component of a track that defines how events are converted to samples
 pub enum SoundSource {
     Instrument(Instrument),
+
+    /// Percussion part where each unpitched note selects its own instrument
+    DrumKit(DrumKit),
 }
 
 impl SoundSource {
     pub fn release_time(&self) -> f64 {
         match &self {
             Self::Instrument(inst) => inst.max_release_time(),
+            Self::DrumKit(kit) => kit.max_release_time(),
         }
     }
+
+    /// Longest tail of the instrument effects
+    pub fn tail_time(&self, sample_rate: u32) -> Seconds {
+        (0..self.instrument_count())
+            .map(|i| self.instrument(i).tail_time(sample_rate))
+            .fold(0.0, Float::max)
+    }
+
+    /// Whether any instrument renders stereo voices
+    pub fn is_stereo(&self) -> bool {
+        (0..self.instrument_count()).any(|i| self.instrument(i).is_stereo())
+    }
+
+    /// Number of instruments with their own voices and effects
+    pub fn instrument_count(&self) -> usize {
+        match self {
+            Self::Instrument(_) => 1,
+            Self::DrumKit(kit) => kit.pieces.len(),
+        }
+    }
+
+    pub fn instrument(&self, index: usize) -> &Instrument {
+        match self {
+            Self::Instrument(inst) => inst,
+            Self::DrumKit(kit) => &kit.pieces[index].instrument,
+        }
+    }
+
+    pub fn instrument_mut(&mut self, index: usize) -> &mut Instrument {
+        match self {
+            Self::Instrument(inst) => inst,
+            Self::DrumKit(kit) => &mut kit.pieces[index].instrument,
+        }
+    }
+
+    /// Index of the instrument that plays an event. Kit notes without a
+    /// matching piece are not played.
+    pub fn route(&self, event: &NoteEvent) -> Option<usize> {
+        match self {
+            Self::Instrument(_) => Some(0),
+            Self::DrumKit(kit) => kit.piece_index(event),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::render::effect::{Delay, EffectChain, SimpleReverb};
+    use crate::render::instrument::picked_bass;
+
+    #[test]
+    fn renders_of_one_engine_are_identical() {
+        let mut instrument = picked_bass();
+        instrument.fx = Some(EffectChain {
+            effects: vec![Box::new(Delay::new(800, 0.5, 0.4))],
+        });
+        let events = (0..4)
+            .map(|i| NoteEvent {
+                freq: Some(110.0),
+                velocity: 0.8,
+                start: i as Seconds * 0.1,
+                end: i as Seconds * 0.1 + 0.05,
+                unpitched: None,
+                instrument: None,
+                position: MusicalPosition::default(),
+                pitch: PitchCurve::default(),
+            })
+            .collect();
+
+        let mut graph = Graph::new();
+        let track = graph.add_node(NodeKind::Track {
+            source: SoundSource::Instrument(instrument),
+            driver: EventDriver::Events(events),
+        });
+        let reverb = graph.add_node(NodeKind::Effect {
+            effect: Box::new(SimpleReverb::new(0.7, 0.3)),
+        });
+        let out = graph.add_node(NodeKind::Output { target: String::new() });
+        graph.connect(track, reverb).unwrap();
+        graph.connect(reverb, out).unwrap();
+
+        let mut engine = Engine {
+            sample_rate: 8000,
+            block_size: 128,
+            node_graph: graph,
+            threads: None,
+        };
+        let first: StereoBuffer = engine.stream(out).flatten().collect();
+        let second: StereoBuffer = engine.stream(out).flatten().collect();
+        assert!(first.iter().any(|&(l, _)| l != 0.0));
+        assert_eq!(first, second);
+    }
+
+    #[test]
+    fn node_names_are_unique() {
+        let mut graph = Graph::new();
+        graph.add_named_node("node1", NodeKind::Bus).unwrap();
+        let first = graph.add_node(NodeKind::Bus);
+        let second = graph.add_node(NodeKind::Bus);
+        assert_eq!(graph.get_node(first).unwrap().name, "node2");
+        assert_eq!(graph.get_node(second).unwrap().name, "node3");
+        assert!(matches!(
+            graph.add_named_node("node2", NodeKind::Bus),
+            Err(GraphError::DuplicateName(_))
+        ));
+    }
 }
@@ -0,0 +1,319 @@
+//! Regression checks that compare a render to a stored reference WAV, so
+//! changes to the DSP code that alter the sound don't go unnoticed.
+//!
+//! Set the `LYRA_REGENERATE_GOLDEN` environment variable to overwrite the
+//! references with the current renders instead of comparing them.
+
+use std::fmt;
+use std::path::Path;
+
+use super::dsp::spectrum::average_spectrum;
+use super::engine::{Engine, NodeId};
+use super::processor::AudioBuffer;
+use super::types::{Float, Hz};
+use super::wav::{read_wav, save_to_wav};
+
+/// When set (to anything but "0") references are rewritten instead of checked
+pub const REGENERATE_VAR: &str = "LYRA_REGENERATE_GOLDEN";
+
+/// Samples per analysis frame of the spectral comparison
+const FRAME_LEN: usize = 4096;
+
+/// Lower edge of the first third-octave band
+const LOWEST_BAND: Hz = 20.0;
+
+/// Bands this far below the loudest reference band are not compared, the
+/// difference between two near silent bands is mostly noise
+const BAND_FLOOR_DB: Float = -80.0;
+
+pub struct Golden {
+    /// Path of the reference WAV file
+    pub reference: String,
+
+    /// Largest allowed difference of any sample
+    pub sample_tolerance: Float,
+
+    /// Largest allowed level difference of any third-octave band
+    pub spectral_tolerance_db: Float,
+}
+
+impl Golden {
+    /// Default tolerances allow for the 32 bit float precision of the
+    /// reference file and little else
+    pub fn new(reference: &str) -> Self {
+        Self {
+            reference: reference.to_string(),
+            sample_tolerance: 1e-4,
+            spectral_tolerance_db: 0.1,
+        }
+    }
+
+    /// Compare a render to the reference, or overwrite the reference when
+    /// regenerating
+    pub fn check(
+        &self,
+        buffer: &AudioBuffer,
+        sample_rate: u32,
+    ) -> GoldenReport {
+        let mut report = GoldenReport {
+            reference: self.reference.clone(),
+            status: GoldenStatus::Passed,
+            sample_rate,
+            reference_sample_rate: sample_rate,
+            rendered_len: buffer.len(),
+            reference_len: buffer.len(),
+            peak_error: 0.0,
+            peak_frame: 0,
+            peak_channel: 0,
+            rms_error: 0.0,
+            worst_band: None,
+            sample_tolerance: self.sample_tolerance,
+            spectral_tolerance_db: self.spectral_tolerance_db,
+        };
+
+        if regenerate() {
+            save_to_wav(&self.reference, sample_rate, buffer);
+            report.status = GoldenStatus::Regenerated;
+            return report;
+        }
+        if !Path::new(&self.reference).exists() {
+            report.status = GoldenStatus::MissingReference;
+            return report;
+        }
+
+        let (reference, reference_rate) = read_wav(&self.reference);
+        report.reference_len = reference.len();
+        report.reference_sample_rate = reference_rate;
+        if reference_rate != sample_rate {
+            report.status = GoldenStatus::Failed;
+            return report;
+        }
+
+        let rendered = stereo_frames(buffer);
+        let reference = stereo_frames(&reference);
+        report.compare_samples(&rendered, &reference);
+        report.worst_band = worst_band(&rendered, &reference, sample_rate);
+
+        let band_failed = report.worst_band.as_ref().is_some_and(|b| {
+            b.difference_db.abs() > self.spectral_tolerance_db
+        });
+        if report.rendered_len != report.reference_len
+            || report.peak_error > self.sample_tolerance
+            || band_failed
+        {
+            report.status = GoldenStatus::Failed;
+        }
+        report
+    }
+
+    /// Check and panic with the report if the render doesn't match
+    pub fn assert_matches(&self, buffer: &AudioBuffer, sample_rate: u32) {
+        let report = self.check(buffer, sample_rate);
+        assert!(report.passed(), "{}", report);
+    }
+
+    /// Stream an output node of an engine and check the result
+    pub fn check_engine(
+        &self,
+        engine: &mut Engine,
+        output: NodeId,
+    ) -> GoldenReport {
+        let sample_rate = engine.sample_rate;
+        let frames = engine.stream(output).flatten().collect();
+        self.check(&AudioBuffer::Stereo(frames), sample_rate)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum GoldenStatus {
+    Passed,
+    Failed,
+
+    /// The reference was overwritten with the render
+    Regenerated,
+    MissingReference,
+}
+
+/// Outcome of a golden check. Display gives a human readable summary of
+/// where the render differs.
+pub struct GoldenReport {
+    pub reference: String,
+    pub status: GoldenStatus,
+    pub sample_rate: u32,
+    pub reference_sample_rate: u32,
+    pub rendered_len: usize,
+    pub reference_len: usize,
+
+    /// Largest absolute sample difference, where it occurs and on which
+    /// channel (0 is left)
+    pub peak_error: Float,
+    pub peak_frame: usize,
+    pub peak_channel: usize,
+    pub rms_error: Float,
+
+    /// Third-octave band with the largest level difference
+    pub worst_band: Option<BandDifference>,
+
+    pub sample_tolerance: Float,
+    pub spectral_tolerance_db: Float,
+}
+
+pub struct BandDifference {
+    pub low: Hz,
+    pub high: Hz,
+
+    /// Rendered level relative to the reference
+    pub difference_db: Float,
+}
+
+impl GoldenReport {
+    pub fn passed(&self) -> bool {
+        matches!(self.status, GoldenStatus::Passed | GoldenStatus::Regenerated)
+    }
+
+    /// Differences past the end of the shorter buffer count against silence
+    fn compare_samples(
+        &mut self,
+        rendered: &[(Float, Float)],
+        reference: &[(Float, Float)],
+    ) {
+        let len = rendered.len().max(reference.len());
+        let mut sum_sq = 0.0;
+        for i in 0..len {
+            let a = rendered.get(i).copied().unwrap_or((0.0, 0.0));
+            let b = reference.get(i).copied().unwrap_or((0.0, 0.0));
+            for (channel, diff) in [(0, a.0 - b.0), (1, a.1 - b.1)] {
+                sum_sq += diff * diff;
+                if diff.abs() > self.peak_error {
+                    self.peak_error = diff.abs();
+                    self.peak_frame = i;
+                    self.peak_channel = channel;
+                }
+            }
+        }
+        if len > 0 {
+            self.rms_error = (sum_sq / (2 * len) as Float).sqrt();
+        }
+    }
+}
+
+impl fmt::Display for GoldenReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.status {
+            GoldenStatus::Passed => {
+                return write!(f, "Golden '{}' matches", self.reference)
+            }
+            GoldenStatus::Regenerated => {
+                return write!(f, "Golden '{}' regenerated", self.reference)
+            }
+            GoldenStatus::MissingReference => {
+                return write!(
+                    f,
+                    "Golden '{}' has no reference, run with {}=1 to create it",
+                    self.reference, REGENERATE_VAR
+                )
+            }
+            GoldenStatus::Failed => {
+                writeln!(f, "Golden '{}' differs", self.reference)?
+            }
+        }
+
+        if self.sample_rate != self.reference_sample_rate {
+            return write!(
+                f,
+                "  sample rate: {} Hz rendered, {} Hz in reference",
+                self.sample_rate, self.reference_sample_rate
+            );
+        }
+
+        if self.rendered_len != self.reference_len {
+            writeln!(
+                f,
+                "  length: {} samples rendered, {} in reference",
+                self.rendered_len, self.reference_len
+            )?;
+        }
+        writeln!(
+            f,
+            "  peak error: {:.6} at sample {} ({:.3} s), {} channel \
+             (tolerance {})",
+            self.peak_error,
+            self.peak_frame,
+            self.peak_frame as Float / self.sample_rate as Float,
+            if self.peak_channel == 0 { "left" } else { "right" },
+            self.sample_tolerance
+        )?;
+        write!(f, "  rms error: {:.6}", self.rms_error)?;
+        if let Some(band) = &self.worst_band {
+            write!(
+                f,
+                "\n  spectrum: {:+.2} dB in band {:.0}-{:.0} Hz (tolerance {} dB)",
+                band.difference_db,
+                band.low,
+                band.high,
+                self.spectral_tolerance_db
+            )?;
+        }
+        Ok(())
+    }
+}
+
+fn regenerate() -> bool {
+    std::env::var(REGENERATE_VAR).is_ok_and(|v| !v.is_empty() && v != "0")
+}
+
+fn stereo_frames(buffer: &AudioBuffer) -> Vec<(Float, Float)> {
+    match buffer.to_stereo() {
+        AudioBuffer::Stereo(frames) => frames,
+        AudioBuffer::Mono(_) => unreachable!(),
+    }
+}
+
+/// Compare the third-octave band levels of the mono sums
+fn worst_band(
+    rendered: &[(Float, Float)],
+    reference: &[(Float, Float)],
+    sample_rate: u32,
+) -> Option<BandDifference> {
+    let mono = |frames: &[(Float, Float)]| -> Vec<Float> {
+        frames.iter().map(|(l, r)| (l + r) / 2.0).collect()
+    };
+    let rendered = average_spectrum(&mono(rendered), FRAME_LEN);
+    let reference = average_spectrum(&mono(reference), FRAME_LEN);
+
+    let bin_hz = sample_rate as Float / FRAME_LEN as Float;
+    let nyquist = sample_rate as Float / 2.0;
+    let level = |spectrum: &[Float], low: Hz, high: Hz| -> Float {
+        let energy: Float = spectrum
+            .iter()
+            .enumerate()
+            .filter(|(k, _)| (low..high).contains(&(*k as Float * bin_hz)))
+            .map(|(_, m)| m * m)
+            .sum();
+        10.0 * (energy + 1e-20).log10()
+    };
+
+    let mut bands = Vec::new();
+    let mut low = LOWEST_BAND;
+    while low < nyquist {
+        let high = (low * 2.0_f64.powf(1.0 / 3.0)).min(nyquist);
+        bands.push((
+            low,
+            high,
+            level(&rendered, low, high),
+            level(&reference, low, high),
+        ));
+        low = high;
+    }
+
+    let loudest = bands.iter().map(|b| b.3).fold(Float::MIN, Float::max);
+    bands
+        .into_iter()
+        .filter(|b| b.2.max(b.3) > loudest + BAND_FLOOR_DB)
+        .map(|(low, high, a, b)| BandDifference {
+            low,
+            high,
+            difference_db: a - b,
+        })
+        .max_by(|a, b| a.difference_db.abs().total_cmp(&b.difference_db.abs()))
+}
@@ -0,0 +1,639 @@
+//! Line based text format for engine graphs, so routing and effect settings
+//! can be changed without recompiling. Each line defines a node or an edge,
+//! `#` starts a comment and values containing spaces are quoted.
+//!
+//! ```text
+//! track kick source=kick_drum part=P1
+//! track pad source=pad part=P2
+//! effect duck Compressor threshold_db=-30 ratio=8
+//! effect verb SimpleReverb feedback=0.8 mix=0.3
+//! send verb_send amount=0.4
+//! bus mix
+//! output main target="output/song.wav"
+//!
+//! connect kick -> mix
+//! connect pad -> duck
+//! sidechain kick -> duck
+//! connect duck -> mix
+//! connect duck -> verb_send
+//! connect verb_send -> verb
+//! connect verb -> mix
+//! connect mix -> main
+//! ```
+//!
+//! Instruments and parts can't be described in the format, tracks refer to
+//! them by name through a `GraphRegistry`.
+
+use std::collections::HashMap;
+use std::fmt::{Debug, Write};
+use std::ops::RangeBounds;
+
+use super::effect::*;
+use super::engine::{EventDriver, Graph, NodeKind, Port, SoundSource};
+use super::instrument::{
+    drum_kit, electric_piano, hihat, kick_drum, organ, picked_bass, snare_drum,
+    supersaw,
+};
+use super::types::Float;
+use crate::compose::{Part, Score};
+
+type SourceFn = Box<dyn Fn() -> SoundSource>;
+type EffectFn = Box<dyn Fn(&Params) -> Result<Box<dyn AudioEffect>, String>>;
+
+/// Sound sources, effects and parts that a graph file can refer to
+pub struct GraphRegistry {
+    sources: HashMap<String, SourceFn>,
+    effects: HashMap<String, EffectFn>,
+
+    /// Parts by id. A part is moved into the track that plays it, so each
+    /// part can be used by one track.
+    parts: HashMap<String, Part>,
+}
+
+impl GraphRegistry {
+    /// Registry with the preset instruments and the built-in effects
+    pub fn new() -> Self {
+        let mut registry = Self {
+            sources: HashMap::new(),
+            effects: HashMap::new(),
+            parts: HashMap::new(),
+        };
+
+        registry.source("kick_drum", || SoundSource::Instrument(kick_drum()));
+        registry.source("snare_drum", || SoundSource::Instrument(snare_drum()));
+        registry.source("hihat", || SoundSource::Instrument(hihat()));
+        registry.source("drum_kit", || SoundSource::DrumKit(drum_kit()));
+        registry
+            .source("picked_bass", || SoundSource::Instrument(picked_bass()));
+        registry.source("electric_piano", || {
+            SoundSource::Instrument(electric_piano())
+        });
+        registry.source("organ", || SoundSource::Instrument(organ()));
+        registry.source("supersaw", || SoundSource::Instrument(supersaw()));
+
+        registry.effect("Pan", |p| {
+            p.check(&["position"])?;
+            let position = p.float_in_or("position", -1.0..=1.0, 0.0)?;
+            Ok(Box::new(Pan { position }))
+        });
+        registry.effect("Balance", |p| {
+            p.check(&["position"])?;
+            let position = p.float_in_or("position", -1.0..=1.0, 0.0)?;
+            Ok(Box::new(Balance { position }))
+        });
+        registry.effect("Gain", |p| {
+            p.check(&["amount"])?;
+            Ok(Box::new(Gain { amount: p.float_in_or("amount", 0.0.., 1.0)? }))
+        });
+        registry.effect("LowPass", |p| {
+            p.check(&["cutoff_hz"])?;
+            let cutoff = p.float_in("cutoff_hz", Float::MIN_POSITIVE..)?;
+            Ok(Box::new(LowPass::new(cutoff)))
+        });
+        registry.effect("Saturation", |p| {
+            p.check(&[])?;
+            Ok(Box::new(Saturation))
+        });
+        registry.effect("Distortion", |p| {
+            p.check(&["threshold"])?;
+            let threshold = p.float_in("threshold", Float::MIN_POSITIVE..)?;
+            Ok(Box::new(Distortion { threshold }))
+        });
+        registry.effect("Bitcrusher", |p| {
+            p.check(&["bits"])?;
+            Ok(Box::new(Bitcrusher {
+                bits: p.whole_in("bits", 1..=24)? as u32,
+            }))
+        });
+        registry.effect("Delay", |p| {
+            p.check(&["delay_samples", "feedback", "mix"])?;
+            Ok(Box::new(Delay::new(
+                p.whole_in("delay_samples", 1..)? as usize,
+                p.float_in_or("feedback", 0.0..1.0, 0.0)?,
+                p.float_in_or("mix", 0.0..=1.0, 0.5)?,
+            )))
+        });
+        registry.effect("Compressor", |p| {
+            p.check(&[
+                "threshold_db",
+                "ratio",
+                "attack",
+                "release",
+                "makeup_db",
+            ])?;
+            let ducker = Compressor::ducker();
+            let mut comp = Compressor::new(
+                p.float_or("threshold_db", ducker.threshold_db)?,
+                p.float_in_or("ratio", 1.0.., ducker.ratio)?,
+                p.float_in_or("attack", 0.0.., ducker.attack)?,
+                p.float_in_or("release", 0.0.., ducker.release)?,
+            );
+            comp.makeup_db = p.float_or("makeup_db", 0.0)?;
+            Ok(Box::new(comp))
+        });
+        registry.effect("Bypass", |p| {
+            p.check(&[])?;
+            Ok(Box::new(Bypass))
+        });
+        registry.effect("SimpleReverb", |p| {
+            p.check(&["feedback", "mix"])?;
+            Ok(Box::new(SimpleReverb::new(
+                p.float_in("feedback", 0.0..1.0)?,
+                p.float_in_or("mix", 0.0..=1.0, 0.3)?,
+            )))
+        });
+
+        registry
+    }
+
+    /// Register a sound source under a name, replacing any previous one
+    pub fn source<F>(&mut self, name: &str, f: F)
+    where
+        F: Fn() -> SoundSource + 'static,
+    {
+        self.sources.insert(name.to_string(), Box::new(f));
+    }
+
+    /// Register an effect type. The constructor should reject parameters it
+    /// doesn't know, see `Params::check`.
+    pub fn effect<F>(&mut self, kind: &str, f: F)
+    where
+        F: Fn(&Params) -> Result<Box<dyn AudioEffect>, String> + 'static,
+    {
+        self.effects.insert(kind.to_string(), Box::new(f));
+    }
+
+    /// Register a part under its id
+    pub fn part(&mut self, part: Part) {
+        self.parts.insert(part.id.clone(), part);
+    }
+
+    /// Register every part of a score
+    pub fn score(&mut self, score: Score) {
+        for part in score.parts {
+            self.part(part);
+        }
+    }
+}
+
+impl Default for GraphRegistry {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The `key=value` pairs of a line
+pub struct Params {
+    values: Vec<(String, String)>,
+}
+
+impl Params {
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.values.iter().find(|(k, _)| k == key).map(|(_, v)| v.as_str())
+    }
+
+    pub fn float(&self, key: &str) -> Result<Float, String> {
+        let value =
+            self.get(key).ok_or(format!("Missing parameter '{}'", key))?;
+        value
+            .parse::<Float>()
+            .ok()
+            .filter(|v| v.is_finite())
+            .ok_or(format!("Invalid number for '{}': '{}'", key, value))
+    }
+
+    pub fn float_or(&self, key: &str, default: Float) -> Result<Float, String> {
+        match self.get(key) {
+            Some(_) => self.float(key),
+            None => Ok(default),
+        }
+    }
+
+    /// Number that must lie in `range`, ie. `0.0..1.0` for a feedback
+    pub fn float_in<R>(&self, key: &str, range: R) -> Result<Float, String>
+    where
+        R: RangeBounds<Float> + Debug,
+    {
+        let value = self.float(key)?;
+        if !range.contains(&value) {
+            return Err(format!(
+                "'{}' should be in {:?}, found {}",
+                key, range, value
+            ));
+        }
+        Ok(value)
+    }
+
+    pub fn float_in_or<R>(
+        &self,
+        key: &str,
+        range: R,
+        default: Float,
+    ) -> Result<Float, String>
+    where
+        R: RangeBounds<Float> + Debug,
+    {
+        match self.get(key) {
+            Some(_) => self.float_in(key, range),
+            None => Ok(default),
+        }
+    }
+
+    /// Whole number that must lie in `range`, ie. a length in samples
+    pub fn whole_in<R>(&self, key: &str, range: R) -> Result<i64, String>
+    where
+        R: RangeBounds<i64> + Debug,
+    {
+        let value = self.float(key)?;
+        if value.fract() != 0.0 {
+            return Err(format!(
+                "Expected a whole number for '{}', found {}",
+                key, value
+            ));
+        }
+        let value = value as i64;
+        if !range.contains(&value) {
+            return Err(format!(
+                "'{}' should be in {:?}, found {}",
+                key, range, value
+            ));
+        }
+        Ok(value)
+    }
+
+    /// Fail on parameters that aren't in `known`, which catches typos that
+    /// would otherwise silently fall back to a default
+    pub fn check(&self, known: &[&str]) -> Result<(), String> {
+        match self.values.iter().find(|(k, _)| !known.contains(&k.as_str())) {
+            Some((key, _)) => Err(format!("Unknown parameter '{}'", key)),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Graph {
+    /// Build a graph from the text format. Edges may refer to nodes defined
+    /// further down. Errors name the offending line.
+    pub fn parse(
+        text: &str,
+        registry: &mut GraphRegistry,
+    ) -> Result<Self, String> {
+        let mut graph = Graph::new();
+        let mut edges = Vec::new();
+
+        for (index, line) in text.lines().enumerate() {
+            let number = index + 1;
+            let tokens = tokenize(line)
+                .map_err(|e| format!("line {}: {}", number, e))?;
+            if tokens.is_empty() {
+                continue;
+            }
+
+            match tokens[0].as_str() {
+                "connect" | "sidechain" => {
+                    let port = match tokens[0].as_str() {
+                        "connect" => Port::Main,
+                        _ => Port::Sidechain,
+                    };
+                    match &tokens[1..] {
+                        [from, arrow, to] if arrow == "->" => {
+                            edges.push((number, from.clone(), to.clone(), port))
+                        }
+                        _ => {
+                            return Err(format!(
+                                "line {}: Expected '{} <from> -> <to>'",
+                                number, tokens[0]
+                            ))
+                        }
+                    }
+                }
+                _ => {
+                    let (name, kind) = parse_node(&tokens, registry)
+                        .map_err(|e| format!("line {}: {}", number, e))?;
+                    graph
+                        .add_named_node(&name, kind)
+                        .map_err(|e| format!("line {}: {}", number, e))?;
+                }
+            }
+        }
+
+        for (number, from, to, port) in edges {
+            let lookup = |name: &str| {
+                graph
+                    .find(name)
+                    .ok_or(format!("line {}: Unknown node '{}'", number, name))
+            };
+            let (from, to) = (lookup(&from)?, lookup(&to)?);
+            let result = match port {
+                Port::Main => graph.connect(from, to),
+                Port::Sidechain => graph.connect_sidechain(from, to),
+            };
+            result.map_err(|e| format!("line {}: {}", number, e))?;
+        }
+
+        Ok(graph)
+    }
+
+    /// Write the graph in the text format, nodes first and edges after.
+    /// Fails for nodes that can't be described, ie. tracks playing
+    /// collected events or effects that don't implement `describe`.
+    pub fn to_text(&self) -> Result<String, String> {
+        let mut out = String::new();
+
+        for node in &self.nodes {
+            let name = quote(&node.name)?;
+            let line = match &node.kind {
+                NodeKind::Track { source, driver } => {
+                    let source = match source {
+                        SoundSource::Instrument(inst) => &inst.name,
+                        SoundSource::DrumKit(kit) => &kit.name,
+                    };
+                    if source.is_empty() {
+                        return Err(format!(
+                            "Track '{}' has an unnamed sound source",
+                            node.name
+                        ));
+                    }
+                    let part = match driver {
+                        EventDriver::MusicXmlPart(part) => &part.id,
+                        EventDriver::Events(_) => {
+                            return Err(format!(
+                                "Track '{}' plays collected events, which \
+                                 can't be saved",
+                                node.name
+                            ))
+                        }
+                    };
+                    format!(
+                        "track {} source={} part={}",
+                        name,
+                        quote(source)?,
+                        quote(part)?
+                    )
+                }
+                NodeKind::Effect { effect } => {
+                    let desc = effect.describe().ok_or(format!(
+                        "Effect '{}' can't be described",
+                        node.name
+                    ))?;
+                    let mut line = format!("effect {} {}", name, desc.kind);
+                    for (key, value) in desc.params {
+                        write!(line, " {}={}", key, value).unwrap();
+                    }
+                    line
+                }
+                NodeKind::Bus => format!("bus {}", name),
+                NodeKind::Send { amount } => {
+                    format!("send {} amount={}", name, amount)
+                }
+                NodeKind::Feedback => format!("feedback {}", name),
+                NodeKind::Output { target } => {
+                    format!("output {} target={}", name, quote(target)?)
+                }
+            };
+            writeln!(out, "{}", line).unwrap();
+        }
+
+        if !self.edges.is_empty() {
+            out.push('\n');
+        }
+        for edge in &self.edges {
+            let keyword = match edge.port {
+                Port::Main => "connect",
+                Port::Sidechain => "sidechain",
+            };
+            writeln!(
+                out,
+                "{} {} -> {}",
+                keyword,
+                quote(&self.nodes[edge.from as usize].name)?,
+                quote(&self.nodes[edge.to as usize].name)?
+            )
+            .unwrap();
+        }
+
+        Ok(out)
+    }
+
+    pub fn load(
+        path: &str,
+        registry: &mut GraphRegistry,
+    ) -> Result<Self, String> {
+        let text = std::fs::read_to_string(path)
+            .map_err(|e| format!("Failed to read '{}': {}", path, e))?;
+        Self::parse(&text, registry)
+    }
+
+    pub fn save(&self, path: &str) -> Result<(), String> {
+        let text = self.to_text()?;
+        std::fs::write(path, text)
+            .map_err(|e| format!("Failed to write '{}': {}", path, e))
+    }
+}
+
+/// Parse a node line into its name and kind
+fn parse_node(
+    tokens: &[String],
+    registry: &mut GraphRegistry,
+) -> Result<(String, NodeKind), String> {
+    let keyword = tokens[0].as_str();
+    let name = tokens
+        .get(1)
+        .ok_or(format!("Missing node name after '{}'", keyword))?
+        .clone();
+
+    // Effects have their type between the name and the parameters
+    let (effect_kind, rest) = match keyword {
+        "effect" => {
+            let kind = tokens
+                .get(2)
+                .ok_or(format!("Missing effect type for '{}'", name))?;
+            (Some(kind.as_str()), &tokens[3..])
+        }
+        _ => (None, &tokens[2..]),
+    };
+    let params = parse_params(rest)?;
+
+    let kind = match keyword {
+        "track" => {
+            params.check(&["source", "part"])?;
+            let source = params.get("source").ok_or("Missing 'source'")?;
+            let source = registry
+                .sources
+                .get(source)
+                .ok_or(format!("Unknown sound source '{}'", source))?(
+            );
+            let part = params.get("part").ok_or("Missing 'part'")?;
+            let part = registry.parts.remove(part).ok_or(format!(
+                "Unknown part '{}' (or it is already used by a track)",
+                part
+            ))?;
+            NodeKind::Track {
+                source,
+                driver: EventDriver::MusicXmlPart(Box::new(part)),
+            }
+        }
+        "effect" => {
+            let kind = effect_kind.unwrap();
+            let make = registry
+                .effects
+                .get(kind)
+                .ok_or(format!("Unknown effect '{}'", kind))?;
+            let effect = make(&params)
+                .map_err(|e| format!("Effect '{}': {}", kind, e))?;
+            NodeKind::Effect { effect }
+        }
+        "bus" => {
+            params.check(&[])?;
+            NodeKind::Bus
+        }
+        "send" => {
+            params.check(&["amount"])?;
+            NodeKind::Send { amount: params.float_in("amount", 0.0..)? }
+        }
+        "feedback" => {
+            params.check(&[])?;
+            NodeKind::Feedback
+        }
+        "output" => {
+            params.check(&["target"])?;
+            let target = params.get("target").ok_or("Missing 'target'")?;
+            NodeKind::Output { target: target.to_string() }
+        }
+        other => return Err(format!("Unknown line type '{}'", other)),
+    };
+
+    Ok((name, kind))
+}
+
+fn parse_params(tokens: &[String]) -> Result<Params, String> {
+    let values = tokens
+        .iter()
+        .map(|t| match t.split_once('=') {
+            Some((k, v)) => Ok((k.to_string(), v.to_string())),
+            None => Err(format!("Expected 'key=value', found '{}'", t)),
+        })
+        .collect::<Result<_, _>>()?;
+    Ok(Params { values })
+}
+
+/// Split a line on whitespace. Quotes group text with spaces into one token
+/// and are removed, `#` outside quotes ends the line.
+fn tokenize(line: &str) -> Result<Vec<String>, String> {
+    let mut tokens = Vec::new();
+    let mut token: Option<String> = None;
+    let mut quoted = false;
+
+    for c in line.chars() {
+        match c {
+            '"' => {
+                quoted = !quoted;
+                token.get_or_insert_with(String::new);
+            }
+            '#' if !quoted => break,
+            c if c.is_whitespace() && !quoted => {
+                tokens.extend(token.take());
+            }
+            c => token.get_or_insert_with(String::new).push(c),
+        }
+    }
+    if quoted {
+        return Err("Unterminated quote".to_string());
+    }
+    tokens.extend(token);
+    Ok(tokens)
+}
+
+/// Quote a value for writing if it would not survive `tokenize` as is
+fn quote(value: &str) -> Result<String, String> {
+    if value.contains('"') {
+        return Err(format!("Can't write value containing a quote: {}", value));
+    }
+    let plain = !value.is_empty()
+        && !value.contains('#')
+        && !value.chars().any(char::is_whitespace);
+    if plain {
+        Ok(value.to_string())
+    } else {
+        Ok(format!("\"{}\"", value))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compose::{AttributesCreateInfo, ScoreCreateInfo};
+
+    fn registry() -> GraphRegistry {
+        let mut score =
+            Score::new(ScoreCreateInfo { title: "text", ..Default::default() });
+        let attributes = AttributesCreateInfo {
+            clefs: ["percussion"].into(),
+            ..Default::default()
+        };
+        score
+            .part("Kick", |p| {
+                p.measure(|m| {
+                    m.attributes(&attributes);
+                    m.note_repeat("E4:q", 4);
+                });
+            })
+            .unwrap();
+        let mut registry = GraphRegistry::new();
+        registry.score(score);
+        registry
+    }
+
+    #[test]
+    fn text_round_trip() {
+        let text = "\
+track kick source=kick_drum part=P1
+effect duck Compressor threshold_db=-30 ratio=8 attack=0.002 release=0.15 \
+makeup_db=0
+effect echo Delay delay_samples=4410 feedback=0.4 mix=0.3
+effect verb SimpleReverb feedback=0.8 mix=0.3
+send verb_send amount=0.4
+feedback loop
+bus mix
+output main target=\"output/my song.wav\"
+
+connect kick -> mix
+sidechain kick -> duck
+connect duck -> mix
+connect mix -> verb_send
+connect verb_send -> verb
+connect verb -> loop
+connect loop -> echo
+connect echo -> mix
+connect mix -> main
+";
+        let graph = Graph::parse(text, &mut registry()).unwrap();
+        let written = graph.to_text().unwrap();
+        assert_eq!(written, text);
+
+        let reparsed = Graph::parse(&written, &mut registry()).unwrap();
+        assert_eq!(reparsed.to_text().unwrap(), written);
+    }
+
+    #[test]
+    fn rejects_values_out_of_range() {
+        for line in [
+            "effect e Delay delay_samples=0",
+            "effect e Delay delay_samples=-10",
+            "effect e Delay delay_samples=2.5",
+            "effect e Delay delay_samples=100 feedback=1.5",
+            "effect e SimpleReverb feedback=1",
+            "effect e Pan position=2",
+            "effect e Bitcrusher bits=0",
+            "effect e LowPass cutoff_hz=0",
+            "effect e Compressor ratio=0.5",
+            "effect e Gain amount=nan",
+            "send s amount=-1",
+        ] {
+            let err = Graph::parse(line, &mut GraphRegistry::new())
+                .err()
+                .unwrap_or_else(|| panic!("'{}' should fail", line));
+            assert!(err.starts_with("line 1: "), "{}", err);
+        }
+    }
+}
@@ -1,14 +1,23 @@
 // TODO remove the compose dependencies by making intermediate representation
 // of Part
-use std::collections::HashMap;
+use std::f64::consts::{PI, SQRT_2};
+use std::ops::RangeInclusive;
 
-use super::dsp::signal::{Noise, NoiseType, Oscillator, SignalSource};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+
+use super::dsp::signal::{
+    Additive, Algorithm, Excitation, Fm, Noise, NoiseType, Operator,
+    OperatorFrequency, Oscillator, Partial, Pluck, SignalSource,
+};
 use super::dsp::wave::Wave;
 use super::dsp::{ModulationMatrix, ModulationSource, ModulationTarget};
 use super::effect::EffectChain;
+use super::engine::NoteEvent;
 use super::processor::{AudioBuffer, RenderContext};
 use super::types::{Float, Seconds};
-use crate::compose::{DirectionType, MeasureItem, Part, StartStop};
+use super::voice::{self, Voice, Voicing};
+use crate::compose::{NaturalTone, Part, Pitch};
 use crate::render::wave::WaveShape;
 use crate::render::{ModulationMode, ModulationRoute, ParametricEnvelope};
 
@@ -28,9 +37,164 @@ pub struct InstrumentLayer {
 
     /// Volume of a given layer from 0.0 to 1.0
     pub volume: Float,
+
+    /// Notes the layer plays. Layers without a zone play every note.
+    pub zone: Option<Zone>,
+
+    /// Stacked copies of the layer for each note. Without unison the layer
+    /// plays a single mono copy.
+    pub unison: Option<Unison>,
+}
+
+impl InstrumentLayer {
+    pub fn plays(&self, voice: &Voice) -> bool {
+        self.zone.as_ref().is_none_or(|z| z.matches(voice))
+    }
+
+    /// Whether the layer renders into a stereo buffer
+    pub fn is_stereo(&self) -> bool {
+        self.unison.as_ref().is_some_and(Unison::is_stereo)
+    }
+}
+
+/// Copies of a layer that play every note together, detuned against each
+/// other and spread across the stereo field, ie. for supersaw pads and leads
+#[derive(Clone, Copy, Debug)]
+pub struct Unison {
+    /// Copies played for each note
+    pub voices: usize,
+
+    /// Distance in cents between the lowest and the highest copy. The copies
+    /// are spaced evenly around the played pitch.
+    pub detune: Float,
+
+    /// Stereo width from 0.0, all copies in the center, to 1.0, the lowest
+    /// copy hard left and the highest hard right
+    pub spread: Float,
+
+    /// Start phases of the copies of an oscillator layer
+    pub phase: UnisonPhase,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum UnisonPhase {
+    /// Every copy starts at the phase of the oscillator, so every note has
+    /// the same attack
+    Fixed,
+
+    /// Every copy starts at a random phase, seeded from the voice index so
+    /// renders are repeatable. Avoids the flanging attack of copies that
+    /// start in sync.
+    Random { seed: u64 },
+}
+
+impl Default for Unison {
+    fn default() -> Self {
+        Self { voices: 1, detune: 0.0, spread: 0.0, phase: UnisonPhase::Fixed }
+    }
+}
+
+impl Unison {
+    /// Copies spread across the full stereo field with random phases
+    pub fn new(voices: usize, detune: Float) -> Self {
+        Self {
+            voices,
+            detune,
+            spread: 1.0,
+            phase: UnisonPhase::Random { seed: 0 },
+        }
+    }
+
+    pub fn is_stereo(&self) -> bool {
+        self.voices > 1 && self.spread > 0.0
+    }
+
+    /// Position of a copy from -1.0 (lowest) to 1.0 (highest)
+    fn position(&self, copy: usize) -> Float {
+        match self.voices > 1 {
+            true => 2.0 * copy as Float / (self.voices - 1) as Float - 1.0,
+            false => 0.0,
+        }
+    }
+
+    /// Frequency ratio of a copy to the played pitch
+    pub fn detune_ratio(&self, copy: usize) -> Float {
+        2.0_f64.powf(self.position(copy) * self.detune / 2400.0)
+    }
+
+    /// Left and right gain of a copy. Copies are panned with equal power, and
+    /// scaled so that the copies together are about as loud as one.
+    pub fn gains(&self, copy: usize) -> (Float, Float) {
+        let level = 1.0 / (self.voices.max(1) as Float).sqrt();
+        if !self.is_stereo() {
+            return (level, level);
+        }
+        let pan = self.position(copy) * self.spread.min(1.0);
+        let angle = (pan + 1.0) * PI / 4.0;
+        (SQRT_2 * level * angle.cos(), SQRT_2 * level * angle.sin())
+    }
+
+    /// Start phase of every copy for the voice `voice`, where `phase` is the
+    /// start phase of the oscillator
+    pub fn start_phases(&self, voice: usize, phase: Float) -> Vec<Float> {
+        let copies = self.voices.max(1);
+        match self.phase {
+            UnisonPhase::Fixed => vec![phase; copies],
+            UnisonPhase::Random { seed } => {
+                let mut rng =
+                    StdRng::seed_from_u64(seed.wrapping_add(voice as u64));
+                (0..copies).map(|_| rng.random_range(0.0..1.0)).collect()
+            }
+        }
+    }
+}
+
+/// Key, velocity and round-robin selection of a layer, ie. one sample of a
+/// multi-sampled instrument
+pub struct Zone {
+    /// MIDI keys, middle C is 60. Unpitched notes play regardless of key.
+    pub keys: RangeInclusive<u8>,
+
+    /// MIDI velocities from 0 to 127
+    pub velocities: RangeInclusive<u8>,
+
+    /// The layer plays voice `position`, `position + length` and so on
+    /// out of every `length` voices of the instrument
+    pub round_robin: Option<RoundRobin>,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct RoundRobin {
+    pub length: usize,
+    pub position: usize,
+}
+
+impl Zone {
+    pub fn matches(&self, voice: &Voice) -> bool {
+        let key = voice.event.freq.map(freq_to_key);
+        let velocity = (voice.event.velocity * 127.0).round() as u8;
+        key.is_none_or(|k| self.keys.contains(&k))
+            && self.velocities.contains(&velocity)
+            && self
+                .round_robin
+                .is_none_or(|rr| voice.index % rr.length == rr.position)
+    }
+}
+
+/// Nearest MIDI key of a frequency
+pub fn freq_to_key(freq: Float) -> u8 {
+    (69.0 + 12.0 * (freq / 440.0).log2()).round().clamp(0.0, 127.0) as u8
+}
+
+/// Frequency of a MIDI key in equal temperament
+pub fn key_to_freq(key: u8) -> Float {
+    440.0 * 2.0_f64.powf((key as Float - 69.0) / 12.0)
 }
 
 pub struct Instrument {
+    /// Identifies the instrument when a graph is saved as text, ie. the name
+    /// of the preset it was built from
+    pub name: String,
     pub layers: Vec<InstrumentLayer>,
 
     /// Global level modulations apply to all instrument layers
@@ -40,9 +204,18 @@ pub struct Instrument {
     pub fx: Option<EffectChain>,
 
     pub is_unpitched: bool,
+
+    /// How notes are assigned to voices
+    pub voicing: Voicing,
 }
 
 impl Instrument {
+    /// Whether voices render in stereo, which is the case as soon as one
+    /// layer spreads its unison copies
+    pub fn is_stereo(&self) -> bool {
+        self.layers.iter().any(InstrumentLayer::is_stereo)
+    }
+
     pub fn max_release_time(&self) -> Float {
         self.layers
             .iter()
@@ -52,99 +225,211 @@ impl Instrument {
                 ModulationSource::Envelope(env) => Some(env.release_time()),
                 _ => None,
             })
+            .chain(self.layers.iter().map(|l| l.signal.release_time()))
             .fold(0.0, |a, b| a.max(b))
     }
 
-    fn process_note_events(
-        &mut self,
-        ctx: &RenderContext,
-        note_events: Vec<NoteEvent>,
-        buf: &mut AudioBuffer,
-    ) {
-        let sr = ctx.sample_rate;
+    /// Tail of the global effects
+    pub fn tail_time(&self, sample_rate: u32) -> Seconds {
+        self.fx.as_ref().map(|fx| fx.tail_time(sample_rate)).unwrap_or(0.0)
+    }
 
-        for event in note_events {
-            let dur = (event.end - event.start) + self.max_release_time();
-            let n_samples = (dur * sr as Float).round() as usize;
-            let start_sample = (event.start * sr as Float).round() as usize;
-
-            // Gate ON for global mod envelopes
-            //if let Some(mods) = &mut self.mods {
-            //    mods.gate_on(event.start);
-            //}
-
-            // Each layer contributes to the note
-            for layer in &mut self.layers {
-                let mut layer_buf = AudioBuffer::Mono(vec![]);
-                layer_buf.resize(n_samples);
-
-                // Gate ON for local mod envelopes
-                if let Some(mods) = &mut layer.mods {
-                    //mods.gate_on(event.start);
-                    mods.gate_on(0.0);
-                }
+    /// Plan the voices of events sorted by onset
+    pub fn allocate_voices(&self, events: &[NoteEvent]) -> Vec<Voice> {
+        voice::allocate(
+            &self.voicing,
+            events,
+            self.max_release_time(),
+            |voice, t| self.voice_level(voice, t),
+        )
+    }
+
+    /// Estimated amplitude of a voice `t` seconds after it started, the
+    /// loudest layer envelope scaled by the velocity
+    pub fn voice_level(&self, voice: &Voice, t: Seconds) -> Float {
+        let gate = voice.gate();
+        let velocity = voice.event.velocity;
+        let level = self
+            .layers
+            .iter()
+            .filter(|layer| layer.plays(voice))
+            .map(|layer| {
+                let gate = layer.signal.gate(gate);
+                let amp = layer
+                    .mods
+                    .as_ref()
+                    .map(|m| {
+                        m.apply_gated(
+                            ModulationTarget::Amplitude,
+                            velocity,
+                            t,
+                            gate,
+                        )
+                    })
+                    .unwrap_or(velocity);
+                amp.abs() * layer.volume
+            })
+            .fold(0.0, Float::max);
+        level * voice.fade_at(t)
+    }
+
+    /// Number of samples a voice lasts including the release
+    pub fn voice_length(&self, voice: &Voice, sample_rate: u32) -> usize {
+        let dur = voice.length(self.max_release_time());
+        (dur * sample_rate as Float).round() as usize
+    }
+
+    /// Render a single voice with all layers and their layer effects, but
+    /// without the global effects. The voice starts at its first note and is
+    /// mono, unless the instrument `is_stereo`. Voices have their own gate,
+    /// pitch and noise, and layer effects are reset for each voice, so the
+    /// result does not depend on the other voices.
+    pub fn render_voice(
+        &mut self,
+        voice: &Voice,
+        sample_rate: u32,
+    ) -> AudioBuffer {
+        let sr = sample_rate;
+        let n_samples = self.voice_length(voice, sr);
+        let gate = voice.gate();
+        let glide = self.voicing.glide;
+        let velocity = voice.event.velocity;
+        let mut out = match self.is_stereo() {
+            true => AudioBuffer::Stereo(vec![(0.0, 0.0); n_samples]),
+            false => AudioBuffer::Mono(vec![0.0; n_samples]),
+        };
+
+        // Each layer contributes to the note
+        for layer in self.layers.iter_mut().filter(|l| l.plays(voice)) {
+            let gate = layer.signal.gate(gate);
+            let unison = layer.unison.unwrap_or_default();
+            let mut layer_buf = match unison.is_stereo() {
+                true => AudioBuffer::Stereo(vec![(0.0, 0.0); n_samples]),
+                false => AudioBuffer::Mono(vec![0.0; n_samples]),
+            };
+
+            let osc_phase = match &layer.signal {
+                SignalSource::Oscillator(osc) => osc.phase,
+                _ => 0.0,
+            };
+            let start_phases = unison.start_phases(voice.index, osc_phase);
+
+            // Every unison copy is a complete rendering of the layer at its
+            // own pitch, phase and position
+            for (copy, start_phase) in start_phases.into_iter().enumerate() {
+                let detune = unison.detune_ratio(copy);
+                let (gain_l, gain_r) = unison.gains(copy);
+
+                // Noise and strings keep their own state for each voice
+                let voice_signal = layer.signal.for_voice(voice, copy, sr);
+                let signal = voice_signal.as_ref().unwrap_or(&layer.signal);
+                let mods =
+                    layer.mods.as_ref().map(|m| m.for_voice(voice, copy, sr));
+
+                // Cycles of the base pitch played so far. Sampling at the
+                // time a constant pitch reaches the same phase keeps glides
+                // continuous.
+                let mut cycles = 0.0;
+
+                // Oscillators run on their own phase, advanced by the
+                // modulated pitch, so pitch modulation doesn't make the wave
+                // jump
+                let mut phase = start_phase;
 
                 for i in 0..n_samples {
                     let t = i as Float / sr as Float;
 
-                    // Gate OFF at note end
-                    if t >= (event.end - event.start) {
-                        if let Some(mods) = &mut layer.mods {
-                            mods.gate_off(t);
-                        }
-                    }
+                    // Unpitched instruments play the base frequency of the
+                    // layer
+                    let base = voice
+                        .freq_at(t, glide)
+                        .or(layer.base_freq)
+                        .unwrap_or(0.0);
+                    let phase_t = if base > 0.0 { cycles / base } else { t };
+                    cycles += base / sr as Float;
 
                     // Get modulated pitch/amplitude
-                    if let Some(f) = event.freq {
-                        // Pitched instrument
-                        let pitch = layer
-                            .mods
-                            .as_ref()
-                            .map(|m| m.apply(ModulationTarget::Pitch, f, t))
-                            .unwrap_or(f);
-                        layer.signal.set_frequency(pitch);
-                    } else {
-                        // Unpitched instrument
-                        if let Some(f) = layer.base_freq {
-                            let pitch = layer
-                                .mods
-                                .as_ref()
-                                .map(|m| m.apply(ModulationTarget::Pitch, f, t))
-                                .unwrap_or(f);
-                            layer.signal.set_frequency(pitch);
-                        } else {
-                            layer.signal.set_frequency(0.0);
-                        }
-                    }
-
-                    let amp = layer
-                        .mods
-                        .as_ref()
-                        .map(|m| {
-                            m.apply(
+                    let (pitch, amp) = match &mods {
+                        Some(m) => (
+                            m.apply_gated(
+                                ModulationTarget::Pitch,
+                                base,
+                                t,
+                                gate,
+                            ),
+                            m.apply_gated(
                                 ModulationTarget::Amplitude,
-                                event.velocity,
+                                velocity,
                                 t,
-                            )
-                        })
-                        .unwrap_or(event.velocity);
-
-                    let sample = layer.signal.sample(t) * amp * layer.volume;
-                    layer_buf.set(i, sample);
-                }
+                                gate,
+                            ),
+                        ),
+                        None => (base, velocity),
+                    };
+                    let pitch = pitch * detune;
+
+                    if let (SignalSource::Fm(fm), Some(m)) = (signal, &mods) {
+                        fm.modulate_levels(|op, level| {
+                            let target = ModulationTarget::OperatorLevel(op);
+                            m.apply_gated(target, level, t, gate)
+                        });
+                    }
 
-                // Apply layer effects
-                if let Some(fx) = &mut layer.fx {
-                    fx.process(&mut layer_buf, sr);
+                    let increment = pitch / sr as Float;
+                    let sample = match signal {
+                        SignalSource::Oscillator(osc) => {
+                            osc.wave.sample_phase(phase, increment)
+                        }
+                        SignalSource::Sampler(s) => {
+                            s.sample_gated(pitch, phase_t, &gate)
+                        }
+                        _ => signal.sample_at(pitch, phase_t),
+                    };
+                    phase = (phase + increment).rem_euclid(1.0);
+
+                    let value = sample * amp * layer.volume * voice.fade_at(t);
+                    match &mut layer_buf {
+                        AudioBuffer::Mono(b) => b[i] += value * gain_l,
+                        AudioBuffer::Stereo(b) => {
+                            b[i].0 += value * gain_l;
+                            b[i].1 += value * gain_r;
+                        }
+                    }
                 }
+            }
 
-                // Mix into final buffer
-                buf.add_offset(&layer_buf, start_sample);
+            // Apply layer effects
+            if let Some(fx) = &mut layer.fx {
+                fx.reset();
+                fx.process(&mut layer_buf, sr);
             }
+
+            out.add(&layer_buf);
         }
+        out
+    }
 
-        // Apply global FX if present
+    fn process_note_events(
+        &mut self,
+        ctx: &RenderContext,
+        mut note_events: Vec<NoteEvent>,
+        buf: &mut AudioBuffer,
+    ) {
+        let sr = ctx.sample_rate;
+        if self.is_stereo() {
+            *buf = buf.to_stereo();
+        }
+
+        note_events.sort_by(|a, b| a.start.total_cmp(&b.start));
+        for voice in self.allocate_voices(&note_events) {
+            let rendered = self.render_voice(&voice, sr);
+            buf.add_offset(&rendered, voice.event.start_sample(sr));
+        }
+
+        // Apply global FX if present, with room for their tail
         if let Some(global_fx) = &mut self.fx {
+            let tail = global_fx.tail_time(sr) * sr as Float;
+            buf.resize(buf.len() + tail.ceil() as usize);
             global_fx.process(buf, sr);
         }
     }
@@ -154,158 +439,136 @@ impl Instrument {
         part: &Part,
         ctx: &RenderContext,
     ) -> AudioBuffer {
-        let mut state = RenderState::default();
-
-        // MusicXML part will collect note events during parsing
-        let mut note_events: Vec<NoteEvent> = vec![];
-
-        // Flatten measures
-        // TODO delegate this to a part fn
-        let items: Vec<&MeasureItem> =
-            part.measures.iter().flat_map(|m| m.items.iter()).collect();
-
-        for item in &items {
-            match item {
-                MeasureItem::Note(note) => {
-                    if !note.is_chord {
-                        state.save_cursor();
-                    }
-
-                    let note_duration = state.ticks_to_secs(note.duration);
-
-                    if let Some(pitch) = &note.pitch {
-                        let mut event = NoteEvent {
-                            velocity: state.velocity,
-                            start: state.saved_cursor,
-                            end: state.saved_cursor + note_duration,
-                            freq: Some(pitch.to_frequency()),
-                        };
-
-                        match note.tie {
-                            Some(StartStop::Start) => {
-                                state.ongoing_ties.insert(
-                                    pitch.to_semitone(),
-                                    (state.saved_cursor, note_duration),
-                                );
-
-                                // do not push to event buffer, this is a tie
-                                // so the event is not over yet
-                                continue;
-                            }
-                            Some(StartStop::Stop) => {
-                                if let Some((prev_start, prev_duration)) = state
-                                    .ongoing_ties
-                                    .remove(&pitch.to_semitone())
-                                {
-                                    // Change note event timing based on tie
-                                    event.start = prev_start;
-                                    event.end = prev_start
-                                        + prev_duration
-                                        + note_duration;
-                                }
-                            }
-                            None => {}
-                        }
-                        note_events.push(event);
-                    } else if let Some(_) = &note.unpitched {
-                        note_events.push(NoteEvent {
-                            velocity: state.velocity,
-                            start: state.saved_cursor,
-                            end: state.saved_cursor + note_duration,
-                            freq: None,
-                        });
-                    }
-
-                    if !note.is_chord {
-                        state.cursor += note_duration;
-                    }
-                }
-
-                MeasureItem::Direction(dir) => match &dir.kind {
-                    DirectionType::Metronome { per_minute, .. } => {
-                        state.tempo_bpm = *per_minute as Float;
-                    }
-                    DirectionType::Dynamics(dynamics) => {
-                        state.velocity = dynamics.normalized_velocity();
-                    }
-                    _ => {}
-                },
-
-                MeasureItem::Forward(fwd) => {
-                    state.cursor += state.ticks_to_secs(fwd.duration);
-                }
-
-                MeasureItem::Backup(bak) => {
-                    state.cursor -= state.ticks_to_secs(bak.duration);
-                }
-
-                MeasureItem::Barline(_) => {}
-            }
-        }
-
         let mut buf = AudioBuffer::Mono(vec![]);
         buf.resize(
             (part.nominal_duration_seconds() * ctx.sample_rate as f64) as usize,
         );
+        self.process_note_events(ctx, part.collect_events(), &mut buf);
+        buf
+    }
+
+    /// Render events that were collected from a part and possibly transformed
+    /// afterwards (ie. humanized). The buffer ends with the last release.
+    pub fn render_events(
+        &mut self,
+        note_events: Vec<NoteEvent>,
+        ctx: &RenderContext,
+    ) -> AudioBuffer {
+        let mut buf = AudioBuffer::Mono(vec![]);
         self.process_note_events(ctx, note_events, &mut buf);
         buf
     }
 }
 
-/// Mutable state used during the rendering process
-pub struct RenderState {
-    pub cursor: Seconds,
-    pub saved_cursor: Seconds,
-    pub tempo_bpm: Float,
-    pub velocity: Float,
-    pub divisions: u32,
-    pub active_voices: Vec<NoteEvent>,
-    // Pitch => (start_time_beats, total_duration_beats)
-    pub ongoing_ties: HashMap<u8, (Float, Float)>,
+/// Identifies which notes of a percussion part a drum kit piece plays
+pub enum DrumKey {
+    /// Staff position of an unpitched note, ie. F4 for a kick drum
+    Display { step: NaturalTone, octave: i8 },
+
+    /// MusicXML instrument id of the note, ie. "P1-I36"
+    InstrumentId(String),
 }
 
-impl Default for RenderState {
-    fn default() -> Self {
-        Self {
-            cursor: 0.0,
-            saved_cursor: 0.0,
-            tempo_bpm: 120.0,
-            velocity: 80.0 / 127.0, // mf
-            divisions: 480,
-            active_voices: vec![],
-            ongoing_ties: HashMap::new(),
+impl DrumKey {
+    /// Key from a staff position written like a pitch ("F4", "C5")
+    pub fn display(position: &str) -> Result<Self, String> {
+        let pitch: Pitch = position.parse().map_err(|e| {
+            format!("Invalid drum key position '{}': {}", position, e)
+        })?;
+        Ok(Self::Display { step: pitch.step, octave: pitch.octave })
+    }
+
+    pub fn matches(&self, event: &NoteEvent) -> bool {
+        match self {
+            Self::Display { step, octave } => {
+                event.unpitched.as_ref().is_some_and(|u| {
+                    u.display_step == *step && u.display_octave == *octave
+                })
+            }
+            Self::InstrumentId(id) => event.instrument.as_ref() == Some(id),
         }
     }
 }
 
-impl RenderState {
-    pub fn seconds_per_beat(&self) -> Float {
-        60.0 / self.tempo_bpm
+pub struct DrumPiece {
+    pub key: DrumKey,
+    pub instrument: Instrument,
+}
+
+/// A set of instruments played from a single percussion part. Each note is
+/// rendered by the first piece whose key matches it, notes without a matching
+/// piece are silent.
+pub struct DrumKit {
+    pub name: String,
+    pub pieces: Vec<DrumPiece>,
+}
+
+impl DrumKit {
+    /// Index of the piece that plays an event
+    pub fn piece_index(&self, event: &NoteEvent) -> Option<usize> {
+        self.pieces.iter().position(|p| p.key.matches(event))
     }
 
-    /// Convert a duration in ticks to a duration in samples
-    pub fn ticks_to_samples(&self, duration: u32, sample_rate: u32) -> usize {
-        let dur_beats = duration as Float / self.divisions as Float;
-        let dur_sec = dur_beats * self.seconds_per_beat();
-        (dur_sec * sample_rate as Float).ceil() as usize
+    pub fn is_stereo(&self) -> bool {
+        self.pieces.iter().any(|p| p.instrument.is_stereo())
     }
 
-    /// Convert a duration in ticks to a duration in samples
-    pub fn ticks_to_secs(&self, duration: u32) -> Float {
-        let dur_beats = duration as Float / self.divisions as Float;
-        dur_beats * self.seconds_per_beat()
+    pub fn max_release_time(&self) -> Float {
+        self.pieces
+            .iter()
+            .map(|p| p.instrument.max_release_time())
+            .fold(0.0, Float::max)
     }
 
-    pub fn save_cursor(&mut self) {
-        self.saved_cursor = self.cursor;
+    pub fn render_part(
+        &mut self,
+        part: &Part,
+        ctx: &RenderContext,
+    ) -> AudioBuffer {
+        let mut buf = AudioBuffer::Mono(vec![]);
+        buf.resize(
+            (part.nominal_duration_seconds() * ctx.sample_rate as f64) as usize,
+        );
+        self.process_note_events(ctx, part.collect_events(), &mut buf);
+        buf
     }
-}
 
-pub struct NoteEvent {
-    pub freq: Option<Float>,
-    pub velocity: Float,
-    pub start: Seconds,
-    pub end: Seconds,
+    pub fn render_events(
+        &mut self,
+        note_events: Vec<NoteEvent>,
+        ctx: &RenderContext,
+    ) -> AudioBuffer {
+        let mut buf = AudioBuffer::Mono(vec![]);
+        self.process_note_events(ctx, note_events, &mut buf);
+        buf
+    }
+
+    /// Each piece renders its own notes with its own effects before the
+    /// pieces are mixed together
+    fn process_note_events(
+        &mut self,
+        ctx: &RenderContext,
+        note_events: Vec<NoteEvent>,
+        buf: &mut AudioBuffer,
+    ) {
+        let mut piece_events: Vec<Vec<NoteEvent>> =
+            self.pieces.iter().map(|_| vec![]).collect();
+        for event in note_events {
+            if let Some(index) = self.piece_index(&event) {
+                piece_events[index].push(event);
+            }
+        }
+        if self.is_stereo() {
+            *buf = buf.to_stereo();
+        }
+
+        for (piece, events) in self.pieces.iter_mut().zip(piece_events) {
+            let mut piece_buf = AudioBuffer::Mono(vec![]);
+            piece_buf.resize(buf.len());
+            piece.instrument.process_note_events(ctx, events, &mut piece_buf);
+            buf.add(&piece_buf);
+        }
+    }
 }
 
 // CONCRETE INSTRUMENTS
@@ -313,6 +576,8 @@ pub struct NoteEvent {
 // Inspired by https://www.youtube.com/watch?v=ndG-6-vONNc
 pub fn kick_drum() -> Instrument {
     Instrument {
+        name: "kick_drum".to_string(),
+        voicing: Voicing::default(),
         is_unpitched: true,
         layers: vec![
             InstrumentLayer {
@@ -325,6 +590,8 @@ pub fn kick_drum() -> Instrument {
                     phase: 0.0,
                 }),
                 volume: 0.7,
+                zone: None,
+                unison: None,
                 base_freq: Some(80.0),
                 mods: Some(ModulationMatrix {
                     routes: vec![
@@ -353,12 +620,12 @@ pub fn kick_drum() -> Instrument {
                         },
                         // Click from beater
                         ModulationRoute {
-                            source: ModulationSource::Signal(
+                            source: ModulationSource::Signal(Box::new(
                                 SignalSource::Noise(Noise::new(
                                     NoiseType::White,
                                     1337,
                                 )),
-                            ),
+                            )),
                             target: ModulationTarget::Pitch,
                             mode: ModulationMode::Multiply,
                             depth: 0.02,
@@ -384,6 +651,8 @@ pub fn kick_drum() -> Instrument {
                 }),
                 base_freq: Some(40.0),
                 volume: 0.3,
+                zone: None,
+                unison: None,
                 mods: Some(ModulationMatrix {
                     routes: vec![ModulationRoute {
                         source: ModulationSource::Envelope(
@@ -411,6 +680,8 @@ pub fn kick_drum() -> Instrument {
                 }),
                 base_freq: Some(120.0),
                 volume: 0.025,
+                zone: None,
+                unison: None,
                 mods: Some(ModulationMatrix {
                     routes: vec![
                         ModulationRoute {
@@ -425,12 +696,12 @@ pub fn kick_drum() -> Instrument {
                             depth_mod: None,
                         },
                         ModulationRoute {
-                            source: ModulationSource::Signal(
+                            source: ModulationSource::Signal(Box::new(
                                 SignalSource::Noise(Noise::new(
                                     NoiseType::Brown,
                                     1337,
                                 )),
-                            ),
+                            )),
                             target: ModulationTarget::Pitch,
                             mode: ModulationMode::Multiply,
                             depth: 0.2,
@@ -452,10 +723,14 @@ pub fn kick_drum() -> Instrument {
 
 pub fn snare_drum() -> Instrument {
     Instrument {
+        name: "snare_drum".to_string(),
+        voicing: Voicing::default(),
         is_unpitched: true,
         layers: vec![
             InstrumentLayer {
                 volume: 0.75,
+                zone: None,
+                unison: None,
                 signal: SignalSource::Oscillator(Oscillator {
                     wave: Wave {
                         source: Box::new(WaveShape::Sine),
@@ -480,6 +755,8 @@ pub fn snare_drum() -> Instrument {
             },
             InstrumentLayer {
                 volume: 0.25,
+                zone: None,
+                unison: None,
                 signal: SignalSource::Noise(Noise::new(NoiseType::White, 42)),
                 base_freq: None,
                 mods: Some(ModulationMatrix {
@@ -503,11 +780,15 @@ pub fn snare_drum() -> Instrument {
 
 pub fn hihat() -> Instrument {
     Instrument {
+        name: "hihat".to_string(),
+        voicing: Voicing::default(),
         is_unpitched: true,
         layers: vec![InstrumentLayer {
             signal: SignalSource::Noise(Noise::new(NoiseType::White, 1337)),
             base_freq: None,
             volume: 1.0,
+            zone: None,
+            unison: None,
             mods: Some(ModulationMatrix {
                 routes: vec![ModulationRoute {
                     source: ModulationSource::Envelope(
@@ -525,3 +806,208 @@ pub fn hihat() -> Instrument {
         fx: None,
     }
 }
+
+/// Plucked string bass, ie. for parts written for an electric bass played
+/// with a pick. The string is damped when the note ends.
+pub fn picked_bass() -> Instrument {
+    let mut pluck = Pluck::new(Excitation::Noise);
+    pluck.brightness = 0.3;
+    pluck.stretch = 1.5;
+    pluck.pick_position = 0.2;
+
+    Instrument {
+        name: "picked_bass".to_string(),
+        voicing: Voicing::default(),
+        is_unpitched: false,
+        layers: vec![InstrumentLayer {
+            signal: SignalSource::Pluck(pluck),
+            base_freq: None,
+            volume: 0.8,
+            zone: None,
+            unison: None,
+            mods: Some(ModulationMatrix {
+                routes: vec![ModulationRoute {
+                    source: ModulationSource::Envelope(
+                        ParametricEnvelope::from_ahdsr(
+                            0.0, 0.0, 0.0, 1.0, 0.06, 1.0,
+                        ),
+                    ),
+                    target: ModulationTarget::Amplitude,
+                    mode: ModulationMode::Scale,
+                    depth: 1.0,
+                    depth_mod: None,
+                }],
+            }),
+            fx: None,
+        }],
+        mods: None,
+        fx: None,
+    }
+}
+
+/// Four operator FM electric piano. A body pair and a tine pair, where the
+/// high ratio of the tine modulator gives the bell-like attack.
+pub fn electric_piano() -> Instrument {
+    let operator = |ratio: Float, level: Float, decay: Seconds| Operator {
+        envelope: Some(ParametricEnvelope::from_ahdsr(
+            0.002, 0.0, decay, 0.0, 0.3, 3.0,
+        )),
+        ..Operator::new(OperatorFrequency::Ratio(ratio), level)
+    };
+    let fm = Fm::new(
+        vec![
+            // Body
+            operator(1.0, 0.5, 3.0),
+            operator(1.0, 1.2, 1.5),
+            // Tine
+            operator(1.0, 0.25, 0.8),
+            operator(14.0, 1.5, 0.08),
+        ],
+        Algorithm::pairs(4),
+    );
+
+    Instrument {
+        name: "electric_piano".to_string(),
+        voicing: Voicing::default(),
+        is_unpitched: false,
+        layers: vec![InstrumentLayer {
+            signal: SignalSource::Fm(fm),
+            base_freq: None,
+            volume: 0.8,
+            zone: None,
+            unison: None,
+            mods: None,
+            fx: None,
+        }],
+        mods: None,
+        fx: None,
+    }
+}
+
+/// Tonewheel organ with the first three drawbars (16', 5 1/3' and 8') pulled
+/// out
+pub fn organ() -> Instrument {
+    // Drawbar ratios to the 8' fundamental and their settings from 0 to 8
+    let drawbars = [
+        (0.5, 8.0),
+        (1.5, 8.0),
+        (1.0, 8.0),
+        (2.0, 0.0),
+        (3.0, 0.0),
+        (4.0, 0.0),
+        (5.0, 0.0),
+        (6.0, 0.0),
+        (8.0, 0.0),
+    ];
+    let partials = drawbars
+        .iter()
+        .filter(|(_, setting)| *setting > 0.0)
+        .map(|&(ratio, setting)| Partial::new(ratio, 0.15 * setting / 8.0))
+        .collect();
+
+    Instrument {
+        name: "organ".to_string(),
+        voicing: Voicing::default(),
+        is_unpitched: false,
+        layers: vec![InstrumentLayer {
+            signal: SignalSource::Additive(Additive::new(partials)),
+            base_freq: None,
+            volume: 1.0,
+            zone: None,
+            unison: None,
+            mods: Some(ModulationMatrix {
+                routes: vec![ModulationRoute {
+                    source: ModulationSource::Envelope(
+                        ParametricEnvelope::from_ahdsr(
+                            0.005, 0.0, 0.0, 1.0, 0.05, 1.0,
+                        ),
+                    ),
+                    target: ModulationTarget::Amplitude,
+                    mode: ModulationMode::Scale,
+                    depth: 1.0,
+                    depth_mod: None,
+                }],
+            }),
+            fx: None,
+        }],
+        mods: None,
+        fx: None,
+    }
+}
+
+/// Seven detuned saws spread across the stereo field, for pads and leads
+pub fn supersaw() -> Instrument {
+    Instrument {
+        name: "supersaw".to_string(),
+        voicing: Voicing::default(),
+        is_unpitched: false,
+        layers: vec![InstrumentLayer {
+            signal: SignalSource::Oscillator(Oscillator {
+                wave: Wave {
+                    source: Box::new(WaveShape::Saw(0.5)),
+                    modifiers: None,
+                },
+                freq: 440.0,
+                phase: 0.0,
+            }),
+            base_freq: None,
+            volume: 0.5,
+            zone: None,
+            unison: Some(Unison::new(7, 40.0)),
+            mods: Some(ModulationMatrix {
+                routes: vec![ModulationRoute {
+                    source: ModulationSource::Envelope(
+                        ParametricEnvelope::from_ahdsr(
+                            0.01, 0.0, 0.0, 1.0, 0.3, 1.0,
+                        ),
+                    ),
+                    target: ModulationTarget::Amplitude,
+                    mode: ModulationMode::Scale,
+                    depth: 1.0,
+                    depth_mod: None,
+                }],
+            }),
+            fx: None,
+        }],
+        mods: None,
+        fx: None,
+    }
+}
+
+/// Synthesized kit using the common drum set staff positions: kick on F4,
+/// snare on C5 and hi-hat on G5
+pub fn drum_kit() -> DrumKit {
+    DrumKit {
+        name: "drum_kit".to_string(),
+        pieces: vec![
+            DrumPiece {
+                key: DrumKey::Display { step: NaturalTone::F, octave: 4 },
+                instrument: kick_drum(),
+            },
+            DrumPiece {
+                key: DrumKey::Display { step: NaturalTone::C, octave: 5 },
+                instrument: snare_drum(),
+            },
+            DrumPiece {
+                key: DrumKey::Display { step: NaturalTone::G, octave: 5 },
+                instrument: hihat(),
+            },
+        ],
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn drum_key_from_position() {
+        let key = DrumKey::display("C5").unwrap();
+        assert!(matches!(
+            key,
+            DrumKey::Display { step: NaturalTone::C, octave: 5 }
+        ));
+        assert!(DrumKey::display("X").is_err());
+        assert!(DrumKey::display("H4").is_err());
+    }
+}
@@ -1,12 +1,24 @@
+pub mod automation;
 pub mod dsp;
 pub mod effect;
 pub mod engine;
+pub mod golden;
+pub mod graph_text;
 pub mod instrument;
+mod parallel;
+pub mod pitch;
 pub mod processor;
+pub mod sf2;
+mod sfz;
 mod types;
+pub mod voice;
 mod wav;
 
+pub use automation::*;
 pub use dsp::*;
 pub use effect::*;
 pub use instrument::*;
+pub use pitch::*;
 pub use processor::*;
+pub use sf2::*;
+pub use voice::*;
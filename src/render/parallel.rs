@@ -0,0 +1,55 @@
+use std::thread;
+
+/// Apply `f` to every item on scoped worker threads, at most `threads` or one
+/// per CPU core if None. 1 runs everything on the calling thread. Results are
+/// returned in the order of the items. Each item is processed by exactly one
+/// thread, so work on an item is sequential and the result is the same as a
+/// single-threaded map.
+pub(crate) fn map<T, R, F>(
+    items: Vec<T>,
+    threads: Option<usize>,
+    f: F,
+) -> Vec<R>
+where
+    T: Send,
+    R: Send,
+    F: Fn(T) -> R + Sync,
+{
+    let workers = threads
+        .or_else(|| thread::available_parallelism().ok().map(|n| n.get()))
+        .unwrap_or(1)
+        .min(items.len());
+    if workers <= 1 {
+        return items.into_iter().map(f).collect();
+    }
+
+    // Deal the items out round robin, remembering their position
+    let mut groups: Vec<Vec<(usize, T)>> =
+        (0..workers).map(|_| vec![]).collect();
+    for (i, item) in items.into_iter().enumerate() {
+        groups[i % workers].push((i, item));
+    }
+
+    let f = &f;
+    let mut results: Vec<(usize, R)> = thread::scope(|s| {
+        let handles: Vec<_> = groups
+            .into_iter()
+            .map(|group| {
+                s.spawn(move || {
+                    group
+                        .into_iter()
+                        .map(|(i, item)| (i, f(item)))
+                        .collect::<Vec<_>>()
+                })
+            })
+            .collect();
+
+        handles
+            .into_iter()
+            .flat_map(|h| h.join().expect("Render thread panicked"))
+            .collect()
+    });
+
+    results.sort_by_key(|(i, _)| *i);
+    results.into_iter().map(|(_, r)| r).collect()
+}
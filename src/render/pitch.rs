@@ -0,0 +1,149 @@
+//! Pitch curves of single notes: glides to the next note, bends and vibrato.
+//! A curve is a deviation in cents from the notated pitch over the time of
+//! the note. Voices apply it to their base pitch before the `Pitch` routes
+//! of the layer modulation, so LFOs and envelopes act on top of it and the
+//! oscillator phase stays continuous.
+
+use std::f64::consts::PI;
+
+use super::types::{Float, Hz, Seconds};
+
+#[derive(Clone, Debug, Default)]
+pub struct PitchCurve {
+    /// Moves the note towards the pitch of the next one
+    pub glide: Option<PitchGlide>,
+
+    /// Breakpoints of (time, cents) relative to the note start. The bend
+    /// rises from zero at the note start to the first point, is linear
+    /// between points and holds the last value after them.
+    pub bend: Vec<(Seconds, Float)>,
+    pub vibrato: Option<Vibrato>,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct PitchGlide {
+    pub to: Hz,
+
+    /// Glide start and end relative to the note start
+    pub start: Seconds,
+    pub end: Seconds,
+
+    /// Move in semitone steps like a glissando on a keyboard or harp, rather
+    /// than a continuous slide
+    pub stepped: bool,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Vibrato {
+    pub rate: Hz,
+
+    /// Peak deviation in cents
+    pub depth: Float,
+
+    /// Time after the note start before the vibrato sets in
+    pub delay: Seconds,
+
+    /// Time the depth takes to grow to its full value after the delay
+    pub fade_in: Seconds,
+}
+
+impl Default for Vibrato {
+    fn default() -> Self {
+        Self { rate: 5.5, depth: 30.0, delay: 0.15, fade_in: 0.25 }
+    }
+}
+
+impl PitchCurve {
+    pub fn is_flat(&self) -> bool {
+        self.glide.is_none() && self.bend.is_empty() && self.vibrato.is_none()
+    }
+
+    /// Deviation from the notated pitch `freq` at `t` seconds into the note
+    pub fn cents_at(&self, freq: Hz, t: Seconds) -> Float {
+        let mut cents = 0.0;
+        if let Some(glide) = &self.glide {
+            cents += glide.cents_at(freq, t);
+        }
+        cents += bend_at(&self.bend, t);
+        if let Some(vibrato) = &self.vibrato {
+            cents += vibrato.cents_at(t);
+        }
+        cents
+    }
+
+    /// Move the curve later by `offset` seconds, ie. when the note is tied to
+    /// an earlier one
+    pub fn delay(&mut self, offset: Seconds) {
+        if let Some(glide) = &mut self.glide {
+            glide.start += offset;
+            glide.end += offset;
+        }
+        if !self.bend.is_empty() && self.bend[0].0 > 0.0 {
+            self.bend.insert(0, (0.0, 0.0));
+        }
+        for point in &mut self.bend {
+            point.0 += offset;
+        }
+        if let Some(vibrato) = &mut self.vibrato {
+            vibrato.delay += offset;
+        }
+    }
+
+    /// Frequency ratio to the notated pitch `freq`
+    pub fn ratio_at(&self, freq: Hz, t: Seconds) -> Float {
+        if self.is_flat() {
+            return 1.0;
+        }
+        2.0_f64.powf(self.cents_at(freq, t) / 1200.0)
+    }
+}
+
+impl PitchGlide {
+    fn cents_at(&self, from: Hz, t: Seconds) -> Float {
+        if from <= 0.0 || self.to <= 0.0 || t <= self.start {
+            return 0.0;
+        }
+        let total = 1200.0 * (self.to / from).log2();
+        let length = self.end - self.start;
+        let progress = match length > 0.0 {
+            true => ((t - self.start) / length).min(1.0),
+            false => 1.0,
+        };
+
+        // Steps land on every semitone between the notes
+        match self.stepped {
+            true => ((total * progress) / 100.0).trunc() * 100.0,
+            false => total * progress,
+        }
+    }
+}
+
+impl Vibrato {
+    fn cents_at(&self, t: Seconds) -> Float {
+        let t = t - self.delay;
+        if t <= 0.0 {
+            return 0.0;
+        }
+        let depth = match self.fade_in > 0.0 {
+            true => self.depth * (t / self.fade_in).min(1.0),
+            false => self.depth,
+        };
+        depth * (2.0 * PI * self.rate * t).sin()
+    }
+}
+
+fn bend_at(points: &[(Seconds, Float)], t: Seconds) -> Float {
+    let Some(&(first_t, first)) = points.first() else {
+        return 0.0;
+    };
+    if t <= first_t {
+        return if first_t <= 0.0 { first } else { first * t / first_t };
+    }
+    for pair in points.windows(2) {
+        let ((t0, c0), (t1, c1)) = (pair[0], pair[1]);
+        if t < t1 {
+            return c0 + (c1 - c0) * (t - t0) / (t1 - t0);
+        }
+    }
+    points.last().unwrap().1
+}
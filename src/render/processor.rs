@@ -1,5 +1,10 @@
+use std::collections::HashSet;
+use std::path::Path;
+
+use super::effect::SILENCE_THRESHOLD;
 use super::instrument::Instrument;
-use super::types::{Float, MonoBuffer, StereoBuffer};
+use super::parallel;
+use super::types::{Float, MonoBuffer, Seconds, StereoBuffer};
 use super::wav::save_to_wav;
 use crate::render::EffectChain;
 
@@ -73,6 +78,12 @@ impl AudioBuffer {
     }
 
     pub fn trim_end(&mut self, threshold: Float) {
+        self.trim_end_after(0, threshold);
+    }
+
+    /// Trim trailing silence, but keep at least `min_len` samples. Used to cut
+    /// effect tails without cutting rests at the end of the music.
+    pub fn trim_end_after(&mut self, min_len: usize, threshold: Float) {
         fn is_silent(sample: Float, threshold: Float) -> bool {
             sample.abs() <= threshold
         }
@@ -80,7 +91,7 @@ impl AudioBuffer {
         match self {
             AudioBuffer::Mono(buf) => {
                 while let Some(&last) = buf.last() {
-                    if is_silent(last, threshold) {
+                    if buf.len() > min_len && is_silent(last, threshold) {
                         buf.pop();
                     } else {
                         break;
@@ -89,7 +100,10 @@ impl AudioBuffer {
             }
             AudioBuffer::Stereo(buf) => {
                 while let Some(&(l, r)) = buf.last() {
-                    if is_silent(l, threshold) && is_silent(r, threshold) {
+                    if buf.len() > min_len
+                        && is_silent(l, threshold)
+                        && is_silent(r, threshold)
+                    {
                         buf.pop();
                     } else {
                         break;
@@ -226,6 +240,9 @@ impl AudioBuffer {
 pub struct Track {
     pub name: String,
     pub buffer: AudioBuffer,
+
+    /// Samples of the buffer taken by the notes, the rest is effect tail
+    pub length: usize,
     pub effects: Option<EffectChain>,
 }
 
@@ -240,18 +257,33 @@ pub struct TrackCreateInfo<'a> {
 
 impl Track {
     pub fn new(ci: TrackCreateInfo<'_>) -> Self {
+        let sr = ci.ctx.sample_rate;
+        let buffer = ci.instrument.render_part(ci.part, ci.ctx).to_stereo();
+        let tail = (ci.instrument.tail_time(sr) * sr as Float).ceil() as usize;
         Self {
             name: ci.name.to_string(),
-            buffer: ci.instrument.render_part(ci.part, ci.ctx).to_stereo(),
+            length: buffer.len().saturating_sub(tail),
+            buffer,
             effects: ci.fx,
         }
     }
 
+    /// Render several tracks concurrently across CPU cores. The result is
+    /// identical to creating the tracks one by one with `Track::new`.
+    pub fn new_all(cis: Vec<TrackCreateInfo<'_>>) -> Vec<Self> {
+        parallel::map(cis, None, Track::new)
+    }
+
     pub fn process(&mut self, sample_rate: u32) {
         if let Some(fx) = &mut self.effects {
             fx.process(&mut self.buffer, sample_rate);
         }
     }
+
+    /// Tail of the track effects
+    pub fn tail_time(&self, sample_rate: u32) -> Seconds {
+        self.effects.as_ref().map(|fx| fx.tail_time(sample_rate)).unwrap_or(0.0)
+    }
 }
 
 // AudioProcessor
@@ -261,6 +293,18 @@ pub struct AudioProcessor {
     pub master_fx: EffectChain,
 }
 
+/// Where and how `AudioProcessor::save_stems` writes its files
+pub struct StemExportInfo<'a> {
+    /// Directory for the stems (named after the tracks) and master.wav.
+    /// Tracks with the same name get a number suffix, ie. Drums_2.wav, so
+    /// no stem overwrites another stem or the master.
+    pub dir: &'a str,
+
+    /// Run every stem through the master effects as well. The effects are
+    /// reset between stems, so each stem sounds as if it was soloed.
+    pub apply_master_fx: bool,
+}
+
 pub struct AudioProcessorCreateInfo<'a> {
     pub ctx: &'a RenderContext,
     pub tracks: Vec<Track>,
@@ -276,26 +320,68 @@ impl AudioProcessor {
         save_to_wav(path, self.ctx.sample_rate, &self.process());
     }
 
+    /// Write every track as its own WAV file with its track effects applied,
+    /// plus the master mix. All files start at the same time and have the
+    /// length of the master.
+    pub fn save_stems(&mut self, ci: StemExportInfo) {
+        let sr = self.ctx.sample_rate;
+        let dir = Path::new(ci.dir);
+
+        self.master_fx.reset();
+        let master = self.process();
+
+        let file_names = stem_file_names(&self.tracks);
+        for (track, file_name) in self.tracks.iter().zip(file_names) {
+            let mut stem = track.buffer.clone();
+            stem.resize(master.len());
+            if ci.apply_master_fx {
+                self.master_fx.reset();
+                self.master_fx.process(&mut stem, sr);
+            }
+
+            let path = dir.join(format!("{}.wav", file_name));
+            save_to_wav(&path.to_string_lossy(), sr, &stem);
+        }
+
+        save_to_wav(&dir.join("master.wav").to_string_lossy(), sr, &master);
+    }
+
+    /// Mix all tracks. Tracks and the mix are extended by the tail of their
+    /// effects, the tail is then cut where it decays below SILENCE_THRESHOLD.
     pub fn process(&mut self) -> AudioBuffer {
+        let sr = self.ctx.sample_rate;
+        let tail_samples = |tail: Seconds| (tail * sr as Float).ceil() as usize;
+
         let mut mix = AudioBuffer::Stereo(vec![]);
+        let content_len =
+            self.tracks.iter().map(|t| t.length).max().unwrap_or(0);
 
-        for track in &mut self.tracks {
-            let mut max = track.buffer.max_amplitude();
-            if max < -1.0 || max > 1.0 {
+        // Track effects are independent of each other and run concurrently,
+        // the mix is summed in track order afterwards
+        let tracks: Vec<&mut Track> = self.tracks.iter_mut().collect();
+        let peaks = parallel::map(tracks, None, |track| {
+            let tail = tail_samples(track.tail_time(sr));
+            track.buffer.resize(track.buffer.len() + tail);
+
+            let before = track.buffer.max_amplitude();
+
+            // apply effects
+            track.process(sr);
+
+            (before, track.buffer.max_amplitude())
+        });
+
+        for (track, (before, after)) in self.tracks.iter().zip(peaks) {
+            if !(-1.0..=1.0).contains(&before) {
                 println!(
                     "Warning: Track '{}' clips before local fx with max amp: {}",
-                    track.name, max
+                    track.name, before
                 );
             }
-
-            // apply effects
-            track.process(self.ctx.sample_rate);
-
-            max = track.buffer.max_amplitude();
-            if max < -1.0 || max > 1.0 {
+            if !(-1.0..=1.0).contains(&after) {
                 println!(
                     "Warning: Track '{}' clips after local fx with max amp: {}",
-                    track.name, max
+                    track.name, after
                 );
             }
 
@@ -310,6 +396,7 @@ impl AudioProcessor {
             );
         }
 
+        mix.resize(mix.len() + tail_samples(self.master_fx.tail_time(sr)));
         for fx in &mut self.master_fx.effects {
             fx.process(&mut mix, self.ctx.sample_rate);
         }
@@ -322,9 +409,62 @@ impl AudioProcessor {
             );
         }
 
-        // TODO maybe make this optional
-        //mix.trim_end(0.0);
+        mix.trim_end_after(content_len, SILENCE_THRESHOLD);
 
         mix
     }
 }
+
+/// File names (without extension) for the stems of `tracks`. Characters that
+/// are not allowed in file names are replaced, and names that are already
+/// taken get a number suffix. Names are compared ignoring case, as on most
+/// file systems, and "master" is reserved for the mix.
+fn stem_file_names(tracks: &[Track]) -> Vec<String> {
+    let mut taken = HashSet::from(["master".to_string()]);
+    tracks
+        .iter()
+        .map(|track| {
+            let base: String = track
+                .name
+                .chars()
+                .map(|c| if "/\\:*?\"<>|".contains(c) { '_' } else { c })
+                .collect();
+            let name = (1..)
+                .map(|n| match n {
+                    1 => base.clone(),
+                    n => format!("{}_{}", base, n),
+                })
+                .find(|name| !taken.contains(&name.to_lowercase()))
+                .expect("Some suffix should be free");
+            taken.insert(name.to_lowercase());
+            name
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn track(name: &str) -> Track {
+        Track {
+            name: name.to_string(),
+            buffer: AudioBuffer::Stereo(vec![]),
+            length: 0,
+            effects: None,
+        }
+    }
+
+    #[test]
+    fn stem_names_are_unique() {
+        let tracks: Vec<Track> =
+            ["Drums", "Master", "Drums", "Drums_2", "a/b", "drums"]
+                .into_iter()
+                .map(track)
+                .collect();
+        assert_eq!(
+            stem_file_names(&tracks),
+            ["Drums", "Master_2", "Drums_2", "Drums_2_2", "a_b", "drums_3"]
+        );
+    }
+}
@@ -0,0 +1,610 @@
+//! Loads SoundFont 2 files. Every preset can be turned into an instrument
+//! with one sampler layer per zone, so General MIDI sound fonts can render
+//! the parts of a score by their MIDI program.
+//!
+//! Supported generators: sample and loop offsets, key and velocity ranges,
+//! root key override, coarse and fine tuning, sample modes, initial
+//! attenuation and the volume envelope. Modulators, filters, effects sends
+//! and panning are ignored. Stereo samples are mixed to mono.
+
+use std::collections::HashMap;
+use std::path::Path;
+
+use super::dsp::signal::{Sampler, SignalSource};
+use super::dsp::{
+    ModulationMatrix, ModulationMode, ModulationRoute, ModulationSource,
+    ModulationTarget, ParametricEnvelope,
+};
+use super::engine::{EventDriver, Graph, NodeKind, NoteEvent, SoundSource};
+use super::instrument::{
+    drum_kit, key_to_freq, Instrument, InstrumentLayer, Zone,
+};
+use super::types::{Float, Seconds};
+use super::voice::Voicing;
+use crate::compose::{MidiInstrument, NaturalTone, Part, Score, Unpitched};
+
+// Generator operators
+const START_OFFSET: u16 = 0;
+const END_OFFSET: u16 = 1;
+const LOOP_START_OFFSET: u16 = 2;
+const LOOP_END_OFFSET: u16 = 3;
+const START_COARSE_OFFSET: u16 = 4;
+const END_COARSE_OFFSET: u16 = 12;
+const ATTACK_VOL_ENV: u16 = 34;
+const HOLD_VOL_ENV: u16 = 35;
+const DECAY_VOL_ENV: u16 = 36;
+const SUSTAIN_VOL_ENV: u16 = 37;
+const RELEASE_VOL_ENV: u16 = 38;
+const INSTRUMENT: u16 = 41;
+const KEY_RANGE: u16 = 43;
+const VEL_RANGE: u16 = 44;
+const LOOP_START_COARSE_OFFSET: u16 = 45;
+const INITIAL_ATTENUATION: u16 = 48;
+const LOOP_END_COARSE_OFFSET: u16 = 50;
+const COARSE_TUNE: u16 = 51;
+const FINE_TUNE: u16 = 52;
+const SAMPLE_ID: u16 = 53;
+const SAMPLE_MODES: u16 = 54;
+const OVERRIDING_ROOT_KEY: u16 = 58;
+
+/// Generators that a preset zone adds to the instrument zone value. Ranges
+/// are intersected and the rest may only be set by instruments.
+const ADDITIVE: [u16; 9] = [
+    ATTACK_VOL_ENV,
+    HOLD_VOL_ENV,
+    DECAY_VOL_ENV,
+    SUSTAIN_VOL_ENV,
+    RELEASE_VOL_ENV,
+    INITIAL_ATTENUATION,
+    COARSE_TUNE,
+    FINE_TUNE,
+    // Volume envelope delay
+    33,
+];
+
+/// Timecents of envelope stages that aren't set, about one millisecond
+const DEFAULT_TIMECENTS: i16 = -12000;
+
+/// MIDI bank of the percussion presets of General MIDI sound fonts
+pub const PERCUSSION_BANK: u16 = 128;
+
+pub struct SoundFont {
+    pub name: String,
+    pub presets: Vec<Sf2Preset>,
+    instruments: Vec<Sf2Instrument>,
+    samples: Vec<Sf2Sample>,
+
+    /// 16 bit sample data of all samples
+    data: Vec<i16>,
+}
+
+pub struct Sf2Preset {
+    pub name: String,
+    pub bank: u16,
+
+    /// MIDI program, zero based
+    pub program: u16,
+    zones: Vec<Sf2Zone>,
+}
+
+struct Sf2Instrument {
+    zones: Vec<Sf2Zone>,
+}
+
+/// Generator amounts by operator, stored as raw 16 bit words
+#[derive(Clone, Default)]
+struct Sf2Zone {
+    generators: HashMap<u16, u16>,
+}
+
+impl Sf2Zone {
+    fn signed(&self, op: u16) -> Option<i16> {
+        self.generators.get(&op).map(|&v| v as i16)
+    }
+
+    fn range(&self, op: u16) -> (u8, u8) {
+        match self.generators.get(&op) {
+            Some(&v) => ((v & 0xff) as u8, (v >> 8) as u8),
+            None => (0, 127),
+        }
+    }
+
+    /// Local generators override the global zone
+    fn merged(global: &Option<Sf2Zone>, local: &Sf2Zone) -> Sf2Zone {
+        let mut zone = global.clone().unwrap_or_default();
+        zone.generators.extend(&local.generators);
+        zone
+    }
+}
+
+struct Sf2Sample {
+    start: u32,
+    end: u32,
+    loop_start: u32,
+    loop_end: u32,
+    sample_rate: u32,
+    original_pitch: u8,
+    pitch_correction: i8,
+    sample_type: u16,
+}
+
+impl SoundFont {
+    pub fn load(path: &str) -> Result<Self, String> {
+        let bytes = std::fs::read(path)
+            .map_err(|e| format!("Failed to read '{}': {}", path, e))?;
+        let mut font = Self::parse(&bytes)?;
+        if font.name.is_empty() {
+            if let Some(stem) = Path::new(path).file_stem() {
+                font.name = stem.to_string_lossy().to_string();
+            }
+        }
+        Ok(font)
+    }
+
+    pub fn parse(bytes: &[u8]) -> Result<Self, String> {
+        if bytes.len() < 12
+            || &bytes[0..4] != b"RIFF"
+            || &bytes[8..12] != b"sfbk"
+        {
+            return Err("Not a SoundFont 2 file".to_string());
+        }
+
+        let mut name = String::new();
+        let mut data = vec![];
+        let mut pdta: HashMap<[u8; 4], &[u8]> = HashMap::new();
+        for (id, body) in chunks(&bytes[12..])? {
+            if &id != b"LIST" || body.len() < 4 {
+                continue;
+            }
+            let list: [u8; 4] = body[0..4].try_into().unwrap();
+            for (id, sub) in chunks(&body[4..])? {
+                match (&list, &id) {
+                    (b"INFO", b"INAM") => name = read_name(sub),
+                    (b"sdta", b"smpl") => {
+                        data = sub
+                            .chunks_exact(2)
+                            .map(|b| i16::from_le_bytes([b[0], b[1]]))
+                            .collect();
+                    }
+                    (b"pdta", _) => {
+                        pdta.insert(id, sub);
+                    }
+                    _ => {}
+                }
+            }
+        }
+
+        let table = |id: &[u8; 4], size: usize| -> Result<Vec<&[u8]>, String> {
+            let body = pdta.get(id).ok_or(format!(
+                "Missing '{}' chunk",
+                String::from_utf8_lossy(id)
+            ))?;
+            Ok(body.chunks_exact(size).collect())
+        };
+
+        let generators: Vec<(u16, u16)> = table(b"pgen", 4)?
+            .iter()
+            .map(|r| (u16_at(r, 0), u16_at(r, 2)))
+            .collect();
+        let bags: Vec<usize> =
+            table(b"pbag", 4)?.iter().map(|r| u16_at(r, 0) as usize).collect();
+        let headers = table(b"phdr", 38)?;
+        let mut presets = vec![];
+
+        // The last record of every list only terminates it
+        for pair in headers.windows(2) {
+            let (header, next) = (pair[0], pair[1]);
+            let bag_range =
+                u16_at(header, 24) as usize..u16_at(next, 24) as usize;
+            presets.push(Sf2Preset {
+                name: read_name(&header[0..20]),
+                program: u16_at(header, 20),
+                bank: u16_at(header, 22),
+                zones: read_zones(&bags, &generators, bag_range)?,
+            });
+        }
+
+        let generators: Vec<(u16, u16)> = table(b"igen", 4)?
+            .iter()
+            .map(|r| (u16_at(r, 0), u16_at(r, 2)))
+            .collect();
+        let bags: Vec<usize> =
+            table(b"ibag", 4)?.iter().map(|r| u16_at(r, 0) as usize).collect();
+        let headers = table(b"inst", 22)?;
+        let mut instruments = vec![];
+        for pair in headers.windows(2) {
+            let bag_range =
+                u16_at(pair[0], 20) as usize..u16_at(pair[1], 20) as usize;
+            instruments.push(Sf2Instrument {
+                zones: read_zones(&bags, &generators, bag_range)?,
+            });
+        }
+
+        let headers = table(b"shdr", 46)?;
+        let samples = headers[..headers.len().saturating_sub(1)]
+            .iter()
+            .map(|r| Sf2Sample {
+                start: u32_at(r, 20),
+                end: u32_at(r, 24),
+                loop_start: u32_at(r, 28),
+                loop_end: u32_at(r, 32),
+                sample_rate: u32_at(r, 36),
+                original_pitch: r[40],
+                pitch_correction: r[41] as i8,
+                sample_type: u16_at(r, 44),
+            })
+            .collect();
+
+        Ok(Self { name, presets, instruments, samples, data })
+    }
+
+    /// Preset by bank and zero based program
+    pub fn preset(&self, bank: u16, program: u16) -> Option<&Sf2Preset> {
+        self.presets.iter().find(|p| p.bank == bank && p.program == program)
+    }
+
+    /// The preset of a program, or the closest program of the same bank if
+    /// the font doesn't have it
+    pub fn nearest_preset(
+        &self,
+        bank: u16,
+        program: u16,
+    ) -> Option<&Sf2Preset> {
+        self.preset(bank, program).or_else(|| {
+            self.presets
+                .iter()
+                .filter(|p| p.bank == bank)
+                .min_by_key(|p| p.program.abs_diff(program))
+        })
+    }
+
+    /// Build a renderable instrument from a preset with one layer per
+    /// combination of preset and instrument zone
+    pub fn instrument(&self, preset: &Sf2Preset) -> Instrument {
+        let (preset_global, preset_zones) =
+            split_global(&preset.zones, INSTRUMENT);
+        let mut layers = vec![];
+
+        for preset_zone in preset_zones {
+            let preset_zone = Sf2Zone::merged(&preset_global, preset_zone);
+            let Some(index) = preset_zone.generators.get(&INSTRUMENT) else {
+                continue;
+            };
+            let Some(inst) = self.instruments.get(*index as usize) else {
+                continue;
+            };
+
+            let (global, zones) = split_global(&inst.zones, SAMPLE_ID);
+            for zone in zones {
+                let zone = Sf2Zone::merged(&global, zone);
+                if let Some(layer) = self.layer(&preset_zone, &zone) {
+                    layers.push(layer);
+                }
+            }
+        }
+
+        Instrument {
+            name: preset.name.clone(),
+            layers,
+            mods: None,
+            fx: None,
+            is_unpitched: false,
+            voicing: Voicing::default(),
+        }
+    }
+
+    /// Instrument for a MusicXML <midi-instrument>. MusicXML numbers
+    /// programs and banks from one and channel 10 selects the percussion
+    /// bank. Programs the font doesn't have fall back to the closest program
+    /// of the bank.
+    pub fn midi_instrument(&self, midi: &MidiInstrument) -> Option<Instrument> {
+        let program = midi.program.unwrap_or(1).saturating_sub(1) as u16;
+        let bank = match midi.channel {
+            Some(10) => PERCUSSION_BANK,
+            _ => midi.bank.unwrap_or(1).saturating_sub(1) as u16,
+        };
+        self.nearest_preset(bank, program).map(|p| self.instrument(p))
+    }
+
+    /// Drum preset of a percussion part and its notes with the MIDI key of
+    /// the drum they play. The key comes from the <midi-unpitched> of the
+    /// part, or else from the staff position of the note. Notes without a
+    /// key are dropped. None if the font has no percussion preset.
+    fn drums(
+        &self,
+        part: &Part,
+        events: Vec<NoteEvent>,
+    ) -> Option<(Instrument, Vec<NoteEvent>)> {
+        let midi = part.midi_instrument();
+        let inst = midi
+            .filter(|m| m.channel == Some(10))
+            .and_then(|m| self.midi_instrument(m))
+            .or_else(|| {
+                let preset = self.nearest_preset(PERCUSSION_BANK, 0)?;
+                Some(self.instrument(preset))
+            })?;
+
+        let part_key =
+            midi.and_then(|m| m.unpitched).map(|k| k.clamp(1, 128) as u8 - 1);
+        let events = events
+            .into_iter()
+            .filter_map(|mut event| {
+                if event.freq.is_none() {
+                    let key = part_key.or_else(|| {
+                        event.unpitched.as_ref().and_then(gm_key)
+                    })?;
+                    event.freq = Some(key_to_freq(key));
+                }
+                Some(event)
+            })
+            .collect();
+        Some((inst, events))
+    }
+
+    /// Engine graph that plays every part of a score through the preset of
+    /// its MIDI program into one output. Parts without a program use the
+    /// first preset. Percussion parts use the drum preset of the font, or
+    /// the synthesized drum kit if it has none.
+    pub fn score_graph(
+        &self,
+        score: Score,
+        target: &str,
+    ) -> Result<Graph, String> {
+        let first = self.presets.first().ok_or("Sound font has no presets")?;
+        let mut graph = Graph::new();
+        let output = graph
+            .add_named_node(
+                "output",
+                NodeKind::Output { target: target.to_string() },
+            )
+            .map_err(|e| e.to_string())?;
+
+        for part in score.parts {
+            let name = part.id.clone();
+            let events = part.collect_events();
+            let (source, driver) = if events.iter().any(|e| e.freq.is_none()) {
+                match self.drums(&part, events) {
+                    Some((inst, events)) => (
+                        SoundSource::Instrument(inst),
+                        EventDriver::Events(events),
+                    ),
+                    None => (
+                        SoundSource::DrumKit(drum_kit()),
+                        EventDriver::MusicXmlPart(Box::new(part)),
+                    ),
+                }
+            } else {
+                let inst = part
+                    .midi_instrument()
+                    .and_then(|midi| self.midi_instrument(midi))
+                    .unwrap_or_else(|| self.instrument(first));
+                (
+                    SoundSource::Instrument(inst),
+                    EventDriver::MusicXmlPart(Box::new(part)),
+                )
+            };
+
+            let track = graph
+                .add_named_node(&name, NodeKind::Track { source, driver })
+                .map_err(|e| e.to_string())?;
+            graph
+                .connect(track, output)
+                .expect("Track should connect to output");
+        }
+        Ok(graph)
+    }
+
+    fn layer(
+        &self,
+        preset_zone: &Sf2Zone,
+        zone: &Sf2Zone,
+    ) -> Option<InstrumentLayer> {
+        let sample =
+            self.samples.get(zone.signed(SAMPLE_ID)? as u16 as usize)?;
+
+        // Preset values offset the instrument values
+        let value = |op: u16, default: i16| -> i32 {
+            let base = zone.signed(op).unwrap_or(default) as i32;
+            match ADDITIVE.contains(&op) {
+                true => base + preset_zone.signed(op).unwrap_or(0) as i32,
+                false => base,
+            }
+        };
+        let offset = |fine: u16, coarse: u16| -> i64 {
+            value(fine, 0) as i64 + value(coarse, 0) as i64 * 32768
+        };
+
+        let start = (sample.start as i64
+            + offset(START_OFFSET, START_COARSE_OFFSET))
+        .clamp(0, self.data.len() as i64) as usize;
+        let end = (sample.end as i64 + offset(END_OFFSET, END_COARSE_OFFSET))
+            .clamp(start as i64, self.data.len() as i64)
+            as usize;
+        let data: Vec<Float> = self.data[start..end]
+            .iter()
+            .map(|&s| s as Float / 32768.0)
+            .collect();
+        if data.is_empty() {
+            return None;
+        }
+
+        let root_key = match value(OVERRIDING_ROOT_KEY, -1) {
+            key @ 0..=127 => key as u8,
+            _ => sample.original_pitch.min(127),
+        };
+        let cents = value(COARSE_TUNE, 0) * 100
+            + value(FINE_TUNE, 0)
+            + sample.pitch_correction as i32;
+        let root =
+            key_to_freq(root_key) / 2.0_f64.powf(cents as Float / 1200.0);
+
+        let mut sampler = Sampler::new(data, sample.sample_rate, root);
+        let mode = value(SAMPLE_MODES, 0);
+        let loop_start = sample.loop_start as i64
+            + offset(LOOP_START_OFFSET, LOOP_START_COARSE_OFFSET)
+            - start as i64;
+        let loop_end = sample.loop_end as i64
+            + offset(LOOP_END_OFFSET, LOOP_END_COARSE_OFFSET)
+            - start as i64;
+        if matches!(mode, 1 | 3)
+            && 0 <= loop_start
+            && loop_start < loop_end
+            && loop_end <= sampler.data.len() as i64
+        {
+            let (loop_start, loop_end) =
+                (loop_start as usize, loop_end as usize);
+
+            // Mode 3 loops while the key is held and then plays the rest
+            sampler = match mode {
+                3 => sampler.sustain_looped(loop_start, loop_end, 0),
+                _ => sampler.looped(loop_start, loop_end, 0),
+            };
+        }
+
+        let envelope = ParametricEnvelope::from_ahdsr(
+            timecents(value(ATTACK_VOL_ENV, DEFAULT_TIMECENTS)),
+            timecents(value(HOLD_VOL_ENV, DEFAULT_TIMECENTS)),
+            timecents(value(DECAY_VOL_ENV, DEFAULT_TIMECENTS)),
+            centibels(value(SUSTAIN_VOL_ENV, 0)),
+            timecents(value(RELEASE_VOL_ENV, DEFAULT_TIMECENTS)),
+            1.0,
+        );
+
+        // Both halves of a stereo pair play, keep their sum at the level of
+        // one channel
+        let stereo = matches!(sample.sample_type & 0x7fff, 2 | 4);
+        let volume = centibels(value(INITIAL_ATTENUATION, 0))
+            * if stereo { 0.5 } else { 1.0 };
+
+        let (lokey, hikey) =
+            intersect(zone.range(KEY_RANGE), preset_zone.range(KEY_RANGE));
+        let (lovel, hivel) =
+            intersect(zone.range(VEL_RANGE), preset_zone.range(VEL_RANGE));
+        if lokey > hikey || lovel > hivel {
+            return None;
+        }
+
+        Some(InstrumentLayer {
+            signal: SignalSource::Sampler(sampler),
+            mods: Some(ModulationMatrix {
+                routes: vec![ModulationRoute {
+                    source: ModulationSource::Envelope(envelope),
+                    target: ModulationTarget::Amplitude,
+                    mode: ModulationMode::Scale,
+                    depth: 1.0,
+                    depth_mod: None,
+                }],
+            }),
+            fx: None,
+            base_freq: None,
+            volume,
+            unison: None,
+            zone: Some(Zone {
+                keys: lokey..=hikey,
+                velocities: lovel..=hivel,
+                round_robin: None,
+            }),
+        })
+    }
+}
+
+/// RIFF chunk id and body
+type Chunk<'a> = ([u8; 4], &'a [u8]);
+
+/// Split RIFF chunks into their ids and bodies
+fn chunks(mut bytes: &[u8]) -> Result<Vec<Chunk<'_>>, String> {
+    let mut chunks = vec![];
+    while bytes.len() >= 8 {
+        let id: [u8; 4] = bytes[0..4].try_into().unwrap();
+        let size = u32_at(bytes, 4) as usize;
+        let body = bytes.get(8..8 + size).ok_or(format!(
+            "Truncated '{}' chunk",
+            String::from_utf8_lossy(&id)
+        ))?;
+        chunks.push((id, body));
+
+        // Chunks are padded to an even size
+        let next = (8 + size + size % 2).min(bytes.len());
+        bytes = &bytes[next..];
+    }
+    Ok(chunks)
+}
+
+fn read_zones(
+    bags: &[usize],
+    generators: &[(u16, u16)],
+    bag_range: std::ops::Range<usize>,
+) -> Result<Vec<Sf2Zone>, String> {
+    bag_range
+        .map(|bag| {
+            let (from, to) = match (bags.get(bag), bags.get(bag + 1)) {
+                (Some(&from), Some(&to)) => (from, to),
+                _ => return Err(format!("Bag {} out of range", bag)),
+            };
+            let generators = generators
+                .get(from..to)
+                .ok_or(format!("Generators of bag {} out of range", bag))?;
+            Ok(Sf2Zone { generators: generators.iter().copied().collect() })
+        })
+        .collect()
+}
+
+/// The first zone is global if it lacks the generator that ends every
+/// other zone
+fn split_global(
+    zones: &[Sf2Zone],
+    terminal: u16,
+) -> (Option<Sf2Zone>, &[Sf2Zone]) {
+    match zones.first() {
+        Some(first) if !first.generators.contains_key(&terminal) => {
+            (Some(first.clone()), &zones[1..])
+        }
+        _ => (None, zones),
+    }
+}
+
+fn intersect(a: (u8, u8), b: (u8, u8)) -> (u8, u8) {
+    (a.0.max(b.0), a.1.min(b.1))
+}
+
+fn timecents(value: i32) -> Seconds {
+    2.0_f64.powf(value as Float / 1200.0)
+}
+
+/// Gain of an attenuation in centibels
+fn centibels(value: i32) -> Float {
+    10.0_f64.powf(-value.max(0) as Float / 200.0)
+}
+
+fn read_name(bytes: &[u8]) -> String {
+    let end = bytes.iter().position(|&b| b == 0).unwrap_or(bytes.len());
+    String::from_utf8_lossy(&bytes[..end]).trim().to_string()
+}
+
+fn u16_at(bytes: &[u8], at: usize) -> u16 {
+    u16::from_le_bytes([bytes[at], bytes[at + 1]])
+}
+
+fn u32_at(bytes: &[u8], at: usize) -> u32 {
+    u32::from_le_bytes(bytes[at..at + 4].try_into().unwrap())
+}
+
+/// General MIDI percussion key of a note on the common drum set staff
+/// positions
+fn gm_key(unpitched: &Unpitched) -> Option<u8> {
+    use NaturalTone::*;
+    let key = match (&unpitched.display_step, unpitched.display_octave) {
+        (D, 4) => 44, // Pedal hi-hat
+        (E, 4) => 35, // Acoustic bass drum
+        (F, 4) => 36, // Bass drum
+        (A, 4) => 43, // Floor tom
+        (C, 5) => 38, // Snare
+        (D, 5) => 47, // Low-mid tom
+        (E, 5) => 48, // High-mid tom
+        (F, 5) => 51, // Ride cymbal
+        (G, 5) => 42, // Closed hi-hat
+        (A, 5) => 49, // Crash cymbal
+        _ => return None,
+    };
+    Some(key)
+}
@@ -0,0 +1,375 @@
+//! Loads multi-sampled instruments from a subset of the SFZ format. Each
+//! `<region>` becomes a sampler layer with a key and velocity zone. Opcodes
+//! set under `<global>` and `<group>` apply to the regions that follow them.
+//!
+//! Supported opcodes: `sample`, `lokey`, `hikey`, `key`, `lovel`, `hivel`,
+//! `pitch_keycenter`, `volume`, `offset`, `end`, `loop_mode`, `loop_start`,
+//! `loop_end`, `loop_crossfade`, `seq_length`, `seq_position`,
+//! `ampeg_attack`, `ampeg_hold`, `ampeg_decay`, `ampeg_sustain`,
+//! `ampeg_release` and `default_path` under `<control>`. Other opcodes are
+//! ignored. `one_shot` regions play to the end of the sample however short
+//! the note, `loop_sustain` regions leave the loop on release and play the
+//! rest of the sample.
+
+use std::collections::HashMap;
+use std::path::Path;
+
+use super::dsp::signal::{Sampler, SignalSource};
+use super::dsp::{
+    ModulationMatrix, ModulationMode, ModulationRoute, ModulationSource,
+    ModulationTarget, ParametricEnvelope,
+};
+use super::instrument::{
+    key_to_freq, Instrument, InstrumentLayer, RoundRobin, Zone,
+};
+use super::processor::AudioBuffer;
+use super::types::Float;
+use super::voice::Voicing;
+use super::wav::try_read_wav;
+
+type Opcodes = HashMap<String, String>;
+
+enum Section {
+    None,
+    Control,
+    Global,
+    Group,
+    Region,
+}
+
+impl Instrument {
+    /// Load an SFZ file, samples are found relative to it
+    pub fn from_sfz(path: &str) -> Result<Self, String> {
+        let text = std::fs::read_to_string(path)
+            .map_err(|e| format!("Failed to read '{}': {}", path, e))?;
+        let path = Path::new(path);
+        let dir = path.parent().unwrap_or(Path::new(""));
+        let mut inst = Self::parse_sfz(&text, dir)?;
+        if let Some(stem) = path.file_stem() {
+            inst.name = stem.to_string_lossy().to_string();
+        }
+        Ok(inst)
+    }
+
+    /// Build an instrument from SFZ text with samples relative to `dir`
+    pub fn parse_sfz(text: &str, dir: &Path) -> Result<Self, String> {
+        let mut section = Section::None;
+        let mut control = Opcodes::new();
+        let mut global = Opcodes::new();
+        let mut group = Opcodes::new();
+        let mut regions: Vec<Opcodes> = vec![];
+
+        for (index, line) in text.lines().enumerate() {
+            let line = line.split("//").next().unwrap();
+            let err = |e: String| format!("line {}: {}", index + 1, e);
+
+            // The last opcode read, words without '=' continue its value
+            let mut last: Option<String> = None;
+
+            for token in tokenize(line) {
+                if let Some(header) = token.strip_prefix('<') {
+                    section = match header.trim_end_matches('>') {
+                        "control" => Section::Control,
+                        "global" => {
+                            global.clear();
+                            group.clear();
+                            Section::Global
+                        }
+                        "group" => {
+                            group.clear();
+                            Section::Group
+                        }
+                        "region" => {
+                            // Regions start with the global and group
+                            // opcodes in effect
+                            let mut region = global.clone();
+                            region.extend(group.clone());
+                            regions.push(region);
+                            Section::Region
+                        }
+                        other => {
+                            return Err(err(format!(
+                                "Unsupported header <{}>",
+                                other
+                            )))
+                        }
+                    };
+                    last = None;
+                    continue;
+                }
+
+                let opcodes = match section {
+                    Section::None => {
+                        return Err(err(format!(
+                            "Opcode '{}' outside of a header",
+                            token
+                        )))
+                    }
+                    Section::Control => &mut control,
+                    Section::Global => &mut global,
+                    Section::Group => &mut group,
+                    Section::Region => regions.last_mut().unwrap(),
+                };
+
+                match (token.split_once('='), &last) {
+                    (Some((key, value)), _) => {
+                        opcodes.insert(key.to_string(), value.to_string());
+                        last = Some(key.to_string());
+                    }
+                    (None, Some(key)) => {
+                        let value = opcodes.get_mut(key).unwrap();
+                        value.push(' ');
+                        value.push_str(&token);
+                    }
+                    (None, None) => {
+                        return Err(err(format!(
+                            "Expected opcode: '{}'",
+                            token
+                        )))
+                    }
+                }
+            }
+        }
+
+        let default_path = control.get("default_path").cloned();
+        let mut cache: HashMap<String, (Vec<Float>, u32)> = HashMap::new();
+        let mut layers = vec![];
+        for (index, region) in regions.iter().enumerate() {
+            let layer = region_layer(region, dir, &default_path, &mut cache)
+                .map_err(|e| format!("region {}: {}", index + 1, e))?;
+            layers.push(layer);
+        }
+
+        Ok(Self {
+            name: String::new(),
+            layers,
+            mods: None,
+            fx: None,
+            is_unpitched: false,
+            voicing: Voicing::default(),
+        })
+    }
+}
+
+/// Split a line into headers and words. Headers may be followed directly
+/// by an opcode, ie. `<region>sample=a.wav`.
+fn tokenize(line: &str) -> Vec<String> {
+    let mut tokens = vec![];
+    for word in line.split_whitespace() {
+        let mut rest = word;
+        while let Some(open) = rest.find('<') {
+            if open > 0 {
+                tokens.push(rest[..open].to_string());
+            }
+            let close = rest[open..].find('>').map_or(rest.len(), |c| open + c);
+            tokens.push(rest[open..(close + 1).min(rest.len())].to_string());
+            rest = &rest[(close + 1).min(rest.len())..];
+        }
+        if !rest.is_empty() {
+            tokens.push(rest.to_string());
+        }
+    }
+    tokens
+}
+
+fn region_layer(
+    region: &Opcodes,
+    dir: &Path,
+    default_path: &Option<String>,
+    cache: &mut HashMap<String, (Vec<Float>, u32)>,
+) -> Result<InstrumentLayer, String> {
+    let number = |key: &str, default: Float| -> Result<Float, String> {
+        match region.get(key) {
+            Some(v) => v
+                .parse::<Float>()
+                .map_err(|_| format!("Invalid number for '{}': '{}'", key, v)),
+            None => Ok(default),
+        }
+    };
+    let key = |key: &str, default: u8| -> Result<u8, String> {
+        region.get(key).map_or(Ok(default), |v| parse_key(v))
+    };
+
+    // Sample data, loaded once per file
+    let sample = region.get("sample").ok_or("Missing 'sample'")?;
+    let mut path = dir.to_path_buf();
+    if let Some(default_path) = default_path {
+        path.push(default_path.replace('\\', "/"));
+    }
+    path.push(sample.replace('\\', "/"));
+    let path = path.to_string_lossy().to_string();
+    if !cache.contains_key(&path) {
+        if !Path::new(&path).exists() {
+            return Err(format!("Sample '{}' not found", path));
+        }
+        let (buffer, sample_rate) = try_read_wav(&path)?;
+        let AudioBuffer::Mono(data) = buffer.to_mono() else { unreachable!() };
+        cache.insert(path.clone(), (data, sample_rate));
+    }
+    let (data, sample_rate) = cache[&path].clone();
+
+    // `key` sets the range and the root at once
+    let single = region.get("key").map(|k| parse_key(k)).transpose()?;
+    let lokey = single.map_or_else(|| key("lokey", 0), Ok)?;
+    let hikey = single.map_or_else(|| key("hikey", 127), Ok)?;
+    let root = single.map_or_else(|| key("pitch_keycenter", 60), Ok)?;
+
+    let mut sampler = Sampler::new(data, sample_rate, key_to_freq(root));
+    let len = sampler.data.len();
+    sampler.start = (number("offset", 0.0)? as usize).min(len);
+
+    // SFZ end points are inclusive
+    sampler.end = (number("end", len as Float - 1.0)? as usize + 1).min(len);
+    match region.get("loop_mode").map(|m| m.as_str()) {
+        None | Some("no_loop") => {}
+        Some("one_shot") => sampler = sampler.one_shot(),
+        Some(mode @ ("loop_continuous" | "loop_sustain")) => {
+            let start = number("loop_start", sampler.start as Float)?;
+            let end = number("loop_end", sampler.end as Float - 1.0)? + 1.0;
+            if start < 0.0
+                || end < 0.0
+                || !sampler.loop_fits(start as usize, end as usize)
+            {
+                return Err(format!(
+                    "Loop {}..{} should lie within frames {}..{} of the sample",
+                    start,
+                    end - 1.0,
+                    sampler.start,
+                    len.max(1) - 1
+                ));
+            }
+            let (start, end) = (start as usize, end as usize);
+            let crossfade = number("loop_crossfade", 0.0)?;
+            let crossfade = (crossfade * sample_rate as Float) as usize;
+            sampler = match mode {
+                "loop_sustain" => sampler.sustain_looped(start, end, crossfade),
+                _ => sampler.looped(start, end, crossfade),
+            };
+        }
+        Some(other) => return Err(format!("Unknown loop_mode '{}'", other)),
+    }
+
+    let envelope = ParametricEnvelope::from_ahdsr(
+        number("ampeg_attack", 0.0)?,
+        number("ampeg_hold", 0.0)?,
+        number("ampeg_decay", 0.0)?,
+        number("ampeg_sustain", 100.0)? / 100.0,
+        number("ampeg_release", 0.001)?,
+        1.0,
+    );
+
+    let seq_length = number("seq_length", 1.0)? as usize;
+    let seq_position = number("seq_position", 1.0)? as usize;
+    let round_robin = (seq_length > 1).then(|| RoundRobin {
+        length: seq_length,
+        position: seq_position.clamp(1, seq_length) - 1,
+    });
+
+    Ok(InstrumentLayer {
+        signal: SignalSource::Sampler(sampler),
+        mods: Some(ModulationMatrix {
+            routes: vec![ModulationRoute {
+                source: ModulationSource::Envelope(envelope),
+                target: ModulationTarget::Amplitude,
+                mode: ModulationMode::Scale,
+                depth: 1.0,
+                depth_mod: None,
+            }],
+        }),
+        fx: None,
+        base_freq: None,
+        volume: 10.0_f64.powf(number("volume", 0.0)? / 20.0),
+        unison: None,
+        zone: Some(Zone {
+            keys: lokey..=hikey,
+            velocities: key("lovel", 0)?..=key("hivel", 127)?,
+            round_robin,
+        }),
+    })
+}
+
+/// MIDI key from a number or a note name like "c4", "f#3" or "eb-1", where
+/// c4 is middle C (60)
+fn parse_key(value: &str) -> Result<u8, String> {
+    if let Ok(key) = value.parse::<u8>() {
+        return Ok(key.min(127));
+    }
+
+    let invalid = || format!("Invalid key '{}'", value);
+    let lower = value.to_lowercase();
+    let mut chars = lower.chars();
+    let class: i32 = match chars.next() {
+        Some('c') => 0,
+        Some('d') => 2,
+        Some('e') => 4,
+        Some('f') => 5,
+        Some('g') => 7,
+        Some('a') => 9,
+        Some('b') => 11,
+        _ => return Err(invalid()),
+    };
+    let rest = chars.as_str();
+    let (alter, octave) = match rest.chars().next() {
+        Some('#') => (1, &rest[1..]),
+        Some('b') => (-1, &rest[1..]),
+        _ => (0, rest),
+    };
+    let octave: i32 = octave.parse().map_err(|_| invalid())?;
+    let key = (octave + 1) * 12 + class + alter;
+    if !(0..=127).contains(&key) {
+        return Err(invalid());
+    }
+    Ok(key as u8)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::*;
+    use crate::render::wav::save_to_wav;
+
+    /// Directory of a test with a 100 frame sample and a file that is not a
+    /// WAV
+    fn samples(test: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("lyra_sfz_{}", test));
+        let sample = AudioBuffer::Mono(vec![0.5; 100]);
+        save_to_wav(&dir.join("a.wav").to_string_lossy(), 8000, &sample);
+        std::fs::write(dir.join("bad.wav"), "not a wav").unwrap();
+        dir
+    }
+
+    #[test]
+    fn rejects_loops_outside_the_sample() {
+        let dir = samples("loops");
+        let region = |opcodes: &str| {
+            let text = format!("<region> sample=a.wav {}", opcodes);
+            Instrument::parse_sfz(&text, &dir)
+        };
+
+        assert!(region("loop_mode=loop_continuous").is_ok());
+        assert!(
+            region("loop_mode=loop_sustain loop_start=10 loop_end=89").is_ok()
+        );
+        for opcodes in [
+            "loop_start=50 loop_end=20",
+            "offset=30 loop_start=10 loop_end=80",
+            "loop_start=10 loop_end=200",
+            "loop_start=-5 loop_end=50",
+        ] {
+            let err = region(&format!("loop_mode=loop_continuous {}", opcodes))
+                .err()
+                .unwrap_or_else(|| panic!("'{}' should fail", opcodes));
+            assert!(err.starts_with("region 1: "), "{}", err);
+        }
+    }
+
+    #[test]
+    fn rejects_unreadable_samples() {
+        let err =
+            Instrument::parse_sfz("<region> sample=bad.wav", &samples("bad"))
+                .err()
+                .unwrap();
+        assert!(err.starts_with("region 1: Failed to read"), "{}", err);
+    }
+}
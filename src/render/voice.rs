@@ -0,0 +1,357 @@
+//! Assigns the note events of an instrument to voices. Voices are planned
+//! ahead of rendering: each one knows when it starts, when its gate closes,
+//! the pitches it glides through and whether it is cut short by a note that
+//! steals it.
+
+use super::dsp::Gate;
+use super::engine::NoteEvent;
+use super::types::{Float, Hz, Seconds};
+
+/// Stolen voices fade out over this time instead of stopping with a click
+pub const STEAL_FADE: Seconds = 0.005;
+
+/// Notes starting within this time after the previous note ends count as
+/// connected, so notes written back to back play legato
+const LEGATO_EPS: Seconds = 1e-6;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum VoiceMode {
+    /// Every note gets its own voice
+    Poly,
+
+    /// One voice at a time. Each note cuts the previous voice, retriggers the
+    /// envelopes and glides from the previous pitch.
+    Mono,
+
+    /// One voice at a time. A note that starts while the previous one is
+    /// still held, or right when it ends, continues its voice without
+    /// retriggering the envelopes and glides to the new pitch. Detached
+    /// notes start a new voice.
+    Legato,
+}
+
+/// Which voice gives way when a note would exceed the voice limit
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum StealPolicy {
+    /// The voice that started first
+    Oldest,
+
+    /// The voice with the lowest amplitude when the new note starts
+    Quietest,
+
+    /// A voice playing the same pitch, or the oldest voice if there is none
+    SameNote,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Voicing {
+    pub mode: VoiceMode,
+
+    /// Limit of voices sounding at once in poly mode, released voices
+    /// included. None allows any number of voices.
+    pub max_voices: Option<usize>,
+    pub steal: StealPolicy,
+
+    /// Portamento time of mono and legato modes
+    pub glide: Seconds,
+}
+
+impl Default for Voicing {
+    fn default() -> Self {
+        Self {
+            mode: VoiceMode::Poly,
+            max_voices: None,
+            steal: StealPolicy::Oldest,
+            glide: 0.0,
+        }
+    }
+}
+
+/// A note, or a phrase of legato notes, rendered as a unit with its own
+/// envelope gate, pitch and noise
+#[derive(Clone)]
+pub struct Voice {
+    /// Allocation order, seeds the noise of the voice
+    pub index: usize,
+
+    /// Note that started the voice. Its velocity is kept for the whole voice.
+    pub event: NoteEvent,
+
+    /// Gate off time
+    pub end: Seconds,
+
+    /// Time the voice was stolen, it fades out over STEAL_FADE from there
+    pub cut: Option<Seconds>,
+
+    /// Pitch the voice glides from at its start
+    pub glide_from: Option<Hz>,
+
+    /// Notes played legato after the first, in order of onset
+    pub legato: Vec<NoteEvent>,
+}
+
+impl Voice {
+    fn new(index: usize, event: &NoteEvent, glide_from: Option<Hz>) -> Self {
+        Self {
+            index,
+            event: event.clone(),
+            end: event.end,
+            cut: None,
+            glide_from,
+            legato: vec![],
+        }
+    }
+
+    pub fn start(&self) -> Seconds {
+        self.event.start
+    }
+
+    /// Gate relative to the voice start
+    pub fn gate(&self) -> Gate {
+        Gate { on: 0.0, off: Some(self.end - self.start()) }
+    }
+
+    /// Duration including the release, or the fade if the voice was stolen
+    pub fn length(&self, release_time: Seconds) -> Seconds {
+        let natural = self.end - self.start() + release_time;
+        match self.cut {
+            Some(cut) => natural.min(cut - self.start() + STEAL_FADE),
+            None => natural,
+        }
+    }
+
+    /// Pitch at `t` seconds into the voice before modulation, including the
+    /// pitch curve of the note sounding at `t` from its own onset. Legato
+    /// notes glide on from the pitch the previous note had reached. None
+    /// for unpitched notes.
+    pub fn freq_at(&self, t: Seconds, glide: Seconds) -> Option<Hz> {
+        let mut note = &self.event;
+        let mut from = self.glide_from.or(note.freq)?;
+        let mut since = 0.0;
+        for next in &self.legato {
+            let at = next.start - self.start();
+            if t < at {
+                break;
+            }
+            from = note_freq_at(note, from, at - since, glide)?;
+            note = next;
+            since = at;
+        }
+        note_freq_at(note, from, t - since, glide)
+    }
+
+    /// Gain of the steal fade at `t` seconds into the voice
+    pub fn fade_at(&self, t: Seconds) -> Float {
+        match self.cut {
+            Some(cut) => {
+                (1.0 - (t - (cut - self.start())) / STEAL_FADE).clamp(0.0, 1.0)
+            }
+            None => 1.0,
+        }
+    }
+
+    fn steal(&mut self, now: Seconds, release_time: Seconds) {
+        if now < self.start() + self.length(release_time) {
+            self.cut = Some(now);
+        }
+    }
+}
+
+/// Pitch of a note `t` seconds after its onset, gliding from `from`
+fn note_freq_at(
+    note: &NoteEvent,
+    from: Hz,
+    t: Seconds,
+    glide: Seconds,
+) -> Option<Hz> {
+    let freq = note.freq?;
+    Some(glide_value(from, freq, t, glide) * note.pitch.ratio_at(freq, t))
+}
+
+/// Glides take the same time for every interval and move evenly in pitch
+fn glide_value(from: Hz, to: Hz, elapsed: Seconds, glide: Seconds) -> Hz {
+    if glide <= 0.0 || elapsed >= glide || from <= 0.0 || to <= 0.0 {
+        return to;
+    }
+    from * (to / from).powf(elapsed / glide)
+}
+
+/// Plan the voices of a list of events sorted by onset. `level` estimates
+/// the amplitude of a voice at a time relative to its start, it is used by
+/// the quietest stealing policy.
+pub fn allocate<F>(
+    voicing: &Voicing,
+    events: &[NoteEvent],
+    release_time: Seconds,
+    level: F,
+) -> Vec<Voice>
+where
+    F: Fn(&Voice, Seconds) -> Float,
+{
+    match voicing.mode {
+        VoiceMode::Poly => allocate_poly(voicing, events, release_time, level),
+        VoiceMode::Mono | VoiceMode::Legato => {
+            allocate_mono(voicing, events, release_time)
+        }
+    }
+}
+
+fn allocate_poly<F>(
+    voicing: &Voicing,
+    events: &[NoteEvent],
+    release_time: Seconds,
+    level: F,
+) -> Vec<Voice>
+where
+    F: Fn(&Voice, Seconds) -> Float,
+{
+    if let Some(max) = voicing.max_voices {
+        assert!(max > 0, "Voice limit should be at least one");
+    }
+
+    let mut voices: Vec<Voice> = vec![];
+
+    // Indices of sounding voices in allocation order
+    let mut active: Vec<usize> = vec![];
+
+    for event in events {
+        let now = event.start;
+        active.retain(|&i| {
+            let v = &voices[i];
+            v.start() + v.length(release_time) > now
+        });
+
+        while voicing.max_voices.is_some_and(|max| active.len() >= max) {
+            let oldest = 0;
+            let victim = match voicing.steal {
+                StealPolicy::Oldest => oldest,
+                StealPolicy::Quietest => (0..active.len())
+                    .min_by(|&a, &b| {
+                        let level_of = |i: usize| {
+                            let v = &voices[active[i]];
+                            level(v, now - v.start())
+                        };
+                        level_of(a).total_cmp(&level_of(b))
+                    })
+                    .unwrap(),
+                StealPolicy::SameNote => active
+                    .iter()
+                    .position(|&i| voices[i].event.freq == event.freq)
+                    .unwrap_or(oldest),
+            };
+            voices[active.remove(victim)].steal(now, release_time);
+        }
+
+        active.push(voices.len());
+        voices.push(Voice::new(voices.len(), event, None));
+    }
+
+    voices
+}
+
+fn allocate_mono(
+    voicing: &Voicing,
+    events: &[NoteEvent],
+    release_time: Seconds,
+) -> Vec<Voice> {
+    let mut voices: Vec<Voice> = vec![];
+
+    for event in events {
+        let now = event.start;
+        let mut glide_from = None;
+
+        if let Some(prev) = voices.last_mut() {
+            let held = now <= prev.end + LEGATO_EPS;
+            let pitched = event.freq.is_some() && prev.event.freq.is_some();
+            if voicing.mode == VoiceMode::Legato && held && pitched {
+                prev.legato.push(event.clone());
+                prev.end = prev.end.max(event.end);
+                continue;
+            }
+
+            // Legato mode only glides between connected notes
+            if voicing.mode == VoiceMode::Mono {
+                glide_from = prev.freq_at(now - prev.start(), voicing.glide);
+            }
+            prev.steal(now, release_time);
+        }
+
+        voices.push(Voice::new(voices.len(), event, glide_from));
+    }
+
+    voices
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::render::engine::MusicalPosition;
+    use crate::render::instrument::kick_drum;
+    use crate::render::pitch::{PitchCurve, PitchGlide};
+
+    fn note(freq: Hz, start: Seconds, end: Seconds) -> NoteEvent {
+        NoteEvent {
+            freq: Some(freq),
+            velocity: 0.8,
+            start,
+            end,
+            unpitched: None,
+            instrument: None,
+            position: MusicalPosition::default(),
+            pitch: PitchCurve::default(),
+        }
+    }
+
+    #[test]
+    fn legato_joins_adjacent_notes() {
+        let voicing = Voicing {
+            mode: VoiceMode::Legato,
+            glide: 0.05,
+            ..Voicing::default()
+        };
+        let events = [note(220.0, 0.0, 0.5), note(330.0, 0.5, 1.0)];
+        let voices = allocate(&voicing, &events, 0.1, |_, _| 1.0);
+
+        assert_eq!(voices.len(), 1);
+        assert_eq!(voices[0].legato.len(), 1);
+        assert_eq!(voices[0].end, 1.0);
+    }
+
+    #[test]
+    fn legato_notes_play_their_own_pitch_curves() {
+        let voicing = Voicing { mode: VoiceMode::Legato, ..Voicing::default() };
+        let mut slide = note(220.0, 0.0, 0.5);
+        slide.pitch.glide = Some(PitchGlide {
+            to: 330.0,
+            start: 0.0,
+            end: 0.5,
+            stepped: false,
+        });
+        let mut bent = note(330.0, 0.5, 1.0);
+        bent.pitch.bend = vec![(0.25, 100.0)];
+        let voices = allocate(&voicing, &[slide, bent], 0.1, |_, _| 1.0);
+
+        let voice = &voices[0];
+        let freq = |t| voice.freq_at(t, 0.0).unwrap();
+        assert!((freq(0.25) - (220.0_f64 * 330.0).sqrt()).abs() < 1e-6);
+        assert!((freq(0.5) - 330.0).abs() < 1e-6);
+        let bend = 330.0 * 2.0_f64.powf(100.0 / 1200.0);
+        assert!((freq(0.75) - bend).abs() < 1e-6);
+    }
+
+    #[test]
+    fn voices_do_not_share_modulation_noise() {
+        // The click of the kick is noise modulating the amplitude
+        let events = [note(55.0, 0.0, 0.1), note(55.0, 0.2, 0.3)];
+        let mut kick = kick_drum();
+        let voices = kick.allocate_voices(&events);
+        let alone = kick_drum().render_voice(&voices[1], 8000);
+        kick.render_voice(&voices[0], 8000);
+        let after_first = kick.render_voice(&voices[1], 8000);
+
+        assert_eq!(alone.len(), after_first.len());
+        for i in 0..alone.len() {
+            assert_eq!(alone.get_mono(i), after_first.get_mono(i));
+        }
+    }
+}
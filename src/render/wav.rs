@@ -1,9 +1,10 @@
 use std::fs::create_dir_all;
 use std::path::Path;
 
-use hound::{SampleFormat, WavSpec, WavWriter};
+use hound::{SampleFormat, WavReader, WavSpec, WavWriter};
 
 use super::processor::AudioBuffer;
+use super::types::Float;
 
 pub fn save_to_wav(path: &str, sample_rate: u32, buffer: &AudioBuffer) {
     let (channels, _len) = match buffer {
@@ -42,3 +43,48 @@ pub fn save_to_wav(path: &str, sample_rate: u32, buffer: &AudioBuffer) {
 
     writer.finalize().unwrap();
 }
+
+/// Read a mono or stereo WAV file and its sample rate. Integer samples are
+/// scaled to -1.0..1.0.
+pub fn read_wav(path: &str) -> (AudioBuffer, u32) {
+    try_read_wav(path).unwrap_or_else(|e| panic!("{}", e))
+}
+
+/// `read_wav` for files that come from the user, returns an error instead of
+/// panicking if the file cannot be read
+pub fn try_read_wav(path: &str) -> Result<(AudioBuffer, u32), String> {
+    let err = |e: hound::Error| format!("Failed to read '{}': {}", path, e);
+    let mut reader = WavReader::open(path).map_err(err)?;
+    let spec = reader.spec();
+
+    let samples: Vec<Float> = match spec.sample_format {
+        SampleFormat::Float => reader
+            .samples::<f32>()
+            .map(|s| s.map(|s| s as Float))
+            .collect::<Result<_, _>>()
+            .map_err(err)?,
+        SampleFormat::Int => {
+            let scale = (1_i64 << (spec.bits_per_sample - 1)) as Float;
+            reader
+                .samples::<i32>()
+                .map(|s| s.map(|s| s as Float / scale))
+                .collect::<Result<_, _>>()
+                .map_err(err)?
+        }
+    };
+
+    let buffer = match spec.channels {
+        1 => AudioBuffer::Mono(samples),
+        2 => AudioBuffer::Stereo(
+            samples.chunks_exact(2).map(|c| (c[0], c[1])).collect(),
+        ),
+        n => {
+            return Err(format!(
+                "Unsupported channel count {} in '{}'",
+                n, path
+            ))
+        }
+    };
+
+    Ok((buffer, spec.sample_rate))
+}
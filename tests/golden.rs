@@ -0,0 +1,175 @@
+//! Golden renders of the oscillators and the time based effects. After an
+//! intended change to the sound, run the tests with LYRA_REGENERATE_GOLDEN=1
+//! to rewrite the references in tests/golden and commit them.
+
+use lyra::render::engine::{MusicalPosition, NoteEvent};
+use lyra::render::golden::Golden;
+use lyra::render::signal::{Oscillator, SignalSource};
+use lyra::render::wave::{Wave, WaveShape};
+use lyra::render::*;
+
+const SAMPLE_RATE: u32 = 22050;
+
+fn golden(name: &str) -> Golden {
+    Golden::new(&format!(
+        "{}/tests/golden/{}.wav",
+        env!("CARGO_MANIFEST_DIR"),
+        name
+    ))
+}
+
+fn ctx() -> RenderContext {
+    RenderContext { sample_rate: SAMPLE_RATE, block_size: 128 }
+}
+
+/// Note gliding over three octaves, so band-limiting is heard at low and
+/// high pitches
+fn sweep() -> NoteEvent {
+    NoteEvent {
+        freq: Some(220.0),
+        velocity: 0.8,
+        start: 0.0,
+        end: 0.4,
+        unpitched: None,
+        instrument: None,
+        position: MusicalPosition::default(),
+        pitch: PitchCurve {
+            glide: Some(PitchGlide {
+                to: 1760.0,
+                start: 0.0,
+                end: 0.4,
+                stepped: false,
+            }),
+            ..PitchCurve::default()
+        },
+    }
+}
+
+fn synth(shape: WaveShape) -> Instrument {
+    Instrument {
+        name: "golden".to_string(),
+        layers: vec![InstrumentLayer {
+            signal: SignalSource::Oscillator(Oscillator {
+                wave: Wave { source: Box::new(shape), modifiers: None },
+                freq: 220.0,
+                phase: 0.0,
+            }),
+            mods: None,
+            fx: None,
+            base_freq: None,
+            volume: 0.5,
+            zone: None,
+            unison: None,
+        }],
+        mods: None,
+        fx: None,
+        is_unpitched: false,
+        voicing: Voicing::default(),
+    }
+}
+
+fn check_oscillator(name: &str, shape: WaveShape) {
+    let buffer = synth(shape).render_events(vec![sweep()], &ctx());
+    golden(name).assert_matches(&buffer, SAMPLE_RATE);
+}
+
+/// Two decaying saw bursts followed by silence, for the effect tails to
+/// ring out in. The stereo version leans left.
+fn bursts(stereo: bool) -> AudioBuffer {
+    let len = SAMPLE_RATE as usize / 2;
+    let burst = SAMPLE_RATE as usize / 20;
+    let onsets = [0, SAMPLE_RATE as usize / 8];
+    let frames = (0..len).map(|i| {
+        onsets
+            .iter()
+            .filter(|&&onset| (onset..onset + burst).contains(&i))
+            .map(|&onset| {
+                let t = (i - onset) as f64 / SAMPLE_RATE as f64;
+                let saw = 2.0 * (t * 150.0).fract() - 1.0;
+                0.8 * saw * (-t * 40.0).exp()
+            })
+            .sum::<f64>()
+    });
+    match stereo {
+        true => AudioBuffer::Stereo(frames.map(|s| (s, 0.3 * s)).collect()),
+        false => AudioBuffer::Mono(frames.collect()),
+    }
+}
+
+fn check_effect(name: &str, mut effect: impl AudioEffect) {
+    for stereo in [false, true] {
+        let mut buffer = bursts(stereo);
+        effect.reset();
+        effect.process(&mut buffer, SAMPLE_RATE);
+        let channels = if stereo { "stereo" } else { "mono" };
+        golden(&format!("{}_{}", name, channels))
+            .assert_matches(&buffer, SAMPLE_RATE);
+    }
+}
+
+#[test]
+fn sine() {
+    check_oscillator("sine", WaveShape::Sine);
+}
+
+#[test]
+fn triangle() {
+    check_oscillator("triangle", WaveShape::Triangle(0.5));
+}
+
+#[test]
+fn saw() {
+    check_oscillator("saw", WaveShape::Saw(0.5));
+}
+
+#[test]
+fn skewed_saw() {
+    check_oscillator("skewed_saw", WaveShape::Saw(0.2));
+}
+
+#[test]
+fn pulse() {
+    check_oscillator("pulse", WaveShape::Pulse(0.25));
+}
+
+#[test]
+fn unison() {
+    let buffer = supersaw().render_events(vec![sweep()], &ctx());
+    golden("unison").assert_matches(&buffer, SAMPLE_RATE);
+}
+
+#[test]
+fn simple_reverb() {
+    check_effect("simple_reverb", SimpleReverb::new(0.7, 0.35));
+}
+
+#[test]
+fn delay() {
+    check_effect("delay", Delay::new(SAMPLE_RATE as usize / 10, 0.5, 0.4));
+}
+
+#[test]
+fn compressor() {
+    let mut compressor = Compressor::new(-20.0, 4.0, 0.005, 0.05);
+    compressor.makeup_db = 6.0;
+    check_effect("compressor", compressor);
+}
+
+#[test]
+fn sidechain_compressor() {
+    let sidechain = bursts(false);
+    for stereo in [false, true] {
+        let mut buffer = AudioBuffer::Mono(vec![0.3; sidechain.len()]);
+        if stereo {
+            buffer = buffer.to_stereo();
+        }
+        Compressor::ducker().process_sidechain(
+            &mut buffer,
+            &sidechain,
+            SAMPLE_RATE,
+        );
+        let channels = if stereo { "stereo" } else { "mono" };
+        golden(&format!("ducker_{}", channels))
+            .assert_matches(&buffer, SAMPLE_RATE);
+    }
+}
@@ -0,0 +1,147 @@
+//! Tracks are rendered on worker threads. The result must not depend on how
+//! the work is split, so renders on worker threads are compared bit for bit
+//! against renders on a single thread.
+
+use lyra::compose::{AttributesCreateInfo, Score, ScoreCreateInfo};
+use lyra::render::engine::*;
+use lyra::render::*;
+
+const SAMPLE_RATE: u32 = 22050;
+
+fn note(freq: f64, start: f64, end: f64) -> NoteEvent {
+    NoteEvent {
+        freq: Some(freq),
+        velocity: 0.8,
+        start,
+        end,
+        unpitched: None,
+        instrument: None,
+        position: MusicalPosition::default(),
+        pitch: PitchCurve::default(),
+    }
+}
+
+/// Eight notes a quarter second apart, long enough for the tracks to be
+/// prefetched more than once
+fn pattern(freq: f64) -> Vec<NoteEvent> {
+    (0..8).map(|i| note(freq, i as f64 * 0.25, i as f64 * 0.25 + 0.2)).collect()
+}
+
+fn graph() -> (Graph, NodeId) {
+    let mut graph = Graph::new();
+    let tracks = [
+        (kick_drum(), 55.0),
+        (hihat(), 440.0),
+        (supersaw(), 220.0),
+        (picked_bass(), 110.0),
+    ];
+    let bus = graph.add_node(NodeKind::Bus);
+    for (instrument, freq) in tracks {
+        let track = graph.add_node(NodeKind::Track {
+            source: SoundSource::Instrument(instrument),
+            driver: EventDriver::Events(pattern(freq)),
+        });
+        let reverb = graph.add_node(NodeKind::Effect {
+            effect: Box::new(SimpleReverb::new(0.5, 0.3)),
+        });
+        graph.connect(track, reverb).unwrap();
+        graph.connect(reverb, bus).unwrap();
+    }
+    let out = graph.add_node(NodeKind::Output { target: String::new() });
+    graph.connect(bus, out).unwrap();
+    (graph, out)
+}
+
+fn render_graph(threads: usize) -> Vec<(f64, f64)> {
+    let (node_graph, out) = graph();
+    let mut engine = Engine {
+        sample_rate: SAMPLE_RATE,
+        block_size: 128,
+        node_graph,
+        threads: Some(threads),
+    };
+    engine.stream(out).flatten().collect()
+}
+
+fn score() -> Score {
+    let mut score =
+        Score::new(ScoreCreateInfo { title: "parallel", ..Default::default() });
+    let attributes = AttributesCreateInfo {
+        clefs: ["percussion"].into(),
+        ..Default::default()
+    };
+    for (name, pattern) in [("Kick", "E4:q"), ("Hat", "E4:e")] {
+        score
+            .part(name, |p| {
+                p.measure(|m| {
+                    m.attributes(&attributes);
+                    m.metronome("quarter", 120);
+                    m.dynamics("mf");
+                    m.note_repeat(pattern, 4);
+                });
+            })
+            .unwrap();
+    }
+    score
+}
+
+/// Tracks of the score, created together on worker threads or one after the
+/// other
+fn render_tracks(score: &Score, parallel: bool) -> Vec<Vec<(f64, f64)>> {
+    let ctx = RenderContext { sample_rate: SAMPLE_RATE, block_size: 128 };
+    let mut instruments = [kick_drum(), hihat()];
+    let cis: Vec<TrackCreateInfo> = score
+        .parts
+        .iter()
+        .zip(&mut instruments)
+        .map(|(part, instrument)| TrackCreateInfo {
+            name: "track",
+            part,
+            instrument,
+            fx: None,
+            ctx: &ctx,
+        })
+        .collect();
+    let tracks = match parallel {
+        true => Track::new_all(cis),
+        false => cis.into_iter().map(Track::new).collect(),
+    };
+    tracks
+        .into_iter()
+        .map(|track| match track.buffer {
+            AudioBuffer::Stereo(frames) => frames,
+            AudioBuffer::Mono(_) => panic!("Track should be stereo"),
+        })
+        .collect()
+}
+
+fn assert_identical(
+    single: &[(f64, f64)],
+    parallel: &[(f64, f64)],
+    what: &str,
+) {
+    assert_eq!(single.len(), parallel.len(), "{} length differs", what);
+    let same = single.iter().zip(parallel).all(|(a, b)| {
+        a.0.to_bits() == b.0.to_bits() && a.1.to_bits() == b.1.to_bits()
+    });
+    assert!(same, "{} differs between single and multi threaded", what);
+}
+
+#[test]
+fn engine_render_matches_single_thread() {
+    let single = render_graph(1);
+    assert!(single.iter().any(|&(l, _)| l != 0.0));
+    assert_identical(&single, &render_graph(4), "Engine render");
+}
+
+#[test]
+fn tracks_match_single_thread() {
+    let score = score();
+    let single = render_tracks(&score, false);
+    let parallel = render_tracks(&score, true);
+    assert_eq!(single.len(), 2);
+    for (single, parallel) in single.iter().zip(&parallel) {
+        assert!(single.iter().any(|&(l, _)| l != 0.0));
+        assert_identical(single, parallel, "Track");
+    }
+}